@@ -0,0 +1,325 @@
+use bytes::Bytes;
+use symphonia::core::{
+    formats::FormatReader,
+    io::{MediaSource, MediaSourceStream},
+};
+
+use crate::config::{AudioContainer, OutputFormat};
+
+/// Yields Opus packets for TeamSpeak from any configured Azure output format
+pub(crate) enum AudioReader {
+    /// Ogg or WebM container, Opus packets are passed through
+    Opus(Box<dyn FormatReader>),
+    #[cfg(feature = "transcode")]
+    Transcode(Box<transcode::Transcoder>),
+}
+
+impl AudioReader {
+    pub(crate) fn open(
+        source: Box<dyn MediaSource>,
+        format: &OutputFormat,
+    ) -> anyhow::Result<Self> {
+        let source = MediaSourceStream::new(source, Default::default());
+        Ok(match format.container() {
+            AudioContainer::Ogg => Self::Opus(Box::new(
+                symphonia::default::formats::OggReader::try_new(source, &Default::default())?,
+            )),
+            AudioContainer::WebM => Self::Opus(Box::new(
+                symphonia::default::formats::MkvReader::try_new(source, &Default::default())?,
+            )),
+            #[cfg(feature = "transcode")]
+            _ => Self::Transcode(Box::new(transcode::Transcoder::new(source, format)?)),
+            #[cfg(not(feature = "transcode"))]
+            _ => unreachable!("Rejected when loading configuration"),
+        })
+    }
+
+    pub(crate) fn next_packet(&mut self) -> Option<Box<[u8]>> {
+        match self {
+            Self::Opus(reader) => reader.next_packet().ok().map(|packet| packet.data),
+            #[cfg(feature = "transcode")]
+            Self::Transcode(transcoder) => transcoder.next_packet(),
+        }
+    }
+
+    /// Give back the underlying stream, used for restart playback from beginning
+    pub(crate) fn into_source(self) -> MediaSourceStream {
+        match self {
+            Self::Opus(reader) => reader.into_inner(),
+            #[cfg(feature = "transcode")]
+            Self::Transcode(transcoder) => transcoder.into_source(),
+        }
+    }
+}
+
+/// Cached audio as 16 bit mono WAV file, other formats than WAV have to be decoded
+pub(crate) fn to_wav(audio: Bytes, format: &OutputFormat) -> anyhow::Result<Bytes> {
+    match format.container() {
+        AudioContainer::Riff => Ok(audio),
+        #[cfg(feature = "transcode")]
+        _ => transcode::to_wav(audio, format).map(Bytes::from),
+        #[cfg(not(feature = "transcode"))]
+        _ => Err(anyhow::anyhow!(
+            "Decoding audio requires `transcode` feature"
+        )),
+    }
+}
+
+#[cfg(feature = "transcode")]
+mod transcode {
+    use std::{
+        collections::VecDeque,
+        io::{Cursor, Read},
+    };
+
+    use audiopus::{
+        Application, Channels, MutSignals, SampleRate,
+        coder::{Decoder as OpusDecoder, Encoder},
+        packet::Packet,
+    };
+    use bytes::Bytes;
+    use symphonia::core::{
+        audio::SampleBuffer, codecs::Decoder, errors::Error as SymphoniaError,
+        formats::FormatReader, io::MediaSourceStream,
+    };
+
+    use crate::config::{AudioContainer, OutputFormat};
+
+    // Opus packet is large enough to hold any 20ms frame under this size
+    const MAX_PACKET_SIZE: usize = 4000;
+
+    enum PcmInput {
+        /// Headerless 16 bit little endian mono samples
+        Raw {
+            source: MediaSourceStream,
+            // Odd byte left from previous read
+            carry: Option<u8>,
+        },
+        Decoded {
+            reader: Box<dyn FormatReader>,
+            decoder: Box<dyn Decoder>,
+        },
+    }
+
+    impl PcmInput {
+        fn open(source: MediaSourceStream, format: &OutputFormat) -> anyhow::Result<Self> {
+            Ok(match format.container() {
+                AudioContainer::Raw => Self::Raw {
+                    source,
+                    carry: None,
+                },
+                container => {
+                    let reader: Box<dyn FormatReader> = match container {
+                        AudioContainer::Riff => {
+                            Box::new(symphonia::default::formats::WavReader::try_new(
+                                source,
+                                &Default::default(),
+                            )?)
+                        }
+                        _ => Box::new(symphonia::default::formats::MpaReader::try_new(
+                            source,
+                            &Default::default(),
+                        )?),
+                    };
+                    let track = reader
+                        .default_track()
+                        .ok_or_else(|| anyhow::anyhow!("No audio track found"))?;
+                    let decoder = symphonia::default::get_codecs()
+                        .make(&track.codec_params, &Default::default())?;
+                    Self::Decoded { reader, decoder }
+                }
+            })
+        }
+
+        /// Append more samples, return false on end of stream
+        fn fill(&mut self, samples: &mut VecDeque<i16>) -> bool {
+            match self {
+                Self::Raw { source, carry } => {
+                    let mut buf = [0u8; 4096];
+                    let offset = match carry.take() {
+                        Some(byte) => {
+                            buf[0] = byte;
+                            1
+                        }
+                        None => 0,
+                    };
+                    let size = match source.read(&mut buf[offset..]) {
+                        Ok(0) | Err(_) => return false,
+                        Ok(size) => size + offset,
+                    };
+                    let chunks = buf[..size].chunks_exact(2);
+                    *carry = chunks.remainder().first().copied();
+                    samples.extend(chunks.map(|b| i16::from_le_bytes([b[0], b[1]])));
+                    true
+                }
+                Self::Decoded { reader, decoder } => loop {
+                    let packet = match reader.next_packet() {
+                        Ok(packet) => packet,
+                        Err(_) => return false,
+                    };
+                    let decoded = match decoder.decode(&packet) {
+                        Ok(decoded) => decoded,
+                        Err(SymphoniaError::DecodeError(e)) => {
+                            log::warn!("Skip undecodable packet: {e}");
+                            continue;
+                        }
+                        Err(e) => {
+                            log::error!("Decode error: {e:?}");
+                            return false;
+                        }
+                    };
+                    let channels = decoded.spec().channels.count().max(1);
+                    let mut buffer =
+                        SampleBuffer::<i16>::new(decoded.capacity() as u64, *decoded.spec());
+                    buffer.copy_interleaved_ref(decoded);
+                    // Azure only produces mono, keep first channel in case
+                    samples.extend(buffer.samples().iter().step_by(channels));
+                    return true;
+                },
+            }
+        }
+
+        fn into_source(self) -> MediaSourceStream {
+            match self {
+                Self::Raw { source, .. } => source,
+                Self::Decoded { reader, .. } => reader.into_inner(),
+            }
+        }
+    }
+
+    pub(crate) struct Transcoder {
+        input: PcmInput,
+        encoder: Encoder,
+        frame_size: usize,
+        samples: VecDeque<i16>,
+        finished: bool,
+    }
+
+    impl Transcoder {
+        pub(crate) fn new(
+            source: MediaSourceStream,
+            format: &OutputFormat,
+        ) -> anyhow::Result<Self> {
+            let input = PcmInput::open(source, format)?;
+            let encoder = Encoder::new(
+                SampleRate::try_from(format.sample_rate() as i32)?,
+                Channels::Mono,
+                Application::Voip,
+            )?;
+            Ok(Self {
+                input,
+                encoder,
+                // 20ms per frame, same as Azure Opus output
+                frame_size: format.sample_rate() as usize / 50,
+                samples: VecDeque::new(),
+                finished: false,
+            })
+        }
+
+        pub(crate) fn next_packet(&mut self) -> Option<Box<[u8]>> {
+            while !self.finished && self.samples.len() < self.frame_size {
+                self.finished = !self.input.fill(&mut self.samples);
+            }
+            if self.samples.is_empty() {
+                return None;
+            }
+            let take = self.frame_size.min(self.samples.len());
+            let mut frame = self.samples.drain(..take).collect::<Vec<_>>();
+            // Pad last frame with silence
+            frame.resize(self.frame_size, 0);
+
+            let mut output = [0u8; MAX_PACKET_SIZE];
+            let size = self
+                .encoder
+                .encode(&frame, &mut output)
+                .inspect_err(|e| log::error!("Opus encode error: {e:?}"))
+                .ok()?;
+            Some(output[..size].into())
+        }
+
+        pub(crate) fn into_source(self) -> MediaSourceStream {
+            self.input.into_source()
+        }
+    }
+
+    /// Decode whole audio into samples and put them in a WAV container
+    pub(crate) fn to_wav(audio: Bytes, format: &OutputFormat) -> anyhow::Result<Vec<u8>> {
+        let mut samples = VecDeque::new();
+        if format.container().need_transcode() {
+            let source = MediaSourceStream::new(Box::new(Cursor::new(audio)), Default::default());
+            let mut input = PcmInput::open(source, format)?;
+            while input.fill(&mut samples) {}
+        } else {
+            let mut reader = super::AudioReader::open(Box::new(Cursor::new(audio)), format)?;
+            let mut decoder = OpusDecoder::new(
+                SampleRate::try_from(format.sample_rate() as i32)?,
+                Channels::Mono,
+            )?;
+            // Longest Opus packet holds 120ms
+            let mut output = vec![0i16; format.sample_rate() as usize / 1000 * 120];
+            while let Some(packet) = reader.next_packet() {
+                let size = decoder.decode(
+                    Some(Packet::try_from(&packet[..])?),
+                    MutSignals::try_from(&mut output[..])?,
+                    false,
+                )?;
+                samples.extend(&output[..size]);
+            }
+        }
+        Ok(wav(samples.make_contiguous(), format.sample_rate()))
+    }
+
+    /// 16 bit mono PCM with the 44 bytes header
+    fn wav(samples: &[i16], sample_rate: u32) -> Vec<u8> {
+        let size = samples.len() as u32 * 2;
+        let mut ret = Vec::with_capacity(44 + size as usize);
+        ret.extend_from_slice(b"RIFF");
+        ret.extend_from_slice(&(36 + size).to_le_bytes());
+        ret.extend_from_slice(b"WAVEfmt ");
+        ret.extend_from_slice(&16u32.to_le_bytes());
+        // PCM, mono
+        ret.extend_from_slice(&1u16.to_le_bytes());
+        ret.extend_from_slice(&1u16.to_le_bytes());
+        ret.extend_from_slice(&sample_rate.to_le_bytes());
+        ret.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+        // Bytes per frame, bits per sample
+        ret.extend_from_slice(&2u16.to_le_bytes());
+        ret.extend_from_slice(&16u16.to_le_bytes());
+        ret.extend_from_slice(b"data");
+        ret.extend_from_slice(&size.to_le_bytes());
+        for sample in samples {
+            ret.extend_from_slice(&sample.to_le_bytes());
+        }
+        ret
+    }
+
+    #[cfg(test)]
+    mod test {
+        use symphonia::core::{formats::FormatReader, io::MediaSourceStream};
+
+        use super::to_wav;
+        use crate::config::OutputFormat;
+
+        #[test]
+        fn test_to_wav() {
+            let format = OutputFormat::try_from("raw-16khz-16bit-mono-pcm".to_string()).unwrap();
+            let pcm = [1i16, -2, 300, i16::MAX]
+                .iter()
+                .flat_map(|sample| sample.to_le_bytes())
+                .collect::<Vec<_>>();
+            let wav = to_wav(pcm.clone().into(), &format).unwrap();
+            assert_eq!(wav.len(), 44 + pcm.len());
+            assert_eq!(&wav[44..], pcm);
+
+            let reader = symphonia::default::formats::WavReader::try_new(
+                MediaSourceStream::new(Box::new(std::io::Cursor::new(wav)), Default::default()),
+                &Default::default(),
+            )
+            .unwrap();
+            let params = &reader.default_track().unwrap().codec_params;
+            assert_eq!(params.sample_rate, Some(16000));
+            assert_eq!(params.channels.unwrap().count(), 1);
+            assert_eq!(params.n_frames, Some(4));
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//! Append-only record of every spoken message, for moderators dealing with abuse
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::{cache::ConnAgent, config::Audit, types::current_timestamp};
+
+/// Where a request comes from
+#[derive(Clone, Debug)]
+pub struct Origin {
+    /// e.g. `websocket`
+    source: &'static str,
+    /// Peer address
+    client: String,
+}
+
+impl Origin {
+    pub fn new(source: &'static str, client: String) -> Self {
+        Self { source, client }
+    }
+
+    pub fn source(&self) -> &'static str {
+        self.source
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AuditEntry {
+    /// Unix timestamp
+    pub timestamp: u64,
+    pub source: String,
+    pub client: String,
+    /// Self-declared by the client, not verified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// As sent by the client, before preprocessing
+    pub text: String,
+    /// Voices reading the message, comma separated
+    pub voice: String,
+    pub cached: bool,
+    pub success: bool,
+    /// Status sent back to the client, or the error
+    pub outcome: String,
+}
+
+impl AuditEntry {
+    pub fn new(origin: &Origin, user: Option<String>, text: String) -> Self {
+        Self {
+            timestamp: current_timestamp(),
+            source: origin.source.to_string(),
+            client: origin.client.clone(),
+            user,
+            text,
+            voice: String::new(),
+            cached: false,
+            success: false,
+            outcome: String::new(),
+        }
+    }
+
+    pub fn finish(&mut self, ret: &anyhow::Result<String>) {
+        self.success = ret.is_ok();
+        self.outcome = match ret {
+            Ok(code) => code.clone(),
+            Err(e) => e.to_string(),
+        };
+    }
+}
+
+/// Filter of admin API, newest entries first
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    /// Default 100
+    limit: Option<usize>,
+    source: Option<String>,
+    client: Option<String>,
+    user: Option<String>,
+    /// Unix timestamp
+    since: Option<u64>,
+    /// Case insensitive part of text
+    contains: Option<String>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.source
+            .as_ref()
+            .is_none_or(|source| &entry.source == source)
+            && self
+                .client
+                .as_ref()
+                .is_none_or(|client| entry.client.starts_with(client.as_str()))
+            && self
+                .user
+                .as_ref()
+                .is_none_or(|user| entry.user.as_ref() == Some(user))
+            && self.since.is_none_or(|since| entry.timestamp >= since)
+            && self
+                .contains
+                .as_ref()
+                .is_none_or(|contains| entry.text.to_lowercase().contains(&contains.to_lowercase()))
+    }
+}
+
+/// Slots read from LevelDB in one round-trip by `query`
+const QUERY_BATCH: u64 = 256;
+
+pub struct AuditLog {
+    leveldb: ConnAgent,
+    file: Option<String>,
+    retention: u64,
+    // Counter is read-modify-write and file lines must not interleave
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn new(leveldb: ConnAgent, config: &Audit) -> Self {
+        Self {
+            leveldb,
+            file: config.file().map(str::to_string),
+            retention: config.retention(),
+            lock: Default::default(),
+        }
+    }
+
+    const COUNTER_KEY: &str = "audit:next";
+
+    /// Entries are kept in a ring of `retention` slots
+    fn slot_key(&self, index: u64) -> String {
+        format!("audit:{}", index % self.retention)
+    }
+
+    async fn next_index(&self) -> u64 {
+        self.leveldb
+            .get_record(Self::COUNTER_KEY)
+            .await
+            .and_then(|value| value.as_ref().try_into().ok())
+            .map(u64::from_le_bytes)
+            .unwrap_or_default()
+    }
+
+    async fn append_file(path: &str, line: &[u8]) -> anyhow::Result<()> {
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(line).await?;
+        Ok(())
+    }
+
+    pub async fn record(&self, entry: &AuditEntry) {
+        log::debug!(
+            "Audit: {} {} {:?}: {}",
+            entry.source,
+            entry.client,
+            entry.user,
+            entry.outcome
+        );
+        let Ok(mut raw) = serde_json::to_vec(entry)
+            .inspect_err(|e| log::error!("Unable encode audit entry: {e:?}"))
+        else {
+            return;
+        };
+        let _guard = self.lock.lock().await;
+        if self.retention > 0 {
+            let index = self.next_index().await;
+            self.leveldb
+                .set_record(&self.slot_key(index), raw.clone())
+                .await
+                .inspect_err(|e| log::error!("Unable write audit entry: {e:?}"))
+                .ok();
+            self.leveldb
+                .set_record(Self::COUNTER_KEY, (index + 1).to_le_bytes().to_vec())
+                .await
+                .inspect_err(|e| log::error!("Unable write audit counter: {e:?}"))
+                .ok();
+        }
+        if let Some(ref path) = self.file {
+            raw.push(b'\n');
+            Self::append_file(path, &raw)
+                .await
+                .inspect_err(|e| log::error!("Unable write audit file {path:?}: {e:?}"))
+                .ok();
+        }
+    }
+
+    /// Matching entries kept in LevelDB, newest first
+    pub async fn query(&self, query: &AuditQuery) -> Vec<AuditEntry> {
+        let limit = query.limit.unwrap_or(100);
+        let mut ret = Vec::new();
+        if self.retention == 0 {
+            return ret;
+        }
+        let next = self.next_index().await;
+        let oldest = next.saturating_sub(self.retention);
+        let mut end = next;
+        // Read slots in batches, newest first, until enough entries match
+        while end > oldest && ret.len() < limit {
+            let start = end.saturating_sub(QUERY_BATCH).max(oldest);
+            let names = (start..end)
+                .rev()
+                .map(|index| self.slot_key(index))
+                .collect();
+            for raw in self.leveldb.get_records(names).await.into_iter().flatten() {
+                let Ok(entry) = serde_json::from_slice::<AuditEntry>(&raw) else {
+                    continue;
+                };
+                if ret.len() < limit && query.matches(&entry) {
+                    ret.push(entry);
+                }
+            }
+            end = start;
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AuditEntry, AuditLog, AuditQuery, Origin};
+    use crate::cache::LevelDB;
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let (agent, db) = LevelDB::new_in_memory();
+        let file = std::env::temp_dir().join(format!("audit-{}.jsonl", std::process::id()));
+        let log = AuditLog::new(
+            agent,
+            &toml::from_str(&format!("file = {:?}\nretention = 3", file.display())).unwrap(),
+        );
+        let origin = Origin::new("websocket", "127.0.0.1".to_string());
+        for (user, text) in [
+            (Some("alice"), "Hello"),
+            (None, "spam spam"),
+            (Some("bob"), "Good morning"),
+            (Some("alice"), "SPAM again"),
+        ] {
+            let mut entry = AuditEntry::new(&origin, user.map(str::to_string), text.to_string());
+            entry.finish(&Ok("200 OK".to_string()));
+            log.record(&entry).await;
+        }
+
+        // Oldest entry is dropped from LevelDB, file keeps everything
+        let texts = |entries: Vec<AuditEntry>| {
+            entries
+                .into_iter()
+                .map(|entry| entry.text)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            texts(log.query(&AuditQuery::default()).await),
+            ["SPAM again", "Good morning", "spam spam"]
+        );
+        let query: AuditQuery = serde_json::from_str(r#"{"contains": "spam"}"#).unwrap();
+        assert_eq!(texts(log.query(&query).await), ["SPAM again", "spam spam"]);
+        let query: AuditQuery = serde_json::from_str(r#"{"user": "alice"}"#).unwrap();
+        assert_eq!(texts(log.query(&query).await), ["SPAM again"]);
+        let query: AuditQuery = serde_json::from_str(r#"{"limit": 1}"#).unwrap();
+        assert_eq!(log.query(&query).await.len(), 1);
+
+        let lines = tokio::fs::read_to_string(&file).await.unwrap();
+        std::fs::remove_file(&file).ok();
+        assert_eq!(lines.lines().count(), 4);
+        let first: AuditEntry = serde_json::from_str(lines.lines().next().unwrap()).unwrap();
+        assert_eq!(first.user.as_deref(), Some("alice"));
+        assert!(first.success);
+
+        db.disconnect().await.unwrap();
+    }
+}
@@ -8,6 +8,14 @@ pub struct ConnAgent(DatabaseHelper);
 
 type KeyType = u64;
 
+/// Prefix of records, data other than audio. Audio is stored under 8 byte keys, records
+/// under longer ones, so no audio cache key can ever reach a record
+const RECORD_PREFIX: &[u8] = b"metadata:";
+
+fn record_key(name: &str) -> Vec<u8> {
+    [RECORD_PREFIX, name.as_bytes()].concat()
+}
+
 impl From<DatabaseHelper> for ConnAgent {
     fn from(value: DatabaseHelper) -> Self {
         Self(value)
@@ -30,6 +38,10 @@ kstool_helper_generator::oneshot_helper! {
         ),
         #[ret(Result<()>)]
         Delete(KeyType),
+        #[ret(Result<()>)]
+        SetRecord(String, Vec<u8>),
+        #[ret(Vec<Option<bytes::Bytes>>)]
+        GetRecords(Vec<String>),
         Exit,
     }
 }
@@ -46,6 +58,11 @@ impl LevelDB {
         Self::new_with_opt(file, Self::opt)
     }
 
+    #[cfg(test)]
+    pub fn new_in_memory() -> (ConnAgent, Self) {
+        Self::new_with_opt("db".to_string(), rusty_leveldb::in_memory)
+    }
+
     fn new_with_opt(file: String, opt_fn: fn() -> rusty_leveldb::Options) -> (ConnAgent, Self) {
         let (sender, receiver) = DatabaseHelper::new(2048);
 
@@ -81,6 +98,16 @@ impl LevelDB {
                     sender.send(db.delete(&k.to_be_bytes())).ok();
                     db.flush()?;
                 }
+                DatabaseEvent::SetRecord(name, v, sender) => {
+                    let ret = db.put(&record_key(&name), &v);
+                    sender.send(ret).ok();
+                    db.flush()?;
+                }
+                DatabaseEvent::GetRecords(names, sender) => {
+                    sender
+                        .send(names.iter().map(|name| db.get(&record_key(name))).collect())
+                        .ok();
+                }
                 DatabaseEvent::Exit => break,
             }
         }
@@ -140,6 +167,32 @@ impl ConnAgent {
         }
         Some(ret)
     }
+
+    pub async fn set_record(&self, name: &str, value: Vec<u8>) -> anyhow::Result<()> {
+        self.0
+            .set_record(name.to_string(), value)
+            .await
+            .map_or(Ok(()), |v| v.map_err(anyhow::Error::from))
+    }
+
+    pub async fn get_record(&self, name: &str) -> Option<bytes::Bytes> {
+        self.get_records(vec![name.to_string()])
+            .await
+            .pop()
+            .flatten()
+    }
+
+    /// Many records in one round-trip, in order of `names`
+    pub async fn get_records(&self, names: Vec<String>) -> Vec<Option<bytes::Bytes>> {
+        let count = names.len();
+        self.0
+            .get_records(names)
+            .await
+            .unwrap_or_else(|| vec![None; count])
+            .into_iter()
+            .map(|value| value.filter(|value| !value.is_empty()))
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -174,4 +227,24 @@ mod test {
             .block_on(db.disconnect())
             .unwrap();
     }
+
+    #[tokio::test]
+    async fn test_records() {
+        let (conn, db) = LevelDB::new_in_memory();
+        conn.set_record("history", "record".as_bytes().to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            conn.get_record("history").await,
+            Some("record".as_bytes().into())
+        );
+        // Records never share a key with audio
+        assert_eq!(conn.get(xxhash_rust::xxh3::xxh3_64(b"history")).await, None);
+        assert_eq!(
+            conn.get_records(vec!["history".to_string(), "missing".to_string()])
+                .await,
+            [Some("record".as_bytes().into()), None]
+        );
+        db.disconnect().await.unwrap();
+    }
 }
@@ -0,0 +1,164 @@
+//! Split long messages at sentence boundaries, each chunk is synthesized and cached on its own
+
+use crate::tts::Segment;
+
+fn is_terminator(c: char) -> bool {
+    matches!(
+        c,
+        '.' | '!' | '?' | ';' | '\n' | '。' | '！' | '？' | '；' | '…'
+    )
+}
+
+/// Split text after sentence terminators, whitespace stays with the sentence before it.
+/// ASCII terminators only count when followed by whitespace, so `3.14` is kept together
+fn sentences(text: &str) -> Vec<&str> {
+    let mut ret = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        if !is_terminator(c) {
+            continue;
+        }
+        // Keep runs like "?!" or "..." together
+        while chars.peek().is_some_and(|&(_, next)| is_terminator(next)) {
+            chars.next();
+        }
+        if c.is_ascii() && c != '\n' && chars.peek().is_some_and(|&(_, next)| !next.is_whitespace())
+        {
+            continue;
+        }
+        while chars.peek().is_some_and(|&(_, next)| next.is_whitespace()) {
+            chars.next();
+        }
+        let end = chars.peek().map_or(text.len(), |&(index, _)| index);
+        ret.push(&text[start..end]);
+        start = end;
+    }
+    if start < text.len() {
+        ret.push(&text[start..]);
+    }
+    ret
+}
+
+/// Split sentence longer than `max` characters, at a comma or space if possible
+fn split_long(mut sentence: &str, max: usize) -> Vec<&str> {
+    let mut ret = Vec::new();
+    while sentence.chars().count() > max {
+        let limit = sentence
+            .char_indices()
+            .nth(max)
+            .map_or(sentence.len(), |(index, _)| index);
+        let end = sentence[..limit]
+            .rfind(|c: char| c.is_whitespace() || matches!(c, ',' | '，' | '、'))
+            .map(|index| index + sentence[index..].chars().next().unwrap().len_utf8())
+            .filter(|&end| !sentence[..end].trim().is_empty())
+            .unwrap_or(limit);
+        ret.push(&sentence[..end]);
+        sentence = &sentence[end..];
+    }
+    ret.push(sentence);
+    ret
+}
+
+/// Group sentences into chunks of at most `max` characters, a message within the limit
+/// is returned as is. Adjacent sentences of the same voice are merged into one segment
+pub fn chunks(segments: Vec<Segment>, max: usize) -> Vec<Vec<Segment>> {
+    let length = |segments: &[Segment]| {
+        segments
+            .iter()
+            .map(|segment| segment.text.chars().count())
+            .sum::<usize>()
+    };
+    if max == 0 || length(&segments) <= max {
+        return vec![segments];
+    }
+    let mut ret = Vec::new();
+    let mut current: Vec<Segment> = Vec::new();
+    for segment in &segments {
+        for piece in sentences(&segment.text)
+            .into_iter()
+            .flat_map(|sentence| split_long(sentence, max))
+        {
+            if !current.is_empty() && length(&current) + piece.chars().count() > max {
+                ret.push(std::mem::take(&mut current));
+            }
+            match current.last_mut() {
+                Some(last) if last.voice == segment.voice => last.text.push_str(piece),
+                _ => current.push(Segment::new(segment.voice.clone(), piece.to_string())),
+            }
+        }
+    }
+    ret.push(current);
+    ret.into_iter()
+        .map(|chunk| {
+            chunk
+                .into_iter()
+                .map(|segment| Segment::new(segment.voice, segment.text.trim().to_string()))
+                .filter(|segment| !segment.text.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .filter(|chunk| !chunk.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{chunks, sentences, split_long};
+    use crate::{presets::Preset, tts::Segment};
+
+    fn texts(chunks: &[Vec<Segment>]) -> Vec<Vec<&str>> {
+        chunks
+            .iter()
+            .map(|chunk| chunk.iter().map(|segment| segment.text.as_str()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_sentences() {
+        assert_eq!(
+            sentences("Pi is 3.14! Really?! Yes... 好的。谢谢\nBye"),
+            [
+                "Pi is 3.14! ",
+                "Really?! ",
+                "Yes... ",
+                "好的。",
+                "谢谢\n",
+                "Bye"
+            ]
+        );
+        assert_eq!(
+            split_long("one two, three four", 10),
+            ["one two, ", "three four"]
+        );
+        assert_eq!(split_long("abcdefgh", 3), ["abc", "def", "gh"]);
+    }
+
+    #[test]
+    fn test_chunks() {
+        let en = Preset::new("en-US-AvaNeural".to_string(), String::new());
+        let zh = Preset::new("zh-CN-XiaoxiaoNeural".to_string(), String::new());
+        let short = vec![Segment::new(en.clone(), " Hello. World. ".to_string())];
+        assert_eq!(chunks(short.clone(), 300), [short.as_slice()]);
+        assert_eq!(chunks(short.clone(), 0), [short.as_slice()]);
+
+        let long = chunks(
+            vec![
+                Segment::new(
+                    en.clone(),
+                    "First sentence here. Second one. Third sentence is longer. ".to_string(),
+                ),
+                Segment::new(zh.clone(), "第四句。".to_string()),
+            ],
+            40,
+        );
+        assert_eq!(
+            texts(&long),
+            [
+                vec!["First sentence here. Second one."],
+                vec!["Third sentence is longer.", "第四句。"],
+            ]
+        );
+        assert_eq!(long[1][0].voice, en);
+        assert_eq!(long[1][1].voice, zh);
+    }
+}
@@ -1,11 +1,14 @@
-use std::sync::Arc;
+use std::collections::HashMap;
 
-use anyhow::anyhow;
 use serde::Deserialize;
-use tap::Tap;
-use tokio::{fs::read_to_string, sync::RwLock};
+use tokio::fs::read_to_string;
 use tsclientlib::ClientDbId;
 
+use crate::{
+    keys::{KeyEndpoint, KeyStore, KeyStrategy},
+    presets::{DefaultVoice, Preset},
+};
+
 fn default_nickname() -> String {
     "tts".into()
 }
@@ -14,6 +17,82 @@ fn default_level_db() -> String {
     "tts.db".into()
 }
 
+fn default_recorder_folder() -> String {
+    "recordings".into()
+}
+
+fn default_recorder_retention() -> u64 {
+    7
+}
+
+fn default_stt_silence() -> u64 {
+    800
+}
+
+fn default_stt_min_duration() -> u64 {
+    500
+}
+
+fn default_stt_timeout() -> u64 {
+    60
+}
+
+fn default_stt_concurrency() -> usize {
+    2
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_tts_retries() -> u32 {
+    3
+}
+
+fn default_tts_revalidate() -> u64 {
+    30
+}
+
+fn default_tts_voices_refresh() -> u64 {
+    24
+}
+
+fn default_tts_chunk_size() -> usize {
+    300
+}
+
+fn default_tts_timeout() -> u64 {
+    5
+}
+
+fn default_preprocess_censor() -> String {
+    "beep".into()
+}
+
+fn default_preprocess_max_repeat() -> usize {
+    3
+}
+
+fn default_usage_timeout() -> u64 {
+    30
+}
+
+fn default_web_port() -> u16 {
+    11400
+}
+
+fn default_web_history() -> usize {
+    30
+}
+
+fn default_audit_retention() -> u64 {
+    10000
+}
+
+fn default_tls_reload() -> u64 {
+    30
+}
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(untagged)]
 pub enum ArrayOrSingle<T> {
@@ -34,10 +113,10 @@ pub enum ArrayOrSingle<T> {
 
 impl<T> ArrayOrSingle<T> {
     pub fn validate(&self) -> Result<(), &'static str> {
-        if let Self::Multiple(v) = self {
-            if v.is_empty() {
-                return Err("Vec is empty");
-            }
+        if let Self::Multiple(v) = self
+            && v.is_empty()
+        {
+            return Err("Vec is empty");
         }
         Ok(())
     }
@@ -57,11 +136,41 @@ pub struct Config {
     teamspeak: TeamSpeak,
     tts: TTS,
     web: Web,
+    recorder: Option<Recorder>,
+    stt: Option<Stt>,
+    usage: Option<Usage>,
+    preprocess: Option<Preprocess>,
+    language: Option<Language>,
+    audit: Option<Audit>,
+    /// Named voice presets
+    #[serde(default)]
+    presets: HashMap<String, Preset>,
+    /// Default voice per user (TeamSpeak UID or other name), preset name or voice
+    #[serde(default)]
+    users: HashMap<String, DefaultVoice>,
 }
 
 impl Config {
     pub async fn load(path: &str) -> anyhow::Result<Self> {
-        Ok(toml::from_str(read_to_string(path).await?.as_str())?)
+        let config: Self = toml::from_str(read_to_string(path).await?.as_str())?;
+        if config.tts.endpoint.is_empty() && config.tts.keys().missing_endpoint().await {
+            return Err(anyhow::anyhow!(
+                "[tts] endpoint is required unless every key has own region or endpoint"
+            ));
+        }
+        for (name, preset) in &config.presets {
+            preset
+                .validate()
+                .map_err(|e| anyhow::anyhow!("Preset {name:?}: {e}"))?;
+        }
+        for (user, voice) in &config.users {
+            if let DefaultVoice::Voice(preset) = voice {
+                preset
+                    .validate()
+                    .map_err(|e| anyhow::anyhow!("Default voice of {user:?}: {e}"))?;
+            }
+        }
+        Ok(config)
     }
 
     pub fn tts(&self) -> &TTS {
@@ -79,6 +188,68 @@ impl Config {
     pub fn leveldb(&self) -> &str {
         &self.leveldb
     }
+
+    pub fn recorder(&self) -> Option<&Recorder> {
+        self.recorder.as_ref()
+    }
+
+    pub fn stt(&self) -> Option<&Stt> {
+        self.stt.as_ref()
+    }
+
+    pub fn usage(&self) -> Option<&Usage> {
+        self.usage.as_ref()
+    }
+
+    pub fn preprocess(&self) -> Option<&Preprocess> {
+        self.preprocess.as_ref()
+    }
+
+    pub fn language(&self) -> Option<&Language> {
+        self.language.as_ref()
+    }
+
+    pub fn audit(&self) -> Option<&Audit> {
+        self.audit.as_ref()
+    }
+
+    pub fn presets(&self) -> &HashMap<String, Preset> {
+        &self.presets
+    }
+
+    pub fn users(&self) -> &HashMap<String, DefaultVoice> {
+        &self.users
+    }
+}
+
+/// Default channel, either a raw channel ID or a path such as `"Gaming/Raid Room"`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ChannelTarget {
+    Id(u64),
+    Path(String),
+}
+
+impl Default for ChannelTarget {
+    fn default() -> Self {
+        Self::Id(0)
+    }
+}
+
+impl ChannelTarget {
+    pub fn id(&self) -> Option<u64> {
+        match self {
+            Self::Id(id) => Some(*id),
+            Self::Path(_) => None,
+        }
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            Self::Path(path) if !path.trim().is_empty() => Some(path),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -89,7 +260,7 @@ pub struct TeamSpeak {
     #[serde(default = "default_nickname")]
     nickname: String,
     #[serde(default)]
-    channel: u64,
+    channel: ChannelTarget,
     follow: Option<u64>,
     #[serde(default)]
     password: String,
@@ -108,8 +279,8 @@ impl TeamSpeak {
         &self.nickname
     }
 
-    pub fn channel(&self) -> u64 {
-        self.channel
+    pub fn channel(&self) -> &ChannelTarget {
+        &self.channel
     }
 
     pub fn follow(&self) -> Option<ClientDbId> {
@@ -121,53 +292,155 @@ impl TeamSpeak {
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct KeyStore {
-    inner: Arc<RwLock<Vec<String>>>,
+/// What happens to a playback interrupted by an urgent message
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InterruptMode {
+    /// Continue from where it stopped
+    #[default]
+    Resume,
+    /// Play again from the beginning
+    Restart,
+    /// Discard the rest
+    Drop,
 }
 
-impl<'de> Deserialize<'de> for KeyStore {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let inner: ArrayOrSingle<String> = ArrayOrSingle::deserialize(deserializer)?;
-        inner.validate().map_err(serde::de::Error::custom)?;
-        Ok(Self {
-            inner: Arc::new(RwLock::new(inner.into_vec())),
-        })
-    }
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioContainer {
+    Ogg,
+    WebM,
+    Raw,
+    Riff,
+    Mp3,
 }
 
-impl KeyStore {
-    pub async fn get_one(&self) -> anyhow::Result<String> {
-        let delegate = self.inner.read().await;
-        if delegate.is_empty() {
-            return Err(anyhow!("KeyStore is empty"));
+impl AudioContainer {
+    /// Containers without Opus packets have to be re-encoded before sending to TeamSpeak
+    pub fn need_transcode(&self) -> bool {
+        !matches!(self, Self::Ogg | Self::WebM)
+    }
+
+    /// File extension of downloaded audio
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Ogg => "ogg",
+            Self::WebM => "webm",
+            Self::Raw => "pcm",
+            Self::Riff => "wav",
+            Self::Mp3 => "mp3",
         }
-        Ok(
-            rand::seq::IndexedRandom::choose(&delegate[..], &mut rand::rng())
-                .unwrap()
-                .tap(|s| log::trace!("Select key: {s:?}"))
-                .to_string(),
-        )
     }
 
-    pub async fn remove(&self, key: &str) -> Option<usize> {
-        let mut delegate = self.inner.write().await;
-        let loc = delegate.iter().position(|x| x.eq(key))?;
-        delegate.swap_remove(loc);
-        log::trace!("Remove api key {key:?}");
-        Some(delegate.len())
+    /// Content type of Azure output, raw PCM has none browsers play
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Self::Ogg => "audio/ogg",
+            Self::WebM => "audio/webm",
+            Self::Raw => "application/octet-stream",
+            Self::Riff => "audio/wav",
+            Self::Mp3 => "audio/mpeg",
+        }
+    }
+}
+
+/// Azure `X-Microsoft-OutputFormat`, e.g. `ogg-48khz-16bit-mono-opus`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct OutputFormat {
+    name: String,
+    container: AudioContainer,
+    sample_rate: u32,
+}
+
+impl OutputFormat {
+    const DEFAULT: &str = "ogg-48khz-16bit-mono-opus";
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn container(&self) -> AudioContainer {
+        self.container
+    }
+
+    #[cfg_attr(not(feature = "transcode"), allow(dead_code))]
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.name.eq(Self::DEFAULT)
+    }
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        Self::try_from(Self::DEFAULT.to_string()).unwrap()
+    }
+}
+
+impl TryFrom<String> for OutputFormat {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        let container = match name.split('-').next() {
+            Some("ogg") if name.ends_with("-opus") => AudioContainer::Ogg,
+            Some("webm") if name.ends_with("-opus") => AudioContainer::WebM,
+            Some("raw") if name.ends_with("-16bit-mono-pcm") => AudioContainer::Raw,
+            Some("riff") if name.ends_with("-16bit-mono-pcm") => AudioContainer::Riff,
+            Some("audio") if name.ends_with("-mono-mp3") => AudioContainer::Mp3,
+            _ => return Err(format!("Unsupported output format: {name:?}")),
+        };
+        let sample_rate = name
+            .split('-')
+            .nth(1)
+            .and_then(|s| s.strip_suffix("khz"))
+            .and_then(|s| s.parse::<u32>().ok())
+            .ok_or_else(|| format!("Unable parse sample rate from {name:?}"))?
+            * 1000;
+        if container.need_transcode() {
+            if cfg!(not(feature = "transcode")) {
+                return Err(format!(
+                    "Output format {name:?} requires `transcode` feature"
+                ));
+            }
+            // Opus encoder only accepts these sample rates
+            if ![8000, 16000, 24000, 48000].contains(&sample_rate) {
+                return Err(format!("Sample rate of {name:?} is not supported"));
+            }
+        }
+        Ok(Self {
+            name,
+            container,
+            sample_rate,
+        })
     }
 }
 
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Debug, Deserialize)]
 pub struct TTS {
+    #[serde(default)]
     endpoint: String,
     #[serde(alias = "Ocp-Apim-Subscription-Key")]
     ocp_apim_subscription_key: KeyStore,
+    #[serde(default)]
+    strategy: KeyStrategy,
+    #[serde(default)]
+    interrupt: InterruptMode,
+    #[serde(default)]
+    format: OutputFormat,
+    #[serde(default = "default_tts_retries")]
+    retries: u32,
+    #[serde(default = "default_tts_revalidate")]
+    revalidate: u64,
+    #[serde(default = "default_tts_voices_refresh")]
+    voices_refresh: u64,
+    /// Longer messages are split at sentence boundaries, 0 disables
+    #[serde(default = "default_tts_chunk_size")]
+    chunk_size: usize,
+    #[serde(default = "default_tts_timeout")]
+    timeout: u64,
 }
 
 impl TTS {
@@ -175,30 +448,341 @@ impl TTS {
         &self.endpoint
     }
 
-    pub async fn ocp_apim_subscription_key(&self) -> anyhow::Result<String> {
-        self.ocp_apim_subscription_key.get_one().await
+    pub fn interrupt(&self) -> InterruptMode {
+        self.interrupt
+    }
+
+    pub fn format(&self) -> &OutputFormat {
+        &self.format
     }
 
-    pub async fn remove_key(&self, key: &str) -> anyhow::Result<()> {
-        self.ocp_apim_subscription_key
-            .remove(key)
-            .await
-            .ok_or_else(|| anyhow!("Key not found"))
-            .and_then(|size| {
-                (size == 0)
-                    .then_some(())
-                    .ok_or_else(|| anyhow!("Keystore is empty"))
-            })
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Interval to re-check disabled keys, `None` if disabled
+    pub fn revalidate(&self) -> Option<std::time::Duration> {
+        (self.revalidate > 0).then(|| std::time::Duration::from_secs(self.revalidate * 60))
+    }
+
+    /// Age of cached voice list before it is fetched again
+    pub fn voices_refresh(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.voices_refresh * 3600)
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Timeout of one Azure request, including audio download
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.timeout)
+    }
+
+    pub fn keys(&self) -> &KeyStore {
+        &self.ocp_apim_subscription_key
+    }
+
+    pub async fn ocp_apim_subscription_key(&self) -> anyhow::Result<KeyEndpoint> {
+        self.ocp_apim_subscription_key.get_one(self.strategy).await
     }
 }
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Web {
+    /// IP address, or `unix:` followed by the path of a unix domain socket
     listen: String,
+    #[serde(default = "default_web_port")]
     port: u16,
+    /// Prefix of every route, e.g. `/tts` if mounted at `https://example.com/tts/`
+    #[serde(default)]
+    base_path: String,
+    /// Permission bits of the unix domain socket, e.g. `0o660`
+    socket_mode: Option<u32>,
+    /// Owner uid of the unix domain socket
+    socket_owner: Option<u32>,
+    /// Owner gid of the unix domain socket
+    socket_group: Option<u32>,
+    admin_token: Option<String>,
+    /// Recent spoken messages kept for replay, 0 disables
+    #[serde(default = "default_web_history")]
+    history: usize,
+    /// Serve HTTPS instead of HTTP, needs the `rustls` feature
+    tls: Option<Tls>,
 }
 
 impl Web {
     pub fn bind(&self) -> String {
+        if self.listen.starts_with("unix:") {
+            return self.listen.clone();
+        }
         format!("{}:{}", self.listen, self.port)
     }
+
+    /// With a leading and without a trailing slash, empty at the root
+    pub fn base_path(&self) -> String {
+        let path = self.base_path.trim_matches('/');
+        if path.is_empty() {
+            String::new()
+        } else {
+            format!("/{path}")
+        }
+    }
+
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn socket_mode(&self) -> Option<u32> {
+        self.socket_mode
+    }
+
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn socket_owner(&self) -> Option<u32> {
+        self.socket_owner
+    }
+
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn socket_group(&self) -> Option<u32> {
+        self.socket_group
+    }
+
+    /// Bearer token for `/api/v1/*` admin endpoints, they are disabled if not set
+    pub fn admin_token(&self) -> Option<&str> {
+        self.admin_token.as_deref()
+    }
+
+    pub fn history(&self) -> usize {
+        self.history
+    }
+
+    pub fn tls(&self) -> Option<&Tls> {
+        self.tls.as_ref()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[cfg_attr(not(feature = "rustls"), allow(dead_code))]
+pub struct Tls {
+    /// PEM certificate chain
+    cert: String,
+    /// PEM private key
+    key: String,
+    /// Seconds between checks whether certificate or key changed, 0 disables
+    #[serde(default = "default_tls_reload")]
+    reload: u64,
+}
+
+#[cfg_attr(not(feature = "rustls"), allow(dead_code))]
+impl Tls {
+    pub fn cert(&self) -> &str {
+        &self.cert
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn reload(&self) -> Option<std::time::Duration> {
+        (self.reload > 0).then(|| std::time::Duration::from_secs(self.reload))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Recorder {
+    #[serde(default = "default_recorder_folder")]
+    folder: String,
+    /// Days to keep recordings, 0 means keep forever
+    #[serde(default = "default_recorder_retention")]
+    retention: u64,
+}
+
+impl Recorder {
+    pub fn folder(&self) -> &str {
+        &self.folder
+    }
+
+    pub fn retention(&self) -> Option<std::time::Duration> {
+        (self.retention > 0).then(|| std::time::Duration::from_secs(self.retention * 86400))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Stt {
+    /// Program and arguments, `{input}` is replaced by the utterance Ogg/Opus file
+    command: Vec<String>,
+    /// Milliseconds of silence that end an utterance
+    #[serde(default = "default_stt_silence")]
+    silence: u64,
+    /// Utterances shorter than this (milliseconds) are dropped
+    #[serde(default = "default_stt_min_duration")]
+    min_duration: u64,
+    /// Seconds before the command is killed
+    #[serde(default = "default_stt_timeout")]
+    timeout: u64,
+    /// Commands running at once, further utterances wait for their turn
+    #[serde(default = "default_stt_concurrency")]
+    concurrency: usize,
+    /// Post transcript to channel chat
+    #[serde(default = "default_true")]
+    chat: bool,
+}
+
+impl Stt {
+    pub fn command(&self) -> &[String] {
+        &self.command
+    }
+
+    pub fn silence(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.silence)
+    }
+
+    pub fn min_duration(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.min_duration)
+    }
+
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.timeout)
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency.max(1)
+    }
+
+    pub fn chat(&self) -> bool {
+        self.chat
+    }
+}
+
+/// Monthly Azure character budget, counted over all keys
+#[derive(Clone, Debug, Deserialize)]
+pub struct Usage {
+    /// Characters before web clients are warned
+    soft_limit: Option<u64>,
+    /// Characters before Azure is not requested anymore until next month
+    hard_limit: Option<u64>,
+    /// Program and arguments used over hard limit, reads text from stdin and writes
+    /// audio in `[tts] format` to stdout. Only cached messages are played if empty
+    #[serde(default)]
+    fallback: Vec<String>,
+    /// Seconds before the fallback command is killed
+    #[serde(default = "default_usage_timeout")]
+    timeout: u64,
+}
+
+impl Usage {
+    pub fn soft_limit(&self) -> Option<u64> {
+        self.soft_limit
+    }
+
+    pub fn hard_limit(&self) -> Option<u64> {
+        self.hard_limit
+    }
+
+    pub fn fallback(&self) -> Option<&[String]> {
+        (!self.fallback.is_empty()).then_some(self.fallback.as_slice())
+    }
+
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.timeout)
+    }
+}
+
+/// What preprocessing does with emoji
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmojiMode {
+    /// Replace by its name, e.g. "thumbs up"
+    #[default]
+    Describe,
+    Strip,
+    Keep,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Preprocess {
+    /// Whole words, case insensitive, e.g. `brb = "be right back"`
+    #[serde(default)]
+    abbreviations: HashMap<String, String>,
+    /// Replace URLs by "link to <host>"
+    #[serde(default = "default_true")]
+    urls: bool,
+    #[serde(default)]
+    emoji: EmojiMode,
+    /// Whole words, case insensitive, replaced by `censor`
+    #[serde(default)]
+    profanity: Vec<String>,
+    #[serde(default = "default_preprocess_censor")]
+    censor: String,
+    /// Longer runs of the same character are shortened, 0 disables
+    #[serde(default = "default_preprocess_max_repeat")]
+    max_repeat: usize,
+}
+
+impl Preprocess {
+    pub fn abbreviations(&self) -> &HashMap<String, String> {
+        &self.abbreviations
+    }
+
+    pub fn urls(&self) -> bool {
+        self.urls
+    }
+
+    pub fn emoji(&self) -> EmojiMode {
+        self.emoji
+    }
+
+    pub fn profanity(&self) -> &[String] {
+        &self.profanity
+    }
+
+    pub fn censor(&self) -> &str {
+        &self.censor
+    }
+
+    pub fn max_repeat(&self) -> usize {
+        self.max_repeat
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Language {
+    /// ISO 639-3 code to voice or preset name, e.g. `cmn = "zh-CN-XiaoxiaoNeural"`
+    voices: HashMap<String, String>,
+    /// Read mixed-language messages by several voices
+    #[serde(default = "default_true")]
+    split: bool,
+    /// Detections below this confidence (0.0 - 1.0) are ignored
+    #[serde(default)]
+    min_confidence: f64,
+}
+
+impl Language {
+    pub fn voices(&self) -> &HashMap<String, String> {
+        &self.voices
+    }
+
+    pub fn split(&self) -> bool {
+        self.split
+    }
+
+    pub fn min_confidence(&self) -> f64 {
+        self.min_confidence
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Audit {
+    /// JSON lines file every entry is appended to
+    file: Option<String>,
+    /// Latest entries kept in LevelDB for admin API, 0 disables
+    #[serde(default = "default_audit_retention")]
+    retention: u64,
+}
+
+impl Audit {
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    pub fn retention(&self) -> u64 {
+        self.retention
+    }
 }
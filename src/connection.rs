@@ -1,4 +1,7 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
 
 use futures::{channel::oneshot, future, StreamExt as _, TryStreamExt as _};
 use tap::TapOptional as _;
@@ -11,9 +14,12 @@ use tsclientlib::{
     Invoker, OutCommandExt, StreamItem,
 };
 use tsproto::Identity;
-use tsproto_packets::packets::OutCommand;
+use tsproto_packets::packets::{AudioData, CodecType, OutCommand};
 
-use crate::{config::Config, tts::TeamSpeakEvent};
+use crate::{
+    config::Config, metrics::Metrics, recorder::RecorderHelper, stt::TranscriberHelper,
+    tts::TeamSpeakEvent,
+};
 
 #[derive(Clone, Copy)]
 pub enum KickEvent {
@@ -82,6 +88,95 @@ fn find_self_and_target(
     None
 }
 
+fn normalize_channel_path(path: &str) -> String {
+    path.split('/')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn build_channel_path(state: &tsclientlib::data::Connection, channel_id: ChannelId) -> String {
+    let mut segments = Vec::new();
+    let mut current = state.channels.get(&channel_id);
+    // Depth limit prevents looping forever on a malformed channel tree
+    while let Some(channel) = current.filter(|_| segments.len() < 64) {
+        segments.push(channel.name.trim());
+        current = state.channels.get(&channel.parent);
+    }
+    segments.reverse();
+    segments.join("/")
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            current[j + 1] = (prev[j] + usize::from(ca != *cb))
+                .min(prev[j + 1] + 1)
+                .min(current[j] + 1);
+        }
+        prev = current;
+    }
+    prev[b.len()]
+}
+
+fn resolve_channel_path(
+    state: &tsclientlib::data::Connection,
+    path: &str,
+) -> anyhow::Result<ChannelId> {
+    find_channel(
+        state
+            .channels
+            .keys()
+            .map(|id| (*id, build_channel_path(state, *id)))
+            .collect(),
+        path,
+    )
+}
+
+/// Channel with `path` among full paths of the channel tree, error suggests similar ones
+fn find_channel(channels: Vec<(ChannelId, String)>, path: &str) -> anyhow::Result<ChannelId> {
+    let target = normalize_channel_path(path);
+    if let Some((id, _)) = channels.iter().find(|(_, name)| name.eq(&target)) {
+        return Ok(*id);
+    }
+
+    let lower_target = target.to_lowercase();
+    let last_segment = lower_target.rsplit('/').next().unwrap_or_default();
+    let threshold = (lower_target.chars().count() / 4).max(2);
+    let mut candidates = channels
+        .into_iter()
+        .filter_map(|(_, name)| {
+            let lower = name.to_lowercase();
+            let distance = edit_distance(&lower, &lower_target);
+            (distance <= threshold
+                || (!last_segment.is_empty()
+                    && lower
+                        .rsplit('/')
+                        .next()
+                        .is_some_and(|s| s.contains(last_segment))))
+            .then_some((distance, name))
+        })
+        .collect::<Vec<_>>();
+    candidates.sort();
+
+    if candidates.is_empty() {
+        return Err(anyhow::anyhow!("Channel {target:?} not found"));
+    }
+    Err(anyhow::anyhow!(
+        "Channel {target:?} not found, did you mean: {}",
+        candidates
+            .into_iter()
+            .take(5)
+            .map(|(_, name)| format!("{name:?}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))
+}
+
 fn get_invoker(invoker: &Option<Invoker>) -> String {
     if let Some(invoker) = invoker {
         format!(
@@ -140,6 +235,43 @@ fn check_is_kick_event(client_id: ClientId, events: &[tsclientlib::events::Event
     KickEvent::Reset
 }
 
+/// Consumers of voice received in bot's channel
+#[derive(Clone, Default)]
+pub struct VoiceListener {
+    recorder: Option<RecorderHelper>,
+    transcriber: Option<TranscriberHelper>,
+}
+
+impl VoiceListener {
+    pub fn new(recorder: Option<RecorderHelper>, transcriber: Option<TranscriberHelper>) -> Self {
+        Self {
+            recorder,
+            transcriber,
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.recorder.is_some() || self.transcriber.is_some()
+    }
+
+    async fn audio(&self, from: u16, name: String, channels: u8, data: Vec<u8>) {
+        if let Some(ref recorder) = self.recorder {
+            recorder
+                .audio(from, name.clone(), channels, data.clone())
+                .await;
+        }
+        if let Some(ref transcriber) = self.transcriber {
+            transcriber.audio(from, name, channels, data).await;
+        }
+    }
+
+    async fn leave(&self, client: u16) {
+        if let Some(ref recorder) = self.recorder {
+            recorder.leave(client).await;
+        }
+    }
+}
+
 pub struct ConnectionHandler {
     handle: JoinHandle<anyhow::Result<()>>,
 }
@@ -151,13 +283,17 @@ impl ConnectionHandler {
         log_command: bool,
         receiver: mpsc::Receiver<TeamSpeakEvent>,
         override_server: Option<String>,
+        listener: VoiceListener,
+        metrics: Metrics,
     ) -> anyhow::Result<(Self, oneshot::Receiver<()>)> {
         let teamspeak_options =
             Connection::build(override_server.unwrap_or_else(|| config.teamspeak().server()))
                 .log_commands(verbose >= 5 || log_command)
                 .log_packets(verbose >= 6)
                 .log_udp_packets(verbose >= 7)
-                .channel_id(tsclientlib::ChannelId(config.teamspeak().channel()))
+                .channel_id(tsclientlib::ChannelId(
+                    config.teamspeak().channel().id().unwrap_or_default(),
+                ))
                 .password(config.teamspeak().password().to_owned())
                 .version(if cfg!(windows) {
                     tsclientlib::Version::Windows_3_6_0__14
@@ -176,8 +312,11 @@ impl ConnectionHandler {
                 .identity(Identity::new_from_str(config.teamspeak().identity())?);
         let handle = tokio::spawn(Self::run(
             teamspeak_options.connect()?,
+            config.teamspeak().channel().path().map(str::to_string),
             config.teamspeak().follow(),
             receiver,
+            listener,
+            metrics,
         ));
 
         let (sender, exit_receiver) = oneshot::channel();
@@ -200,8 +339,11 @@ impl ConnectionHandler {
 
     async fn run(
         mut conn: Connection,
+        default_channel: Option<String>,
         tail_target: Option<ClientDbId>,
         mut receiver: mpsc::Receiver<TeamSpeakEvent>,
+        listener: VoiceListener,
+        metrics: Metrics,
     ) -> anyhow::Result<()> {
         if let Some(r) = conn
             .events()
@@ -211,11 +353,28 @@ impl ConnectionHandler {
         {
             r?;
         }
+        metrics.teamspeak_connected(true);
 
         tsclientlib::messages::c2s::OutChannelSubscribeAllMessage::new()
             .send(&mut conn)
             .unwrap();
 
+        if let Some(path) = default_channel {
+            let state = conn.get_state()?;
+            let current_user = state.own_client;
+            // Not fatal, bot stays in the channel server put it in
+            if let Ok(channel_id) = resolve_channel_path(state, &path).inspect_err(|e| {
+                log::error!("Unable resolve default channel, staying in server default: {e}")
+            }) && state
+                .clients
+                .get(&current_user)
+                .is_none_or(|client| client.channel != channel_id)
+            {
+                log::debug!("Resolved channel {path:?} to {channel_id}");
+                make_out_message(current_user, channel_id).send(&mut conn)?;
+            }
+        }
+
         let mut tail_target_client = None;
         let mut current_channel = None;
         let client_id = conn.get_state().unwrap().own_client;
@@ -225,6 +384,9 @@ impl ConnectionHandler {
         let mut measure_timer = tokio::time::interval(std::time::Duration::from_secs(60));
 
         let mut refresh = true;
+        // Speakers and recording flag are checked again after book events only
+        let mut speakers_stale = true;
+        let speakers: Arc<RwLock<HashMap<ClientId, String>>> = Default::default();
 
         #[cfg(feature = "measure-time")]
         let mut start = tokio::time::Instant::now();
@@ -246,6 +408,7 @@ impl ConnectionHandler {
                     KickEvent::Channel => {
                         current_channel.take();
                         refresh = true;
+                        speakers_stale = true;
                     }
                 }
             }
@@ -272,13 +435,25 @@ impl ConnectionHandler {
                 }
             }
 
+            if listener.is_enabled() && speakers_stale {
+                for client in Self::update_recording_state(&mut conn, &speakers) {
+                    listener.leave(client.0).await;
+                }
+                speakers_stale = false;
+            }
+
             let notify_waiter = notifier.clone();
             let events = conn.events().try_for_each(|event| {
                 let notify = notifier.clone();
                 let exit_notifier = exit_notifier.clone();
+                let listener = listener.clone();
+                let speakers = speakers.clone();
+                let metrics = metrics.clone();
                 async move {
                     match event {
                         StreamItem::BookEvents(event) => {
+                            // Reconnected, book events only arrive while connected
+                            metrics.teamspeak_connected(true);
                             let event = check_is_kick_event(client_id, &event);
                             match event {
                                 KickEvent::Reset => {}
@@ -292,6 +467,31 @@ impl ConnectionHandler {
                         StreamItem::MessageEvent(_) => {
                             notify.notify_waiters();
                         }
+                        StreamItem::DisconnectedTemporarily(_) => {
+                            metrics.teamspeak_reconnect();
+                        }
+                        StreamItem::Audio(packet) => {
+                            if !listener.is_enabled() {
+                                return Ok(());
+                            }
+                            let (from, channels, data) = match packet.data().data() {
+                                AudioData::S2C {
+                                    from, codec, data, ..
+                                } if !data.is_empty() => match codec {
+                                    CodecType::OpusVoice => (*from, 1, data.to_vec()),
+                                    CodecType::OpusMusic => (*from, 2, data.to_vec()),
+                                    _ => return Ok(()),
+                                },
+                                _ => return Ok(()),
+                            };
+                            let name = speakers
+                                .read()
+                                .unwrap()
+                                .get(&ClientId(from))
+                                .cloned()
+                                .unwrap_or_default();
+                            listener.audio(from, name, channels, data).await;
+                        }
                         _ => {}
                     }
                     Ok(())
@@ -324,12 +524,14 @@ impl ConnectionHandler {
                 }
                 _ =  async move {
                     notify_waiter.notified().await;
-                }, if tail_target.is_some() => {
+                }, if tail_target.is_some() || listener.is_enabled() => {
                     refresh = true;
+                    speakers_stale = true;
                 }
                 _ = measure_timer.tick() => {
                     current_channel.take();
                     refresh = true;
+                    speakers_stale = true;
                 }
                 ret = events => {
                     ret?;
@@ -345,9 +547,51 @@ impl ConnectionHandler {
             )?;
         }
         conn.events().for_each(|_| future::ready(())).await;
+        metrics.teamspeak_connected(false);
         Ok(())
     }
 
+    /// Refresh speaker names in bot's channel and keep recording flag visible after reconnect,
+    /// return speakers who left the channel
+    fn update_recording_state(
+        conn: &mut Connection,
+        speakers: &RwLock<HashMap<ClientId, String>>,
+    ) -> Vec<ClientId> {
+        let state = match conn.get_state() {
+            Ok(state) => state,
+            Err(e) => {
+                log::warn!("Get state error: {e:?}");
+                return Vec::new();
+            }
+        };
+        let Some(own) = state.clients.get(&state.own_client) else {
+            return Vec::new();
+        };
+        let need_flag = !own.is_recording;
+        let current: HashMap<ClientId, String> = state
+            .clients
+            .values()
+            .filter(|client| client.channel == own.channel)
+            .map(|client| (client.id, client.name.clone()))
+            .collect();
+        let left = speakers
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|client| !current.contains_key(client))
+            .copied()
+            .collect();
+        *speakers.write().unwrap() = current;
+
+        if need_flag {
+            match TeamSpeakEvent::Recording(true).to_packet().send(conn) {
+                Ok(_) => log::info!("Recording flag enabled"),
+                Err(e) => log::warn!("Unable set recording flag: {e:?}"),
+            }
+        }
+        left
+    }
+
     async fn handle_packet(
         packet: TeamSpeakEvent,
         conn: &mut Connection,
@@ -358,6 +602,15 @@ impl ConnectionHandler {
                 packet.to_packet().send(conn)?;
                 *is_muted = muted;
             }
+            TeamSpeakEvent::Recording(_) => {
+                packet.to_packet().send(conn)?;
+            }
+            TeamSpeakEvent::Message(message) => {
+                let command = conn
+                    .get_state()?
+                    .send_message(tsclientlib::MessageTarget::Channel, &message);
+                command.send(conn)?;
+            }
             TeamSpeakEvent::Data(packet) => {
                 if *is_muted {
                     TeamSpeakEvent::Muted(false).to_packet().send(conn)?;
@@ -375,3 +628,81 @@ impl ConnectionHandler {
         self.handle.await?
     }
 }
+
+#[cfg(test)]
+mod test {
+    use tsclientlib::ChannelId;
+
+    use super::{edit_distance, find_channel, normalize_channel_path};
+
+    fn channels() -> Vec<(ChannelId, String)> {
+        [
+            "Lobby",
+            "Gaming",
+            "Gaming/Raid Room",
+            "Gaming/Raid Room/AFK",
+            "Music",
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(id, path)| (ChannelId(id as u64 + 1), path.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn test_normalize_channel_path() {
+        assert_eq!(
+            normalize_channel_path("Gaming/Raid Room"),
+            "Gaming/Raid Room"
+        );
+        assert_eq!(
+            normalize_channel_path(" /Gaming /  Raid Room// "),
+            "Gaming/Raid Room"
+        );
+        assert_eq!(normalize_channel_path("/"), "");
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("lobby", ""), 5);
+        assert_eq!(edit_distance("raid", "raid"), 0);
+        assert_eq!(edit_distance("raid", "rad"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        // Counts characters, not bytes
+        assert_eq!(edit_distance("大厅", "大厅1"), 1);
+    }
+
+    #[test]
+    fn test_find_channel() {
+        let find = |path: &str| find_channel(channels(), path);
+
+        assert_eq!(find("Lobby").unwrap(), ChannelId(1));
+        // Nested paths, the same name under other parents does not match
+        assert_eq!(find("Gaming/Raid Room").unwrap(), ChannelId(3));
+        assert_eq!(find("Gaming/Raid Room/AFK").unwrap(), ChannelId(4));
+        assert!(find("AFK").is_err());
+        // Whitespace around segments is ignored
+        assert_eq!(find(" Gaming / Raid Room ").unwrap(), ChannelId(3));
+
+        // Names are matched exactly, other case is only suggested
+        assert_eq!(
+            find("gaming/raid room").unwrap_err().to_string(),
+            "Channel \"gaming/raid room\" not found, did you mean: \"Gaming/Raid Room\", \
+            \"Gaming/Raid Room/AFK\""
+        );
+        assert_eq!(
+            find("Gaming/Raid Rom").unwrap_err().to_string(),
+            "Channel \"Gaming/Raid Rom\" not found, did you mean: \"Gaming/Raid Room\""
+        );
+        // Last segment found under another parent
+        assert_eq!(
+            find("Music/AFK").unwrap_err().to_string(),
+            "Channel \"Music/AFK\" not found, did you mean: \"Gaming/Raid Room/AFK\""
+        );
+        assert_eq!(
+            find("Nowhere").unwrap_err().to_string(),
+            "Channel \"Nowhere\" not found"
+        );
+    }
+}
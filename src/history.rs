@@ -0,0 +1,168 @@
+//! Recent spoken messages shared by every web client, audio is replayed from cache
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::{cache::ConnAgent, types::current_timestamp};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HistoryEntry {
+    pub id: u64,
+    /// Unix timestamp
+    pub timestamp: u64,
+    /// As sent by the client, before preprocessing
+    pub text: String,
+    /// Voices reading the message, comma separated
+    pub voice: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Cache keys of audio, one per chunk
+    pub audio: Vec<u64>,
+}
+
+pub struct History {
+    leveldb: ConnAgent,
+    size: usize,
+    entries: RwLock<VecDeque<HistoryEntry>>,
+}
+
+impl History {
+    pub fn new(leveldb: ConnAgent, size: usize) -> Self {
+        Self {
+            leveldb,
+            size,
+            entries: Default::default(),
+        }
+    }
+
+    const RECORD: &str = "history";
+
+    pub fn is_enabled(&self) -> bool {
+        self.size > 0
+    }
+
+    /// Restore history of last run
+    pub async fn load(&self) {
+        if !self.is_enabled() {
+            return;
+        }
+        let Some(raw) = self.leveldb.get_record(Self::RECORD).await else {
+            return;
+        };
+        match serde_json::from_slice::<VecDeque<HistoryEntry>>(&raw) {
+            Ok(mut entries) => {
+                while entries.len() > self.size {
+                    entries.pop_front();
+                }
+                *self.entries.write().await = entries;
+            }
+            Err(e) => log::error!("Unable decode stored history: {e:?}"),
+        }
+    }
+
+    /// Append spoken message, `None` if history is disabled
+    pub async fn push(
+        &self,
+        text: String,
+        voice: String,
+        user: Option<String>,
+        audio: Vec<u64>,
+    ) -> Option<HistoryEntry> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let mut entries = self.entries.write().await;
+        let entry = HistoryEntry {
+            id: entries.back().map_or(1, |last| last.id + 1),
+            timestamp: current_timestamp(),
+            text,
+            voice,
+            user,
+            audio,
+        };
+        entries.push_back(entry.clone());
+        while entries.len() > self.size {
+            entries.pop_front();
+        }
+        match serde_json::to_vec(&*entries) {
+            Ok(raw) => {
+                self.leveldb
+                    .set_record(Self::RECORD, raw)
+                    .await
+                    .inspect_err(|e| log::error!("Unable write history: {e:?}"))
+                    .ok();
+            }
+            Err(e) => log::error!("Unable encode history: {e:?}"),
+        }
+        Some(entry)
+    }
+
+    /// Oldest first
+    pub async fn entries(&self) -> Vec<HistoryEntry> {
+        self.entries.read().await.iter().cloned().collect()
+    }
+
+    pub async fn get(&self, id: u64) -> Option<HistoryEntry> {
+        self.entries
+            .read()
+            .await
+            .iter()
+            .find(|entry| entry.id == id)
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::History;
+    use crate::cache::LevelDB;
+
+    #[tokio::test]
+    async fn test_history() {
+        let (agent, db) = LevelDB::new_in_memory();
+        let history = History::new(agent.clone(), 2);
+        for text in ["one", "two", "three"] {
+            let entry = history
+                .push(
+                    text.to_string(),
+                    "en-US-AvaNeural".to_string(),
+                    None,
+                    vec![1],
+                )
+                .await
+                .unwrap();
+            assert_eq!(entry.text, text);
+        }
+        assert!(history.get(1).await.is_none());
+        assert_eq!(history.get(3).await.unwrap().text, "three");
+
+        // Restored after restart, ids keep counting
+        let restored = History::new(agent.clone(), 2);
+        restored.load().await;
+        assert_eq!(
+            restored
+                .entries()
+                .await
+                .into_iter()
+                .map(|entry| entry.id)
+                .collect::<Vec<_>>(),
+            [2, 3]
+        );
+        let entry = restored
+            .push("four".to_string(), String::new(), None, Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(entry.id, 4);
+
+        let disabled = History::new(agent, 0);
+        assert!(
+            disabled
+                .push(String::new(), String::new(), None, Vec::new())
+                .await
+                .is_none()
+        );
+        db.disconnect().await.unwrap();
+    }
+}
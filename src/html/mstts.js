@@ -1 +0,0 @@
-var MSTTS = { "Female": { "af-ZA": ["AdriNeural"], "am-ET": ["MekdesNeural"], "ar-AE": ["FatimaNeural"], "ar-BH": ["LailaNeural"], "ar-DZ": ["AminaNeural"], "ar-EG": ["SalmaNeural"], "ar-IQ": ["RanaNeural"], "ar-JO": ["SanaNeural"], "ar-KW": ["NouraNeural"], "ar-LB": ["LaylaNeural"], "ar-LY": ["ImanNeural"], "ar-MA": ["MounaNeural"], "ar-OM": ["AyshaNeural"], "ar-QA": ["AmalNeural"], "ar-SA": ["ZariyahNeural"], "ar-SY": ["AmanyNeural"], "ar-TN": ["ReemNeural"], "ar-YE": ["MaryamNeural"], "as-IN": ["YashicaNeural"], "az-AZ": ["BanuNeural"], "bg-BG": ["KalinaNeural"], "bn-BD": ["NabanitaNeural"], "bn-IN": ["TanishaaNeural"], "bs-BA": ["VesnaNeural"], "ca-ES": ["JoanaNeural", "AlbaNeural"], "cs-CZ": ["VlastaNeural"], "cy-GB": ["NiaNeural"], "da-DK": ["ChristelNeural"], "de-AT": ["IngridNeural"], "de-CH": ["LeniNeural"], "de-DE": ["KatjaNeural", "AmalaNeural", "ElkeNeural", "KlarissaNeural", "LouisaNeural", "MajaNeural", "SeraphinaMultilingualNeural", "TanjaNeural"], "el-GR": ["AthinaNeural"], "en-AU": ["NatashaNeural", "AnnetteNeural", "CarlyNeural", "ElsieNeural", "FreyaNeural", "JoanneNeural", "KimNeural", "TinaNeural"], "en-CA": ["ClaraNeural"], "en-GB": ["SoniaNeural", "LibbyNeural", "AbbiNeural", "BellaNeural", "HollieNeural", "OliviaNeural", "AdaMultilingualNeural"], "en-HK": ["YanNeural"], "en-IE": ["EmilyNeural"], "en-IN": ["NeerjaNeural", "AashiNeural", "AnanyaNeural", "KavyaNeural"], "en-KE": ["AsiliaNeural"], "en-NG": ["EzinneNeural"], "en-NZ": ["MollyNeural"], "en-PH": ["RosaNeural"], "en-SG": ["LunaNeural"], "en-TZ": ["ImaniNeural"], "en-US": ["AvaMultilingualNeural", "EmmaMultilingualNeural", "AvaNeural", "EmmaNeural", "JennyNeural", "AriaNeural", "JaneNeural", "SaraNeural", "NancyNeural", "AmberNeural", "AshleyNeural", "CoraNeural", "ElizabethNeural", "JennyMultilingualNeural", "MichelleNeural", "MonicaNeural", "AIGenerate2Neural", "AmandaMultilingualNeural", "CoraMultilingualNeural", "EvelynMultilingualNeural", "LolaMultilingualNeural", "LunaNeural", "NancyMultilingualNeural", "NovaTurboMultilingualNeural", "PhoebeMultilingualNeural", "SerenaMultilingualNeural", "NovaMultilingualNeural", "ShimmerMultilingualNeural", "NovaMultilingualNeuralHD", "ShimmerMultilingualNeuralHD"], "en-ZA": ["LeahNeural"], "es-AR": ["ElenaNeural"], "es-BO": ["SofiaNeural"], "es-CL": ["CatalinaNeural"], "es-CO": ["SalomeNeural"], "es-CR": ["MariaNeural"], "es-CU": ["BelkysNeural"], "es-DO": ["RamonaNeural"], "es-EC": ["AndreaNeural"], "es-ES": ["ElviraNeural", "AbrilNeural", "EstrellaNeural", "IreneNeural", "LaiaNeural", "LiaNeural", "TrianaNeural", "VeraNeural", "XimenaNeural", "ArabellaMultilingualNeural", "IsidoraMultilingualNeural", "XimenaMultilingualNeural"], "es-GQ": ["TeresaNeural"], "es-GT": ["MartaNeural"], "es-HN": ["KarlaNeural"], "es-MX": ["DaliaNeural", "BeatrizNeural", "CandelaNeural", "CarlotaNeural", "LarissaNeural", "MarinaNeural", "NuriaNeural", "RenataNeural"], "es-NI": ["YolandaNeural"], "es-PA": ["MargaritaNeural"], "es-PE": ["CamilaNeural"], "es-PR": ["KarinaNeural"], "es-PY": ["TaniaNeural"], "es-SV": ["LorenaNeural"], "es-US": ["PalomaNeural"], "es-UY": ["ValentinaNeural"], "es-VE": ["PaolaNeural"], "et-EE": ["AnuNeural"], "eu-ES": ["AinhoaNeural"], "fa-IR": ["DilaraNeural"], "fi-FI": ["SelmaNeural", "NooraNeural"], "fil-PH": ["BlessicaNeural"], "fr-BE": ["CharlineNeural"], "fr-CA": ["SylvieNeural"], "fr-CH": ["ArianeNeural"], "fr-FR": ["DeniseNeural", "BrigitteNeural", "CelesteNeural", "CoralieNeural", "JacquelineNeural", "JosephineNeural", "VivienneMultilingualNeural", "YvetteNeural"], "ga-IE": ["OrlaNeural"], "gl-ES": ["SabelaNeural"], "gu-IN": ["DhwaniNeural"], "he-IL": ["HilaNeural"], "hi-IN": ["SwaraNeural", "AnanyaNeural", "KavyaNeural"], "hr-HR": ["GabrijelaNeural"], "hu-HU": ["NoemiNeural"], "hy-AM": ["AnahitNeural"], "id-ID": ["GadisNeural"], "is-IS": ["GudrunNeural"], "it-IT": ["ElsaNeural", "IsabellaNeural", "FabiolaNeural", "FiammaNeural", "ImeldaNeural", "IrmaNeural", "PalmiraNeural", "PierinaNeural", "IsabellaMultilingualNeural"], "iu-CANS-CA": ["iu-Cans-CA-SiqiniqNeural"], "iu-LATN-CA": ["iu-Latn-CA-SiqiniqNeural"], "ja-JP": ["NanamiNeural", "AoiNeural", "MayuNeural", "ShioriNeural"], "jv-ID": ["SitiNeural"], "ka-GE": ["EkaNeural"], "kk-KZ": ["AigulNeural"], "km-KH": ["SreymomNeural"], "kn-IN": ["SapnaNeural"], "ko-KR": ["SunHiNeural", "JiMinNeural", "SeoHyeonNeural", "SoonBokNeural", "YuJinNeural"], "lo-LA": ["KeomanyNeural"], "lt-LT": ["OnaNeural"], "lv-LV": ["EveritaNeural"], "mk-MK": ["MarijaNeural"], "ml-IN": ["SobhanaNeural"], "mn-MN": ["YesuiNeural"], "mr-IN": ["AarohiNeural"], "ms-MY": ["YasminNeural"], "mt-MT": ["GraceNeural"], "my-MM": ["NilarNeural"], "nb-NO": ["PernilleNeural", "IselinNeural"], "ne-NP": ["HemkalaNeural"], "nl-BE": ["DenaNeural"], "nl-NL": ["FennaNeural", "ColetteNeural"], "or-IN": ["SubhasiniNeural"], "pa-IN": ["VaaniNeural"], "pl-PL": ["AgnieszkaNeural", "ZofiaNeural"], "ps-AF": ["LatifaNeural"], "pt-BR": ["FranciscaNeural", "BrendaNeural", "ElzaNeural", "GiovannaNeural", "LeilaNeural", "LeticiaNeural", "ManuelaNeural", "ThalitaNeural", "YaraNeural", "ThalitaMultilingualNeural"], "pt-PT": ["RaquelNeural", "FernandaNeural"], "ro-RO": ["AlinaNeural"], "ru-RU": ["SvetlanaNeural", "DariyaNeural"], "si-LK": ["ThiliniNeural"], "sk-SK": ["ViktoriaNeural"], "sl-SI": ["PetraNeural"], "so-SO": ["UbaxNeural"], "sq-AL": ["AnilaNeural"], "sr-LATN-RS": ["sr-Latn-RS-SophieNeural"], "sr-RS": ["SophieNeural"], "su-ID": ["TutiNeural"], "sv-SE": ["SofieNeural", "HilleviNeural"], "sw-KE": ["ZuriNeural"], "sw-TZ": ["RehemaNeural"], "ta-IN": ["PallaviNeural"], "ta-LK": ["SaranyaNeural"], "ta-MY": ["KaniNeural"], "ta-SG": ["VenbaNeural"], "te-IN": ["ShrutiNeural"], "th-TH": ["PremwadeeNeural", "AcharaNeural"], "tr-TR": ["EmelNeural"], "uk-UA": ["PolinaNeural"], "ur-IN": ["GulNeural"], "ur-PK": ["UzmaNeural"], "uz-UZ": ["MadinaNeural"], "vi-VN": ["HoaiMyNeural"], "wuu-CN": ["XiaotongNeural"], "yue-CN": ["XiaoMinNeural"], "zh-CN": ["XiaoxiaoNeural", "XiaoyiNeural", "XiaochenNeural", "XiaochenMultilingualNeural", "XiaohanNeural", "XiaomengNeural", "XiaomoNeural", "XiaoqiuNeural", "XiaoruiNeural", "XiaoxiaoDialectsNeural", "XiaoxiaoMultilingualNeural", "XiaoyanNeural", "XiaoyuMultilingualNeural", "XiaozhenNeural"], "zh-CN-liaoning": ["XiaobeiNeural"], "zh-CN-shaanxi": ["XiaoniNeural"], "zh-HK": ["HiuMaanNeural", "HiuGaaiNeural"], "zh-TW": ["HsiaoChenNeural", "HsiaoYuNeural"], "zu-ZA": ["ThandoNeural"] }, "Male": { "af-ZA": ["WillemNeural"], "am-ET": ["AmehaNeural"], "ar-AE": ["HamdanNeural"], "ar-BH": ["AliNeural"], "ar-DZ": ["IsmaelNeural"], "ar-EG": ["ShakirNeural"], "ar-IQ": ["BasselNeural"], "ar-JO": ["TaimNeural"], "ar-KW": ["FahedNeural"], "ar-LB": ["RamiNeural"], "ar-LY": ["OmarNeural"], "ar-MA": ["JamalNeural"], "ar-OM": ["AbdullahNeural"], "ar-QA": ["MoazNeural"], "ar-SA": ["HamedNeural"], "ar-SY": ["LaithNeural"], "ar-TN": ["HediNeural"], "ar-YE": ["SalehNeural"], "as-IN": ["PriyomNeural"], "az-AZ": ["BabekNeural"], "bg-BG": ["BorislavNeural"], "bn-BD": ["PradeepNeural"], "bn-IN": ["BashkarNeural"], "bs-BA": ["GoranNeural"], "ca-ES": ["EnricNeural"], "cs-CZ": ["AntoninNeural"], "cy-GB": ["AledNeural"], "da-DK": ["JeppeNeural"], "de-AT": ["JonasNeural"], "de-CH": ["JanNeural"], "de-DE": ["ConradNeural", "BerndNeural", "ChristophNeural", "FlorianMultilingualNeural", "KasperNeural", "KillianNeural", "KlausNeural", "RalfNeural"], "el-GR": ["NestorasNeural"], "en-AU": ["WilliamNeural", "DarrenNeural", "DuncanNeural", "KenNeural", "NeilNeural", "TimNeural"], "en-CA": ["LiamNeural"], "en-GB": ["RyanNeural", "AlfieNeural", "ElliotNeural", "EthanNeural", "NoahNeural", "OliverNeural", "ThomasNeural", "OllieMultilingualNeural"], "en-HK": ["SamNeural"], "en-IE": ["ConnorNeural"], "en-IN": ["PrabhatNeural", "AaravNeural", "KunalNeural", "RehaanNeural"], "en-KE": ["ChilembaNeural"], "en-NG": ["AbeoNeural"], "en-NZ": ["MitchellNeural"], "en-PH": ["JamesNeural"], "en-SG": ["WayneNeural"], "en-TZ": ["ElimuNeural"], "en-US": ["AndrewMultilingualNeural", "BrianMultilingualNeural", "AndrewNeural", "BrianNeural", "GuyNeural", "DavisNeural", "JasonNeural", "TonyNeural", "BrandonNeural", "ChristopherNeural", "EricNeural", "JacobNeural", "RogerNeural", "RyanMultilingualNeural", "SteffanNeural", "AdamMultilingualNeural", "AIGenerate1Neural", "AlloyTurboMultilingualNeural", "BrandonMultilingualNeural", "ChristopherMultilingualNeural", "DavisMultilingualNeural", "DerekMultilingualNeural", "DustinMultilingualNeural", "KaiNeural", "LewisMultilingualNeural", "SamuelMultilingualNeural", "SteffanMultilingualNeural", "AlloyMultilingualNeural", "EchoMultilingualNeural", "OnyxMultilingualNeural", "AlloyMultilingualNeuralHD", "EchoMultilingualNeuralHD", "OnyxMultilingualNeuralHD"], "en-ZA": ["LukeNeural"], "es-AR": ["TomasNeural"], "es-BO": ["MarceloNeural"], "es-CL": ["LorenzoNeural"], "es-CO": ["GonzaloNeural"], "es-CR": ["JuanNeural"], "es-CU": ["ManuelNeural"], "es-DO": ["EmilioNeural"], "es-EC": ["LuisNeural"], "es-ES": ["AlvaroNeural", "ArnauNeural", "DarioNeural", "EliasNeural", "NilNeural", "SaulNeural", "TeoNeural", "TristanMultilingualNeural"], "es-GQ": ["JavierNeural"], "es-GT": ["AndresNeural"], "es-HN": ["CarlosNeural"], "es-MX": ["JorgeNeural", "CecilioNeural", "GerardoNeural", "LibertoNeural", "LucianoNeural", "PelayoNeural", "YagoNeural"], "es-NI": ["FedericoNeural"], "es-PA": ["RobertoNeural"], "es-PE": ["AlexNeural"], "es-PR": ["VictorNeural"], "es-PY": ["MarioNeural"], "es-SV": ["RodrigoNeural"], "es-US": ["AlonsoNeural"], "es-UY": ["MateoNeural"], "es-VE": ["SebastianNeural"], "et-EE": ["KertNeural"], "eu-ES": ["AnderNeural"], "fa-IR": ["FaridNeural"], "fi-FI": ["HarriNeural"], "fil-PH": ["AngeloNeural"], "fr-BE": ["GerardNeural"], "fr-CA": ["JeanNeural", "AntoineNeural", "ThierryNeural"], "fr-CH": ["FabriceNeural"], "fr-FR": ["HenriNeural", "AlainNeural", "ClaudeNeural", "JeromeNeural", "MauriceNeural", "RemyMultilingualNeural", "YvesNeural", "LucienMultilingualNeural"], "ga-IE": ["ColmNeural"], "gl-ES": ["RoiNeural"], "gu-IN": ["NiranjanNeural"], "he-IL": ["AvriNeural"], "hi-IN": ["MadhurNeural", "AaravNeural", "KunalNeural", "RehaanNeural"], "hr-HR": ["SreckoNeural"], "hu-HU": ["TamasNeural"], "hy-AM": ["HaykNeural"], "id-ID": ["ArdiNeural"], "is-IS": ["GunnarNeural"], "it-IT": ["DiegoNeural", "BenignoNeural", "CalimeroNeural", "CataldoNeural", "GianniNeural", "GiuseppeNeural", "LisandroNeural", "RinaldoNeural", "AlessioMultilingualNeural", "GiuseppeMultilingualNeural", "MarcelloMultilingualNeural"], "iu-CANS-CA": ["iu-Cans-CA-TaqqiqNeural"], "iu-LATN-CA": ["iu-Latn-CA-TaqqiqNeural"], "ja-JP": ["KeitaNeural", "DaichiNeural", "NaokiNeural", "MasaruMultilingualNeural"], "jv-ID": ["DimasNeural"], "ka-GE": ["GiorgiNeural"], "kk-KZ": ["DauletNeural"], "km-KH": ["PisethNeural"], "kn-IN": ["GaganNeural"], "ko-KR": ["InJoonNeural", "BongJinNeural", "GookMinNeural", "HyunsuNeural", "HyunsuMultilingualNeural"], "lo-LA": ["ChanthavongNeural"], "lt-LT": ["LeonasNeural"], "lv-LV": ["NilsNeural"], "mk-MK": ["AleksandarNeural"], "ml-IN": ["MidhunNeural"], "mn-MN": ["BataaNeural"], "mr-IN": ["ManoharNeural"], "ms-MY": ["OsmanNeural"], "mt-MT": ["JosephNeural"], "my-MM": ["ThihaNeural"], "nb-NO": ["FinnNeural"], "ne-NP": ["SagarNeural"], "nl-BE": ["ArnaudNeural"], "nl-NL": ["MaartenNeural"], "or-IN": ["SukantNeural"], "pa-IN": ["OjasNeural"], "pl-PL": ["MarekNeural"], "ps-AF": ["GulNawazNeural"], "pt-BR": ["AntonioNeural", "DonatoNeural", "FabioNeural", "HumbertoNeural", "JulioNeural", "NicolauNeural", "ValerioNeural", "MacerioMultilingualNeural"], "pt-PT": ["DuarteNeural"], "ro-RO": ["EmilNeural"], "ru-RU": ["DmitryNeural"], "si-LK": ["SameeraNeural"], "sk-SK": ["LukasNeural"], "sl-SI": ["RokNeural"], "so-SO": ["MuuseNeural"], "sq-AL": ["IlirNeural"], "sr-LATN-RS": ["sr-Latn-RS-NicholasNeural"], "sr-RS": ["NicholasNeural"], "su-ID": ["JajangNeural"], "sv-SE": ["MattiasNeural"], "sw-KE": ["RafikiNeural"], "sw-TZ": ["DaudiNeural"], "ta-IN": ["ValluvarNeural"], "ta-LK": ["KumarNeural"], "ta-MY": ["SuryaNeural"], "ta-SG": ["AnbuNeural"], "te-IN": ["MohanNeural"], "th-TH": ["NiwatNeural"], "tr-TR": ["AhmetNeural"], "uk-UA": ["OstapNeural"], "ur-IN": ["SalmanNeural"], "ur-PK": ["AsadNeural"], "uz-UZ": ["SardorNeural"], "vi-VN": ["NamMinhNeural"], "wuu-CN": ["YunzheNeural"], "yue-CN": ["YunSongNeural"], "zh-CN": ["YunxiNeural", "YunjianNeural", "YunyangNeural", "YunfengNeural", "YunhaoNeural", "YunjieNeural", "YunxiaNeural", "YunyeNeural", "YunyiMultilingualNeural", "YunzeNeural", "YunfanMultilingualNeural", "YunxiaoMultilingualNeural"], "zh-CN-GUANGXI": ["zh-CN-guangxi-YunqiNeural"], "zh-CN-henan": ["YundengNeural"], "zh-CN-liaoning": ["YunbiaoNeural"], "zh-CN-shandong": ["YunxiangNeural"], "zh-CN-sichuan": ["YunxiNeural"], "zh-HK": ["WanLungNeural"], "zh-TW": ["YunJheNeural"], "zu-ZA": ["ThembaNeural"] }, "Female, Child": { "de-DE": ["GiselaNeural"], "en-GB": ["MaisieNeural"], "en-US": ["AnaNeural"], "fr-FR": ["EloiseNeural"], "zh-CN": ["XiaoshuangNeural", "XiaoyouNeural"] }, "Neutral": { "en-US": ["BlueNeural", "FableMultilingualNeural", "FableMultilingualNeuralHD"] } };
\ No newline at end of file
@@ -0,0 +1,454 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tap::Tap;
+use tokio::{sync::RwLock, time::Instant};
+
+use crate::{
+    config::ArrayOrSingle,
+    types::{current_month, current_timestamp},
+};
+
+fn default_key_quota() -> u64 {
+    // Azure free tier
+    500_000
+}
+
+/// How a key is picked for each request
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyStrategy {
+    #[default]
+    Random,
+    RoundRobin,
+    /// Random, weighted by characters left in this month's quota
+    Weighted,
+    /// Always the first usable key in configured order
+    Fallback,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum KeyConfig {
+    Plain(String),
+    Detailed {
+        key: String,
+        /// Azure region, e.g. `westeurope`
+        region: Option<String>,
+        /// Overrides region
+        endpoint: Option<String>,
+        #[serde(default = "default_key_quota")]
+        quota: u64,
+    },
+}
+
+/// Selected key and the endpoint it belongs to, `None` means `[tts] endpoint`
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyEndpoint {
+    pub key: String,
+    pub endpoint: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct KeyHealth {
+    /// Unix timestamp
+    last_success: Option<u64>,
+    /// Unix timestamp
+    last_failure: Option<u64>,
+    failure_reason: Option<String>,
+    requests: u64,
+    characters: u64,
+}
+
+#[derive(Debug)]
+struct ApiKey {
+    key: String,
+    endpoint: Option<String>,
+    quota: u64,
+    // Characters used in `month`, for weighted selection
+    month: u32,
+    used: u64,
+    // Disabled keys are not selected, only re-validation may bring them back
+    disabled: Option<String>,
+    // Throttled keys are skipped until the instant passed
+    cooldown: Option<Instant>,
+    health: KeyHealth,
+}
+
+impl ApiKey {
+    fn new(config: KeyConfig) -> Self {
+        let (key, endpoint, quota) = match config {
+            KeyConfig::Plain(key) => (key, None, default_key_quota()),
+            KeyConfig::Detailed {
+                key,
+                region,
+                endpoint,
+                quota,
+            } => (
+                key,
+                endpoint.or_else(|| {
+                    region.map(|region| {
+                        format!("https://{region}.tts.speech.microsoft.com/cognitiveservices/v1")
+                    })
+                }),
+                quota,
+            ),
+        };
+        Self {
+            key,
+            endpoint,
+            quota,
+            month: current_month(),
+            used: 0,
+            disabled: None,
+            cooldown: None,
+            health: Default::default(),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.disabled.is_none()
+    }
+
+    fn available(&self, now: Instant) -> bool {
+        self.enabled() && self.cooldown.is_none_or(|until| until <= now)
+    }
+
+    fn target(&self) -> KeyEndpoint {
+        KeyEndpoint {
+            key: self.key.clone(),
+            endpoint: self.endpoint.clone(),
+        }
+    }
+
+    fn remaining(&self) -> u64 {
+        if self.month != current_month() {
+            return self.quota;
+        }
+        self.quota.saturating_sub(self.used)
+    }
+
+    fn failure(&mut self, reason: String) {
+        self.health.requests += 1;
+        self.health.last_failure = Some(current_timestamp());
+        self.health.failure_reason = Some(reason);
+    }
+}
+
+/// Hide key for API responses, only first and last 4 characters are kept
+pub fn mask(key: &str) -> String {
+    let chars = key.chars().collect::<Vec<_>>();
+    if chars.len() <= 8 {
+        return "*".repeat(chars.len());
+    }
+    format!(
+        "{}...{}",
+        chars[..4].iter().collect::<String>(),
+        chars[chars.len() - 4..].iter().collect::<String>()
+    )
+}
+
+/// Key state reported by admin API, the key itself is masked
+#[derive(Debug, Serialize)]
+pub struct KeyStatus {
+    id: usize,
+    key: String,
+    endpoint: Option<String>,
+    enabled: bool,
+    disabled_reason: Option<String>,
+    /// Seconds until throttled key is usable again
+    cooldown: u64,
+    /// Characters left in this month's quota
+    remaining: u64,
+    #[serde(flatten)]
+    health: KeyHealth,
+}
+
+#[derive(Clone, Debug)]
+pub struct KeyStore {
+    inner: Arc<RwLock<Vec<ApiKey>>>,
+    // Next key for round-robin
+    cursor: Arc<AtomicUsize>,
+}
+
+impl<'de> Deserialize<'de> for KeyStore {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let inner: ArrayOrSingle<KeyConfig> = ArrayOrSingle::deserialize(deserializer)?;
+        inner.validate().map_err(serde::de::Error::custom)?;
+        Ok(Self {
+            inner: Arc::new(RwLock::new(
+                inner.into_vec().into_iter().map(ApiKey::new).collect(),
+            )),
+            cursor: Default::default(),
+        })
+    }
+}
+
+impl KeyStore {
+    pub async fn get_one(&self, strategy: KeyStrategy) -> anyhow::Result<KeyEndpoint> {
+        let delegate = self.inner.read().await;
+        if !delegate.iter().any(ApiKey::enabled) {
+            return Err(anyhow!("KeyStore is empty"));
+        }
+        let now = Instant::now();
+        let available = delegate
+            .iter()
+            .enumerate()
+            .filter(|(_, key)| key.available(now))
+            .collect::<Vec<_>>();
+        if available.is_empty() {
+            return Err(anyhow!("All keys are cooling down"));
+        }
+        let (_, selected) = match strategy {
+            KeyStrategy::Random => {
+                *rand::seq::IndexedRandom::choose(&available[..], &mut rand::rng()).unwrap()
+            }
+            KeyStrategy::RoundRobin => {
+                available[self.cursor.fetch_add(1, Ordering::AcqRel) % available.len()]
+            }
+            KeyStrategy::Weighted => {
+                // Fall back to uniform once every quota is used up
+                rand::seq::IndexedRandom::choose_weighted(
+                    &available[..],
+                    &mut rand::rng(),
+                    |(_, key)| key.remaining(),
+                )
+                .or_else(|_| {
+                    rand::seq::IndexedRandom::choose(&available[..], &mut rand::rng()).ok_or(())
+                })
+                .copied()
+                .unwrap()
+            }
+            KeyStrategy::Fallback => available[0],
+        };
+        Ok(selected
+            .target()
+            .tap(|s| log::trace!("Select key: {}", mask(&s.key))))
+    }
+
+    /// Keys without own endpoint, they need `[tts] endpoint`
+    pub async fn missing_endpoint(&self) -> bool {
+        self.inner
+            .read()
+            .await
+            .iter()
+            .any(|key| key.endpoint.is_none())
+    }
+
+    async fn update<F: FnOnce(&mut ApiKey)>(&self, key: &str, f: F) -> bool {
+        let mut delegate = self.inner.write().await;
+        delegate.iter_mut().find(|x| x.key.eq(key)).map(f).is_some()
+    }
+
+    pub async fn cooldown(&self, key: &str, duration: Duration) {
+        self.update(key, |key| {
+            key.cooldown = Some(Instant::now() + duration);
+            key.failure("Throttled".to_string());
+        })
+        .await;
+    }
+
+    /// Time until a key is usable again, `None` if any key is usable now or no key left
+    pub async fn cooldown_remaining(&self) -> Option<Duration> {
+        let delegate = self.inner.read().await;
+        let now = Instant::now();
+        delegate
+            .iter()
+            .filter(|key| key.enabled())
+            .map(|key| {
+                key.cooldown
+                    .map(|until| until.saturating_duration_since(now))
+                    .unwrap_or_default()
+            })
+            .min()
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    pub async fn record_success(&self, key: &str, characters: usize) {
+        self.update(key, |key| {
+            key.health.requests += 1;
+            key.health.characters += characters as u64;
+            key.health.last_success = Some(current_timestamp());
+            let month = current_month();
+            if key.month != month {
+                key.month = month;
+                key.used = 0;
+            }
+            key.used += characters as u64;
+        })
+        .await;
+    }
+
+    /// Restore characters used in `month` from persisted usage
+    pub async fn set_used(&self, key: &str, month: u32, used: u64) {
+        self.update(key, |key| {
+            key.month = month;
+            key.used = used;
+        })
+        .await;
+    }
+
+    pub async fn record_failure(&self, key: &str, reason: String) {
+        self.update(key, |key| key.failure(reason)).await;
+    }
+
+    /// Disable key and return count of keys still enabled, `None` if key is not found
+    /// or already disabled
+    pub async fn disable(&self, key: &str, reason: String) -> Option<usize> {
+        let mut delegate = self.inner.write().await;
+        let target = delegate.iter_mut().find(|x| x.key.eq(key) && x.enabled())?;
+        target.failure(reason.clone());
+        target.disabled = Some(reason);
+        log::trace!("Disable api key {}", mask(key));
+        Some(delegate.iter().filter(|key| key.enabled()).count())
+    }
+
+    pub async fn enable(&self, key: &str) -> bool {
+        self.update(key, |key| {
+            key.disabled = None;
+            key.cooldown = None;
+        })
+        .await
+    }
+
+    /// Enable key by its id in admin API
+    pub async fn enable_by_id(&self, id: usize) -> bool {
+        let key = match self.inner.read().await.get(id) {
+            Some(key) => key.key.clone(),
+            None => return false,
+        };
+        self.enable(&key).await
+    }
+
+    /// Every configured key in order, index is the id in admin API
+    pub async fn list(&self) -> Vec<String> {
+        self.inner
+            .read()
+            .await
+            .iter()
+            .map(|key| key.key.clone())
+            .collect()
+    }
+
+    pub async fn disabled(&self) -> Vec<KeyEndpoint> {
+        self.inner
+            .read()
+            .await
+            .iter()
+            .filter(|key| !key.enabled())
+            .map(ApiKey::target)
+            .collect()
+    }
+
+    pub async fn status(&self) -> Vec<KeyStatus> {
+        let now = Instant::now();
+        self.inner
+            .read()
+            .await
+            .iter()
+            .enumerate()
+            .map(|(id, key)| KeyStatus {
+                id,
+                key: mask(&key.key),
+                endpoint: key.endpoint.clone(),
+                enabled: key.enabled(),
+                disabled_reason: key.disabled.clone(),
+                cooldown: key
+                    .cooldown
+                    .map(|until| until.saturating_duration_since(now).as_secs())
+                    .unwrap_or_default(),
+                remaining: key.remaining(),
+                health: key.health.clone(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KeyStore, KeyStrategy};
+
+    fn store(keys: &str) -> KeyStore {
+        serde_json::from_str(keys).unwrap()
+    }
+
+    async fn select(store: &KeyStore, strategy: KeyStrategy, count: usize) -> Vec<String> {
+        let mut ret = Vec::new();
+        for _ in 0..count {
+            ret.push(store.get_one(strategy).await.unwrap().key);
+        }
+        ret
+    }
+
+    #[tokio::test]
+    async fn test_round_robin() {
+        let store = store(r#"["a", "b", "c"]"#);
+        assert_eq!(
+            select(&store, KeyStrategy::RoundRobin, 4).await,
+            ["a", "b", "c", "a"]
+        );
+        store.disable("b", "401".to_string()).await;
+        assert_eq!(
+            select(&store, KeyStrategy::RoundRobin, 3).await,
+            ["a", "c", "a"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fallback() {
+        let store = store(r#"["primary", "secondary"]"#);
+        assert_eq!(
+            select(&store, KeyStrategy::Fallback, 2).await,
+            ["primary", "primary"]
+        );
+        store
+            .cooldown("primary", std::time::Duration::from_secs(60))
+            .await;
+        assert_eq!(
+            select(&store, KeyStrategy::Fallback, 1).await,
+            ["secondary"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_weighted() {
+        let store = store(r#"[{"key": "spent", "quota": 100}, {"key": "fresh", "quota": 100}]"#);
+        store.record_success("spent", 100).await;
+        assert!(
+            select(&store, KeyStrategy::Weighted, 20)
+                .await
+                .iter()
+                .all(|key| key == "fresh")
+        );
+        // Still usable once every quota is used up
+        store.record_success("fresh", 100).await;
+        assert_eq!(select(&store, KeyStrategy::Weighted, 5).await.len(), 5);
+    }
+
+    #[test]
+    fn test_key_endpoint() {
+        let store = store(
+            r#"["a", {"key": "b", "region": "westeurope"}, {"key": "c", "region": "eastus", "endpoint": "http://localhost/cognitiveservices/v1"}]"#,
+        );
+        let keys = store.inner.try_read().unwrap();
+        assert_eq!(keys[0].endpoint, None);
+        assert_eq!(
+            keys[1].endpoint.as_deref(),
+            Some("https://westeurope.tts.speech.microsoft.com/cognitiveservices/v1")
+        );
+        assert_eq!(
+            keys[2].endpoint.as_deref(),
+            Some("http://localhost/cognitiveservices/v1")
+        );
+    }
+}
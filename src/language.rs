@@ -0,0 +1,168 @@
+//! Pick voice by detected language, mixed-language messages are split by script
+
+use std::collections::HashMap;
+
+use whatlang::Script;
+
+use crate::config::Language;
+
+/// Han, kana and Hangul are mixed within Japanese and Korean, detect them together
+fn script_group(c: char) -> Option<Script> {
+    if !c.is_alphabetic() {
+        return None;
+    }
+    match whatlang::detect_script(c.encode_utf8(&mut [0; 4]))? {
+        Script::Mandarin | Script::Hiragana | Script::Katakana | Script::Hangul => {
+            Some(Script::Mandarin)
+        }
+        script => Some(script),
+    }
+}
+
+pub struct LanguageDetector {
+    voices: HashMap<String, String>,
+    split: bool,
+    min_confidence: f64,
+}
+
+impl LanguageDetector {
+    pub fn new(config: &Language) -> Self {
+        Self {
+            voices: config.voices().clone(),
+            split: config.split(),
+            min_confidence: config.min_confidence(),
+        }
+    }
+
+    /// Configured voice or preset name for language of text
+    fn detect(&self, text: &str) -> Option<&str> {
+        let info = whatlang::detect(text)?;
+        log::trace!(
+            "Detected {} ({:.2}) for {text:?}",
+            info.lang().code(),
+            info.confidence()
+        );
+        if info.confidence() < self.min_confidence {
+            return None;
+        }
+        self.voices.get(info.lang().code()).map(String::as_str)
+    }
+
+    /// Split text into runs of the same script
+    fn runs(text: &str) -> Vec<String> {
+        let mut runs: Vec<String> = Vec::new();
+        let mut current = None;
+        for c in text.chars() {
+            match script_group(c) {
+                Some(group) if current != Some(group) => {
+                    current = Some(group);
+                    runs.push(c.to_string());
+                }
+                // Spaces, digits and punctuation stay with the current run
+                _ => match runs.last_mut() {
+                    Some(run) => run.push(c),
+                    None => runs.push(c.to_string()),
+                },
+            }
+        }
+        runs
+    }
+
+    /// Text split into (voice or preset name, text) segments, empty if no language
+    /// could be mapped to a voice
+    pub fn segments<'a>(&'a self, text: &str) -> Vec<(&'a str, String)> {
+        if !self.split {
+            return self
+                .detect(text)
+                .map(|voice| vec![(voice, text.to_string())])
+                .unwrap_or_default();
+        }
+        let detected = Self::runs(text)
+            .into_iter()
+            .map(|run| (self.detect(&run), run))
+            .collect::<Vec<_>>();
+        let mut segments: Vec<(&str, String)> = Vec::new();
+        // Runs without known language are read by the voice before them
+        let mut pending = String::new();
+        for (voice, run) in detected {
+            let Some(voice) = voice else {
+                match segments.last_mut() {
+                    Some((_, text)) => text.push_str(&run),
+                    None => pending.push_str(&run),
+                }
+                continue;
+            };
+            match segments.last_mut() {
+                Some((last, text)) if *last == voice => text.push_str(&run),
+                _ => segments.push((voice, std::mem::take(&mut pending) + &run)),
+            }
+        }
+        segments
+            .into_iter()
+            .map(|(voice, text)| (voice, text.trim().to_string()))
+            .filter(|(_, text)| !text.is_empty())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LanguageDetector;
+
+    fn detector(config: &str) -> LanguageDetector {
+        LanguageDetector::new(&toml::from_str(config).unwrap())
+    }
+
+    const VOICES: &str = r#"
+[voices]
+cmn = "zh-CN-XiaoxiaoNeural"
+eng = "en-US-AvaNeural"
+jpn = "narrator"
+"#;
+
+    #[test]
+    fn test_detect() {
+        let detector = detector(VOICES);
+        assert_eq!(
+            detector.segments("今天天气很好，我们一起去公园散步吧"),
+            [(
+                "zh-CN-XiaoxiaoNeural",
+                "今天天气很好，我们一起去公园散步吧".to_string()
+            )]
+        );
+        assert_eq!(
+            detector.segments("今日はとても良い天気ですね、散歩に行きましょう"),
+            [(
+                "narrator",
+                "今日はとても良い天気ですね、散歩に行きましょう".to_string()
+            )]
+        );
+        assert!(detector.segments("12345 !!!").is_empty());
+    }
+
+    #[test]
+    fn test_mixed() {
+        let mixed = detector(VOICES);
+        assert_eq!(
+            mixed.segments(
+                "我们明天下午三点开会 please remember to bring the quarterly report 谢谢大家"
+            ),
+            [
+                ("zh-CN-XiaoxiaoNeural", "我们明天下午三点开会".to_string()),
+                (
+                    "en-US-AvaNeural",
+                    "please remember to bring the quarterly report".to_string()
+                ),
+                ("zh-CN-XiaoxiaoNeural", "谢谢大家".to_string()),
+            ]
+        );
+
+        let whole = detector(&format!("split = false\n{VOICES}"));
+        assert_eq!(
+            whole
+                .segments("我们明天下午三点开会 please remember 谢谢大家")
+                .len(),
+            1
+        );
+    }
+}
@@ -2,18 +2,43 @@ use std::sync::Arc;
 
 use anyhow::Result;
 use config::Config;
-use connection::ConnectionHandler;
+use connection::{ConnectionHandler, VoiceListener};
+use metrics::Metrics;
+use recorder::VoiceRecorder;
+use sink::{DryRunSink, TeamSpeakSink};
+use stt::Transcriber;
 use tokio::sync::{broadcast, mpsc};
 
 use tts::MiddlewareTask;
 use types::{AdditionalArguments, MainEvent};
 use web::route;
 
+mod audio;
+mod audit;
 pub mod cache;
+mod chunk;
 mod config;
 mod connection;
+mod history;
+mod keys;
+mod language;
+mod metrics;
+mod preprocess;
+mod presets;
+mod protocol;
+mod recorder;
+mod sink;
+mod stt;
+#[cfg(test)]
+mod test_support;
+#[cfg(feature = "rustls")]
+mod tls;
 mod tts;
 mod types;
+#[cfg(unix)]
+mod unix_socket;
+mod usage;
+mod voices;
 mod web;
 
 fn init_log(verbose: u8) {
@@ -55,6 +80,7 @@ fn main() -> Result<()> {
             clap::arg!(--server <SERVER> "Override teamspeak server"),
             clap::arg!(--web <BIND_ADDRESS> "Override web server bind address"),
             clap::arg!(--leveldb <LEVELDB_FOLDER> "Override leveldb location"),
+            clap::arg!(--"dry-run" "Do not connect to TeamSpeak, only log played audio"),
         ])
         .get_matches();
 
@@ -85,6 +111,8 @@ async fn async_main(
     let (audio_sender, audio_receiver) = mpsc::channel(16);
     let (middle_sender, middle_receiver) = mpsc::channel(16);
     let (global_sender, global_receiver) = broadcast::channel(2);
+    let (notify_sender, _) = broadcast::channel(32);
+    let metrics = Metrics::new()?;
 
     let (cache_handler, leveldb_helper) =
         cache::LevelDB::connect(args.leveldb.unwrap_or_else(|| config.leveldb().to_string()));
@@ -93,23 +121,73 @@ async fn async_main(
         middle_receiver,
         audio_sender.clone(),
         Arc::new(leveldb_helper.clone()),
+        metrics.clone(),
     );
-    let handler = tokio::spawn(tts::send_audio(audio_receiver, teamspeak_sender.clone()));
+    let handler = if args.dry_run {
+        log::warn!("Dry run mode, TeamSpeak connection, recorder and STT are disabled");
+        tokio::spawn(tts::send_audio(
+            audio_receiver,
+            DryRunSink::default(),
+            config.tts().interrupt(),
+            config.tts().format().clone(),
+            metrics.clone(),
+        ))
+    } else {
+        tokio::spawn(tts::send_audio(
+            audio_receiver,
+            TeamSpeakSink::new(teamspeak_sender.clone()),
+            config.tts().interrupt(),
+            config.tts().format().clone(),
+            metrics.clone(),
+        ))
+    };
 
     let web = tokio::spawn(route(
         config.clone(),
         leveldb_helper,
         middle_sender.clone(),
         global_receiver.resubscribe(),
+        notify_sender.clone(),
+        metrics.clone(),
         args.web,
     ));
 
-    let (ts_conn, early_exit_receiver) =
-        ConnectionHandler::start(config, verbose, log_command, teamspeak_recv, args.server)?;
+    let (recorder, recorder_helper) = config
+        .recorder()
+        .filter(|_| !args.dry_run)
+        .cloned()
+        .map(VoiceRecorder::start)
+        .unzip();
+    let (transcriber, transcriber_helper) = config
+        .stt()
+        .filter(|_| !args.dry_run)
+        .cloned()
+        .map(|stt| Transcriber::start(stt, teamspeak_sender.clone(), notify_sender.clone()))
+        .unzip();
+
+    let (ts_conn, early_exit_receiver) = if args.dry_run {
+        (None, None)
+    } else {
+        let (conn, receiver) = ConnectionHandler::start(
+            config,
+            verbose,
+            log_command,
+            teamspeak_recv,
+            args.server,
+            VoiceListener::new(recorder_helper.clone(), transcriber_helper.clone()),
+            metrics,
+        )?;
+        (Some(conn), Some(receiver))
+    };
 
     tokio::select! {
         _ = tokio::signal::ctrl_c() => {}
-        _ = early_exit_receiver => {
+        _ = async {
+            match early_exit_receiver {
+                Some(receiver) => receiver.await.ok(),
+                None => futures::future::pending().await,
+            }
+        } => {
             log::error!("Program exit by early receiver");
         }
     }
@@ -121,8 +199,25 @@ async fn async_main(
 
     tokio::select! {
         ret = async {
-            ts_conn.join().await?;
-            log::debug!("Exit TeamSpeak thread");
+            if let Some(ts_conn) = ts_conn {
+                ts_conn.join().await?;
+                log::debug!("Exit TeamSpeak thread");
+            }
+
+            if let Some(recorder) = recorder {
+                if let Some(helper) = recorder_helper {
+                    helper.exit().await;
+                }
+                recorder.join().await?;
+                log::debug!("Exit recorder");
+            }
+            if let Some(transcriber) = transcriber {
+                if let Some(helper) = transcriber_helper {
+                    helper.exit().await;
+                }
+                transcriber.join().await?;
+                log::debug!("Exit transcriber");
+            }
 
             middle_handler.join()?;
             log::debug!("Exit middleware");
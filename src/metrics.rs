@@ -0,0 +1,182 @@
+//! Prometheus metrics served at `/metrics`
+
+use std::time::Duration;
+
+use prometheus::{
+    Counter, Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+
+/// Cheap to clone, every clone updates the same metrics
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    cache: IntCounterVec,
+    azure_latency: Histogram,
+    azure_responses: IntCounterVec,
+    cached_bytes: IntCounter,
+    queue_depth: IntGauge,
+    audio_seconds: Counter,
+    websocket_clients: IntGauge,
+    teamspeak_connected: IntGauge,
+    teamspeak_reconnects: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> anyhow::Result<Self> {
+        let registry = Registry::new_custom(Some("teamspeak_tts".to_string()), None)?;
+        let requests = IntCounterVec::new(
+            Opts::new("requests_total", "TTS requests by source"),
+            &["source"],
+        )?;
+        let cache = IntCounterVec::new(
+            Opts::new("cache_total", "Cache lookups of TTS requests by result"),
+            &["result"],
+        )?;
+        let azure_latency = Histogram::with_opts(HistogramOpts::new(
+            "azure_request_duration_seconds",
+            "Time until Azure responds, without audio download",
+        ))?;
+        let azure_responses = IntCounterVec::new(
+            Opts::new("azure_responses_total", "Azure responses by status code"),
+            &["status"],
+        )?;
+        let cached_bytes = IntCounter::new("cached_bytes_total", "Audio bytes written to cache")?;
+        let queue_depth = IntGauge::new("queue_depth", "Messages waiting for playback")?;
+        let audio_seconds = Counter::new("audio_played_seconds_total", "Audio played")?;
+        let websocket_clients = IntGauge::new("websocket_clients", "Connected web clients")?;
+        let teamspeak_connected =
+            IntGauge::new("teamspeak_connected", "1 if connected to TeamSpeak server")?;
+        let teamspeak_reconnects = IntCounter::new(
+            "teamspeak_reconnects_total",
+            "Lost TeamSpeak connections, reconnect is automatic",
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(cache.clone()))?;
+        registry.register(Box::new(azure_latency.clone()))?;
+        registry.register(Box::new(azure_responses.clone()))?;
+        registry.register(Box::new(cached_bytes.clone()))?;
+        registry.register(Box::new(queue_depth.clone()))?;
+        registry.register(Box::new(audio_seconds.clone()))?;
+        registry.register(Box::new(websocket_clients.clone()))?;
+        registry.register(Box::new(teamspeak_connected.clone()))?;
+        registry.register(Box::new(teamspeak_reconnects.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            cache,
+            azure_latency,
+            azure_responses,
+            cached_bytes,
+            queue_depth,
+            audio_seconds,
+            websocket_clients,
+            teamspeak_connected,
+            teamspeak_reconnects,
+        })
+    }
+
+    pub fn request(&self, source: &str) {
+        self.requests.with_label_values(&[source]).inc();
+    }
+
+    pub fn cache(&self, hit: bool) {
+        self.cache
+            .with_label_values(&[if hit { "hit" } else { "miss" }])
+            .inc();
+    }
+
+    /// `status` is the HTTP status code, or `error` if no response is received
+    pub fn azure(&self, status: &str, elapsed: Duration) {
+        self.azure_latency.observe(elapsed.as_secs_f64());
+        self.azure_responses.with_label_values(&[status]).inc();
+    }
+
+    pub fn cached(&self, bytes: usize) {
+        self.cached_bytes.inc_by(bytes as u64);
+    }
+
+    pub fn queue_depth(&self, depth: usize) {
+        self.queue_depth.set(depth as i64);
+    }
+
+    pub fn played(&self, duration: Duration) {
+        self.audio_seconds.inc_by(duration.as_secs_f64());
+    }
+
+    pub fn websocket(&self, connected: bool) {
+        if connected {
+            self.websocket_clients.inc();
+        } else {
+            self.websocket_clients.dec();
+        }
+    }
+
+    pub fn teamspeak_connected(&self, connected: bool) {
+        self.teamspeak_connected.set(connected as i64);
+    }
+
+    pub fn teamspeak_reconnect(&self) {
+        self.teamspeak_reconnects.inc();
+        self.teamspeak_connected(false);
+    }
+
+    /// Queue depth, connected web clients and TeamSpeak connection state
+    pub fn snapshot(&self) -> (i64, i64, bool) {
+        (
+            self.queue_depth.get(),
+            self.websocket_clients.get(),
+            self.teamspeak_connected.get() == 1,
+        )
+    }
+
+    /// Prometheus text format
+    pub fn render(&self) -> anyhow::Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new().expect("Metric names are valid")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Metrics;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.request("websocket");
+        metrics.cache(true);
+        metrics.cache(false);
+        metrics.azure("200", Duration::from_millis(120));
+        metrics.played(Duration::from_millis(20));
+        metrics.websocket(true);
+        metrics.teamspeak_connected(true);
+        metrics.teamspeak_reconnect();
+
+        let text = metrics.render().unwrap();
+        for line in [
+            "teamspeak_tts_requests_total{source=\"websocket\"} 1",
+            "teamspeak_tts_cache_total{result=\"hit\"} 1",
+            "teamspeak_tts_azure_responses_total{status=\"200\"} 1",
+            "teamspeak_tts_azure_request_duration_seconds_count 1",
+            "teamspeak_tts_audio_played_seconds_total 0.02",
+            "teamspeak_tts_websocket_clients 1",
+            "teamspeak_tts_teamspeak_connected 0",
+            "teamspeak_tts_teamspeak_reconnects_total 1",
+        ] {
+            assert!(text.contains(line), "{line} not in {text}");
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! Clean up chat-style text before it is hashed and sent to Azure
+
+use std::collections::{HashMap, HashSet};
+
+use reqwest::Url;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::config::{EmojiMode, Preprocess};
+
+pub struct Preprocessor {
+    // Keys are lowercase
+    abbreviations: HashMap<String, String>,
+    urls: bool,
+    emoji: EmojiMode,
+    profanity: HashSet<String>,
+    censor: String,
+    max_repeat: usize,
+}
+
+impl Preprocessor {
+    pub fn new(config: &Preprocess) -> Self {
+        Self {
+            abbreviations: config
+                .abbreviations()
+                .iter()
+                .map(|(short, long)| (short.to_lowercase(), long.clone()))
+                .collect(),
+            urls: config.urls(),
+            emoji: config.emoji(),
+            profanity: config
+                .profanity()
+                .iter()
+                .map(|word| word.to_lowercase())
+                .collect(),
+            censor: config.censor().to_string(),
+            max_repeat: config.max_repeat(),
+        }
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        if self.urls {
+            text = Self::replace_urls(&text);
+        }
+        text = self.replace_emoji(&text);
+        text = self.replace_words(&text);
+        if self.max_repeat > 0 {
+            text = Self::collapse_repeats(&text, self.max_repeat);
+        }
+        text.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    /// `https://www.example.com/path?q=1` -> `link to example.com`
+    fn replace_urls(text: &str) -> String {
+        text.split_inclusive(char::is_whitespace)
+            .map(|token| {
+                let word = token.trim_end();
+                let trimmed = word.trim_end_matches(['.', ',', '!', '?', ')', ';', ':']);
+                let candidate = if trimmed.starts_with("www.") {
+                    format!("http://{trimmed}")
+                } else {
+                    trimmed.to_string()
+                };
+                let Some(host) = Url::parse(&candidate)
+                    .ok()
+                    .filter(|url| matches!(url.scheme(), "http" | "https"))
+                    .and_then(|url| url.host_str().map(str::to_string))
+                else {
+                    return token.to_string();
+                };
+                format!(
+                    "link to {}{}{}",
+                    host.trim_start_matches("www."),
+                    &word[trimmed.len()..],
+                    &token[word.len()..]
+                )
+            })
+            .collect()
+    }
+
+    fn replace_emoji(&self, text: &str) -> String {
+        if let EmojiMode::Keep = self.emoji {
+            return text.to_string();
+        }
+        text.graphemes(true)
+            .map(|grapheme| {
+                if grapheme.is_ascii() {
+                    return grapheme.to_string();
+                }
+                let Some(emoji) = emojis::get(grapheme).or_else(|| {
+                    // Unknown sequence, e.g. unusual skin tone, try its base emoji
+                    grapheme
+                        .chars()
+                        .next()
+                        .and_then(|c| emojis::get(c.encode_utf8(&mut [0; 4])))
+                }) else {
+                    return grapheme.to_string();
+                };
+                match self.emoji {
+                    EmojiMode::Describe => format!(" {} ", emoji.name()),
+                    _ => " ".to_string(),
+                }
+            })
+            .collect()
+    }
+
+    /// Expand abbreviations and censor profanity, both match whole words ignoring case
+    fn replace_words(&self, text: &str) -> String {
+        if self.abbreviations.is_empty() && self.profanity.is_empty() {
+            return text.to_string();
+        }
+        let mut ret = String::with_capacity(text.len());
+        let mut word = String::new();
+        let flush = |word: &mut String, ret: &mut String| {
+            let lowercase = word.to_lowercase();
+            if self.profanity.contains(&lowercase) {
+                ret.push_str(&self.censor);
+            } else if let Some(long) = self.abbreviations.get(&lowercase) {
+                ret.push_str(long);
+            } else {
+                ret.push_str(word);
+            }
+            word.clear();
+        };
+        for c in text.chars() {
+            // Apostrophe keeps words like "don't" together
+            if c.is_alphanumeric() || c == '\'' {
+                word.push(c);
+            } else {
+                flush(&mut word, &mut ret);
+                ret.push(c);
+            }
+        }
+        flush(&mut word, &mut ret);
+        ret
+    }
+
+    /// `soooooo!!!!!!` -> `sooo!!!`, numbers are kept
+    fn collapse_repeats(text: &str, max: usize) -> String {
+        let mut ret = String::with_capacity(text.len());
+        let mut last = None;
+        let mut count = 0;
+        for c in text.chars() {
+            if Some(c) == last {
+                count += 1;
+            } else {
+                last = Some(c);
+                count = 1;
+            }
+            if count <= max || c.is_ascii_digit() {
+                ret.push(c);
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Preprocessor;
+
+    fn preprocessor(config: &str) -> Preprocessor {
+        Preprocessor::new(&toml::from_str(config).unwrap())
+    }
+
+    #[test]
+    fn test_default() {
+        let preprocessor = preprocessor("");
+        assert_eq!(
+            preprocessor.apply("see https://www.example.com/a?b=1, or www.rust-lang.org."),
+            "see link to example.com, or link to rust-lang.org."
+        );
+        assert_eq!(
+            preprocessor.apply("nice 👍🏽 🚀"),
+            "nice thumbs up: medium skin tone rocket"
+        );
+        assert_eq!(
+            preprocessor.apply("nooooooo!!!!!! 100000"),
+            "nooo!!! 100000"
+        );
+        // Non-emoji text is untouched
+        assert_eq!(preprocessor.apply("你好，世界"), "你好，世界");
+    }
+
+    #[test]
+    fn test_dictionary() {
+        let preprocessor = preprocessor(
+            r#"
+abbreviations = { brb = "be right back", GG = "good game" }
+profanity = ["darn"]
+emoji = "strip"
+max_repeat = 0
+"#,
+        );
+        assert_eq!(
+            preprocessor.apply("BRB, gg! Darn 🚀 brbx aaaaa"),
+            "be right back, good game! beep brbx aaaaa"
+        );
+    }
+}
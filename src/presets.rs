@@ -0,0 +1,352 @@
+//! Named voice presets and per-user default voices, from config or admin API
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::cache::ConnAgent;
+
+/// SSML `<prosody>` attributes, e.g. `rate = "-10%"`, `pitch = "+2st"`, `volume = "loud"`
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Prosody {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rate: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pitch: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    volume: Option<String>,
+}
+
+impl Prosody {
+    fn attributes(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            ("rate", &self.rate),
+            ("pitch", &self.pitch),
+            ("volume", &self.volume),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value.as_deref()?)))
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.attributes().next().is_none()
+    }
+
+    /// Values end up in SSML attributes, only allow what Azure documents
+    fn validate(&self) -> Result<(), String> {
+        for (name, value) in self.attributes() {
+            if value.is_empty()
+                || !value
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.%".contains(c))
+            {
+                return Err(format!("Invalid prosody {name}: {value:?}"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Wrap text into `<prosody>` if any attribute is set
+    pub fn wrap(&self, text: &str) -> String {
+        if self.is_default() {
+            return text.to_string();
+        }
+        let attributes = self
+            .attributes()
+            .map(|(name, value)| format!(" {name}='{value}'"))
+            .collect::<String>();
+        format!("<prosody{attributes}>{text}</prosody>")
+    }
+}
+
+impl std::fmt::Display for Prosody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, value) in self.attributes() {
+            write!(f, "[{name}={value}]")?;
+        }
+        Ok(())
+    }
+}
+
+/// Voice with optional prosody, either a named preset or built from request fields
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Preset {
+    /// Full voice name, e.g. `en-US-AvaNeural`
+    voice: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    gender: String,
+    #[serde(flatten)]
+    prosody: Prosody,
+}
+
+impl Preset {
+    pub fn new(voice: String, gender: String) -> Self {
+        Self {
+            voice,
+            gender,
+            prosody: Default::default(),
+        }
+    }
+
+    pub fn voice(&self) -> &str {
+        &self.voice
+    }
+
+    /// Locale part of voice name, e.g. `en-US`
+    pub fn locale(&self) -> String {
+        self.voice
+            .splitn(3, '-')
+            .take(2)
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    /// Language part of voice name, e.g. `en`
+    pub fn language(&self) -> &str {
+        self.voice.split('-').next().unwrap_or_default()
+    }
+
+    pub fn gender(&self) -> &str {
+        &self.gender
+    }
+
+    pub fn prosody(&self) -> &Prosody {
+        &self.prosody
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.voice.split('-').count() < 3
+            || !self
+                .voice
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-:_".contains(c))
+        {
+            return Err(format!("Invalid voice name: {:?}", self.voice));
+        }
+        if !self.gender.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(format!("Invalid gender: {:?}", self.gender));
+        }
+        self.prosody.validate()
+    }
+}
+
+/// Default voice of a user, either preset name or voice
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum DefaultVoice {
+    Preset(String),
+    Voice(Preset),
+}
+
+impl DefaultVoice {
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Self::Preset(_) => Ok(()),
+            Self::Voice(preset) => preset.validate(),
+        }
+    }
+}
+
+/// Presets and user defaults set by admin API, kept in LevelDB
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct Stored {
+    presets: BTreeMap<String, Preset>,
+    users: BTreeMap<String, DefaultVoice>,
+}
+
+pub struct PresetStore {
+    leveldb: ConnAgent,
+    presets: HashMap<String, Preset>,
+    users: HashMap<String, DefaultVoice>,
+    stored: RwLock<Stored>,
+}
+
+impl PresetStore {
+    pub fn new(
+        leveldb: ConnAgent,
+        presets: HashMap<String, Preset>,
+        users: HashMap<String, DefaultVoice>,
+    ) -> Self {
+        Self {
+            leveldb,
+            presets,
+            users,
+            stored: Default::default(),
+        }
+    }
+
+    const RECORD: &str = "presets";
+
+    /// Restore presets and user defaults set by admin API
+    pub async fn load(&self) {
+        let Some(raw) = self.leveldb.get_record(Self::RECORD).await else {
+            return;
+        };
+        match serde_json::from_slice(&raw) {
+            Ok(stored) => *self.stored.write().await = stored,
+            Err(e) => log::error!("Unable decode stored presets: {e:?}"),
+        }
+    }
+
+    async fn save(&self, stored: &Stored) -> anyhow::Result<()> {
+        self.leveldb
+            .set_record(Self::RECORD, serde_json::to_vec(stored)?)
+            .await?;
+        Ok(())
+    }
+
+    /// Every preset, the ones set by admin API override config
+    pub async fn presets(&self) -> BTreeMap<String, Preset> {
+        let mut ret = self
+            .presets
+            .iter()
+            .map(|(name, preset)| (name.clone(), preset.clone()))
+            .collect::<BTreeMap<_, _>>();
+        ret.extend(self.stored.read().await.presets.clone());
+        ret
+    }
+
+    pub async fn preset(&self, name: &str) -> Option<Preset> {
+        if let Some(preset) = self.stored.read().await.presets.get(name) {
+            return Some(preset.clone());
+        }
+        self.presets.get(name).cloned()
+    }
+
+    pub async fn set_preset(&self, name: String, preset: Preset) -> Result<(), String> {
+        preset.validate()?;
+        let mut stored = self.stored.write().await;
+        stored.presets.insert(name, preset);
+        self.save(&stored).await.map_err(|e| e.to_string())
+    }
+
+    /// `None` if preset is not set by admin API
+    pub async fn remove_preset(&self, name: &str) -> Option<anyhow::Result<()>> {
+        let mut stored = self.stored.write().await;
+        stored.presets.remove(name)?;
+        Some(self.save(&stored).await)
+    }
+
+    pub async fn users(&self) -> BTreeMap<String, DefaultVoice> {
+        let mut ret = self
+            .users
+            .iter()
+            .map(|(user, voice)| (user.clone(), voice.clone()))
+            .collect::<BTreeMap<_, _>>();
+        ret.extend(self.stored.read().await.users.clone());
+        ret
+    }
+
+    pub async fn set_user(&self, user: String, voice: DefaultVoice) -> Result<(), String> {
+        voice.validate()?;
+        let mut stored = self.stored.write().await;
+        stored.users.insert(user, voice);
+        self.save(&stored).await.map_err(|e| e.to_string())
+    }
+
+    /// `None` if user default is not set by admin API
+    pub async fn remove_user(&self, user: &str) -> Option<anyhow::Result<()>> {
+        let mut stored = self.stored.write().await;
+        stored.users.remove(user)?;
+        Some(self.save(&stored).await)
+    }
+
+    /// Default voice of user, preset names are resolved
+    pub async fn user(&self, user: &str) -> anyhow::Result<Option<Preset>> {
+        let voice = match self.stored.read().await.users.get(user) {
+            Some(voice) => Some(voice.clone()),
+            None => self.users.get(user).cloned(),
+        };
+        match voice {
+            Some(DefaultVoice::Preset(name)) => self
+                .preset(&name)
+                .await
+                .map(Some)
+                .ok_or_else(|| anyhow!("Preset {name:?} of user {user:?} not found")),
+            Some(DefaultVoice::Voice(preset)) => Ok(Some(preset)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{DefaultVoice, Preset, PresetStore};
+    use crate::cache::LevelDB;
+
+    fn preset(raw: &str) -> Preset {
+        serde_json::from_str(raw).unwrap()
+    }
+
+    #[test]
+    fn test_prosody() {
+        let narrator = preset(r#"{"voice": "en-US-GuyNeural", "rate": "-10%", "volume": "+20%"}"#);
+        assert_eq!(narrator.locale(), "en-US");
+        assert_eq!(
+            narrator.prosody().wrap("Hello"),
+            "<prosody rate='-10%' volume='+20%'>Hello</prosody>"
+        );
+        assert_eq!(narrator.prosody().to_string(), "[rate=-10%][volume=+20%]");
+        assert!(narrator.validate().is_ok());
+        assert!(
+            preset(r#"{"voice": "en-US-GuyNeural", "pitch": "'/><x"}"#)
+                .validate()
+                .is_err()
+        );
+        assert!(preset(r#"{"voice": "GuyNeural"}"#).validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_store() {
+        let (agent, db) = LevelDB::new_in_memory();
+        let store = PresetStore::new(
+            agent.clone(),
+            HashMap::from([(
+                "narrator".to_string(),
+                preset(r#"{"voice": "en-US-GuyNeural"}"#),
+            )]),
+            HashMap::from([(
+                "uid=".to_string(),
+                DefaultVoice::Preset("narrator".to_string()),
+            )]),
+        );
+        assert_eq!(
+            store.user("uid=").await.unwrap().unwrap().voice(),
+            "en-US-GuyNeural"
+        );
+        assert!(store.user("nobody").await.unwrap().is_none());
+
+        // API presets override config and are persisted
+        store
+            .set_preset(
+                "narrator".to_string(),
+                preset(r#"{"voice": "en-GB-RyanNeural"}"#),
+            )
+            .await
+            .unwrap();
+        store
+            .set_user(
+                "other".to_string(),
+                DefaultVoice::Preset("missing".to_string()),
+            )
+            .await
+            .unwrap();
+        assert!(store.user("other").await.is_err());
+
+        let restored = PresetStore::new(agent, HashMap::new(), HashMap::new());
+        restored.load().await;
+        assert_eq!(
+            restored.preset("narrator").await.unwrap().voice(),
+            "en-GB-RyanNeural"
+        );
+        assert!(restored.remove_preset("narrator").await.is_some());
+        assert!(restored.remove_preset("narrator").await.is_none());
+        assert_eq!(restored.users().await.len(), 1);
+
+        db.disconnect().await.unwrap();
+    }
+}
@@ -0,0 +1,269 @@
+//! Websocket protocol v1 at `/ws/v1`: tagged JSON messages, every request carries an id
+//! chosen by the client and its progress events repeat it. `/ws` keeps the plain text protocol
+
+use std::collections::BTreeSet;
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{history::HistoryEntry, tts::Priority, types::BroadcastEvent, web::Data};
+
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Speak {
+        id: u64,
+        #[serde(flatten)]
+        data: Data,
+    },
+    /// Play a history entry again from cache
+    Replay {
+        id: u64,
+        entry: u64,
+        #[serde(default)]
+        priority: Priority,
+    },
+    /// Stop a queued or playing request of this connection
+    Cancel {
+        id: u64,
+        request: u64,
+    },
+    Ping {
+        id: u64,
+    },
+    /// Replace the broadcast events this connection receives, none by default
+    Subscribe {
+        id: u64,
+        topics: BTreeSet<Topic>,
+    },
+    Status {
+        id: u64,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    Transcript,
+    Warning,
+    History,
+}
+
+/// Progress of a speak or replay request, sent by whoever handles it at the moment
+#[derive(Clone, Debug)]
+pub enum Progress {
+    /// Waiting for Azure
+    Synthesizing,
+    /// Audio is ready and waits for playback, `detail` is e.g. `Hit cache` or Azure status
+    Queued(String),
+    Playing,
+    Done,
+    /// Playback is stopped by an urgent message
+    Interrupted {
+        requeued: bool,
+    },
+    Cancelled,
+    /// Audio of a preview, one per chunk, sent to the client instead of the channel
+    Audio {
+        audio: Bytes,
+        mime: &'static str,
+        last: bool,
+    },
+    Error(String),
+}
+
+impl Progress {
+    /// Text sent by the plain protocol, `None` if it is not sent there. Its clients get
+    /// the queued status as the reply to their request
+    pub fn legacy(&self) -> Option<String> {
+        match self {
+            Self::Synthesizing | Self::Queued(_) | Self::Cancelled | Self::Audio { .. } => None,
+            Self::Playing => Some("Sending audio".to_string()),
+            Self::Done => Some("Send audio successful".to_string()),
+            Self::Interrupted { requeued: true } => {
+                Some("Interrupted by urgent message, re-queued".to_string())
+            }
+            Self::Interrupted { requeued: false } => {
+                Some("Interrupted by urgent message".to_string())
+            }
+            Self::Error(message) => Some(message.clone()),
+        }
+    }
+
+    /// No more progress follows
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            Self::Done
+                | Self::Interrupted { requeued: false }
+                | Self::Cancelled
+                | Self::Audio { last: true, .. }
+                | Self::Error(_)
+        )
+    }
+
+    /// Sent as binary message, after the event on protocol v1
+    pub fn audio(&self) -> Option<Bytes> {
+        match self {
+            Self::Audio { audio, .. } => Some(audio.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn into_message(self, id: u64) -> ServerMessage {
+        match self {
+            Self::Synthesizing => ServerMessage::Synthesizing { id },
+            Self::Queued(detail) => ServerMessage::Queued { id, detail },
+            Self::Playing => ServerMessage::Playing { id },
+            Self::Done => ServerMessage::Done { id },
+            Self::Interrupted { requeued: true } => ServerMessage::Queued {
+                id,
+                detail: "Interrupted by urgent message".to_string(),
+            },
+            Self::Interrupted { requeued: false } => ServerMessage::Error {
+                id: Some(id),
+                message: "Interrupted by urgent message".to_string(),
+            },
+            Self::Cancelled => ServerMessage::Error {
+                id: Some(id),
+                message: "Cancelled".to_string(),
+            },
+            Self::Audio { mime, last, .. } => ServerMessage::Audio { id, mime, last },
+            Self::Error(message) => ServerMessage::Error {
+                id: Some(id),
+                message,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// First message of every connection
+    Hello {
+        version: u32,
+    },
+    Synthesizing {
+        id: u64,
+    },
+    Queued {
+        id: u64,
+        detail: String,
+    },
+    Playing {
+        id: u64,
+    },
+    Done {
+        id: u64,
+    },
+    /// Binary message with the audio follows
+    Audio {
+        id: u64,
+        mime: &'static str,
+        last: bool,
+    },
+    /// `id` is missing if the request could not be decoded
+    Error {
+        id: Option<u64>,
+        message: String,
+    },
+    Pong {
+        id: u64,
+    },
+    Subscribed {
+        id: u64,
+        topics: BTreeSet<Topic>,
+    },
+    Status {
+        id: u64,
+        version: &'static str,
+        /// Messages waiting for playback
+        queue: i64,
+        clients: i64,
+        teamspeak: bool,
+    },
+    Transcript {
+        speaker: String,
+        text: String,
+    },
+    Warning {
+        message: String,
+    },
+    History {
+        entry: HistoryEntry,
+    },
+}
+
+impl ServerMessage {
+    pub fn topic(&self) -> Option<Topic> {
+        match self {
+            Self::Transcript { .. } => Some(Topic::Transcript),
+            Self::Warning { .. } => Some(Topic::Warning),
+            Self::History { .. } => Some(Topic::History),
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Server message is always serializable")
+    }
+}
+
+impl From<BroadcastEvent> for ServerMessage {
+    fn from(value: BroadcastEvent) -> Self {
+        match value {
+            BroadcastEvent::Transcript(speaker, text) => Self::Transcript { speaker, text },
+            BroadcastEvent::Warning(message) => Self::Warning { message },
+            BroadcastEvent::History(entry) => Self::History { entry },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{ClientMessage, Progress, ServerMessage, Topic};
+
+    #[test]
+    fn test_messages() {
+        let message: ClientMessage = serde_json::from_value(json!({
+            "type": "speak", "id": 7, "content": "Hello", "preset": "narrator"
+        }))
+        .unwrap();
+        assert!(matches!(message, ClientMessage::Speak { id: 7, .. }));
+        let message: ClientMessage = serde_json::from_value(json!({
+            "type": "subscribe", "id": 8, "topics": ["warning", "history"]
+        }))
+        .unwrap();
+        let ClientMessage::Subscribe { topics, .. } = message else {
+            panic!("Subscribe expected");
+        };
+        assert_eq!(
+            topics.into_iter().collect::<Vec<_>>(),
+            [Topic::Warning, Topic::History]
+        );
+        assert!(serde_json::from_value::<ClientMessage>(json!({"type": "ping"})).is_err());
+
+        assert_eq!(
+            serde_json::to_value(Progress::Queued("Hit cache".to_string()).into_message(7))
+                .unwrap(),
+            json!({"type": "queued", "id": 7, "detail": "Hit cache"})
+        );
+        assert_eq!(
+            serde_json::to_value(Progress::Done.into_message(7)).unwrap(),
+            json!({"type": "done", "id": 7})
+        );
+        assert_eq!(
+            ServerMessage::Warning {
+                message: String::new()
+            }
+            .topic(),
+            Some(Topic::Warning)
+        );
+        assert_eq!(Progress::Synthesizing.legacy(), None);
+    }
+}
@@ -0,0 +1,450 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use kstool_helper_generator::Helper;
+use ogg::{PacketWriteEndInfo, PacketWriter};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::{config::Recorder, types::current_timestamp};
+
+const OPUS_SAMPLE_RATE: u128 = 48000;
+// TeamSpeak sends 20ms Opus frames
+const FRAME_SAMPLES: u64 = 960;
+// Force a page boundary roughly every second so an unclean exit loses little audio
+const PACKETS_PER_PAGE: usize = 50;
+/// Speakers quiet for this long get a new file once they talk again
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// Files are split once they cover this long
+const MAX_FILE_DURATION: Duration = Duration::from_secs(3600);
+
+#[derive(Helper)]
+pub enum RecorderEvent {
+    /// Client id, speaker name, channel count, opus packet
+    Audio(u16, String, u8, Vec<u8>),
+    /// Client id of a speaker who left the channel
+    Leave(u16),
+    Exit,
+}
+
+pub(crate) struct SpeakerStream {
+    path: PathBuf,
+    writer: PacketWriter<BufWriter<File>>,
+    serial: u32,
+    start: Instant,
+    last: Instant,
+    granule: u64,
+    packets: usize,
+    // Held back one packet so the last one can be marked as end of stream
+    pending: Option<(Vec<u8>, u64)>,
+}
+
+impl SpeakerStream {
+    fn sanitize(name: &str) -> String {
+        name.chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    }
+
+    fn build_head(channels: u8) -> Vec<u8> {
+        let mut head = Vec::with_capacity(19);
+        head.extend_from_slice(b"OpusHead");
+        head.push(1);
+        head.push(channels);
+        head.extend_from_slice(&0u16.to_le_bytes());
+        head.extend_from_slice(&(OPUS_SAMPLE_RATE as u32).to_le_bytes());
+        head.extend_from_slice(&0i16.to_le_bytes());
+        head.push(0);
+        head
+    }
+
+    fn build_tags(comments: &[String]) -> Vec<u8> {
+        let vendor = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+        let mut tags = Vec::new();
+        tags.extend_from_slice(b"OpusTags");
+        tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        tags.extend_from_slice(vendor.as_bytes());
+        tags.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            tags.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            tags.extend_from_slice(comment.as_bytes());
+        }
+        tags
+    }
+
+    fn create(folder: &Path, client_id: u16, name: &str, channels: u8) -> std::io::Result<Self> {
+        let base = format!(
+            "{}_{client_id}_{}",
+            current_timestamp(),
+            Self::sanitize(name)
+        );
+        // Speaker left and came back within the same second
+        let mut path = folder.join(format!("{base}.ogg"));
+        let mut index = 1;
+        while path.exists() {
+            path = folder.join(format!("{base}-{index}.ogg"));
+            index += 1;
+        }
+        log::debug!("Start recording {name}({client_id}) to {path:?}");
+        Self::open(path, name, channels)
+    }
+
+    pub(crate) fn open(path: PathBuf, name: &str, channels: u8) -> std::io::Result<Self> {
+        let timestamp = current_timestamp();
+        let serial = rand::random();
+        let mut writer = PacketWriter::new(BufWriter::new(File::create(&path)?));
+        writer.write_packet(
+            Self::build_head(channels).into_boxed_slice(),
+            serial,
+            PacketWriteEndInfo::EndPage,
+            0,
+        )?;
+        writer.write_packet(
+            Self::build_tags(&[format!("SPEAKER={name}"), format!("TIMESTAMP={timestamp}")])
+                .into_boxed_slice(),
+            serial,
+            PacketWriteEndInfo::EndPage,
+            0,
+        )?;
+        Ok(Self {
+            path,
+            writer,
+            serial,
+            start: Instant::now(),
+            last: Instant::now(),
+            granule: 0,
+            packets: 0,
+            pending: None,
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Quiet for too long or covering too much time, next packet goes to a new file
+    fn is_expired(&self) -> bool {
+        self.last.elapsed() >= IDLE_TIMEOUT || self.start.elapsed() >= MAX_FILE_DURATION
+    }
+
+    pub(crate) fn write(&mut self, data: Vec<u8>) -> std::io::Result<()> {
+        self.last = Instant::now();
+        // Granule follows wall clock, so silence between talk spurts keeps its real length
+        let elapsed = (self.start.elapsed().as_micros() * OPUS_SAMPLE_RATE / 1_000_000) as u64;
+        self.granule = elapsed.max(self.granule + FRAME_SAMPLES);
+        let Some((previous, granule)) = self.pending.replace((data, self.granule)) else {
+            return Ok(());
+        };
+        self.packets += 1;
+        let end_page = self.packets >= PACKETS_PER_PAGE;
+        self.writer.write_packet(
+            previous.into_boxed_slice(),
+            self.serial,
+            if end_page {
+                PacketWriteEndInfo::EndPage
+            } else {
+                PacketWriteEndInfo::NormalPacket
+            },
+            granule,
+        )?;
+        if end_page {
+            self.packets = 0;
+            self.writer.inner_mut().flush()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> std::io::Result<()> {
+        if let Some((data, granule)) = self.pending.take() {
+            self.writer.write_packet(
+                data.into_boxed_slice(),
+                self.serial,
+                PacketWriteEndInfo::EndStream,
+                granule,
+            )?;
+        }
+        self.writer.inner_mut().flush()?;
+        log::trace!("Finish stream {:?}", self.path);
+        Ok(())
+    }
+}
+
+pub struct VoiceRecorder {
+    handle: JoinHandle<anyhow::Result<()>>,
+}
+
+impl VoiceRecorder {
+    pub fn start(config: Recorder) -> (Self, RecorderHelper) {
+        let (sender, receiver) = RecorderHelper::new(256);
+        (
+            Self {
+                handle: tokio::spawn(Self::run(config, receiver)),
+            },
+            sender,
+        )
+    }
+
+    fn cleanup(folder: &Path, retention: Duration, active: &[PathBuf]) {
+        let entries = match std::fs::read_dir(folder) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::error!("Unable read recording folder: {e:?}");
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "ogg") || active.contains(&path) {
+                continue;
+            }
+            let expired = entry
+                .metadata()
+                .and_then(|meta| meta.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|elapsed| elapsed > retention);
+            if expired {
+                std::fs::remove_file(&path)
+                    .inspect(|_| log::debug!("Remove expired recording {path:?}"))
+                    .inspect_err(|e| log::error!("Unable remove {path:?}: {e:?}"))
+                    .ok();
+            }
+        }
+    }
+
+    async fn finish(stream: SpeakerStream) -> anyhow::Result<()> {
+        tokio::task::spawn_blocking(move || {
+            stream
+                .finish()
+                .inspect_err(|e| log::error!("Unable finish recording: {e:?}"))
+                .ok();
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Write packet to the stream of speaker, a new file is started for a new session
+    async fn write(
+        folder: &Path,
+        stream: Option<SpeakerStream>,
+        client_id: u16,
+        name: String,
+        channels: u8,
+        data: Vec<u8>,
+    ) -> anyhow::Result<Option<SpeakerStream>> {
+        let stream = match stream {
+            Some(stream) if stream.is_expired() => {
+                Self::finish(stream).await?;
+                None
+            }
+            stream => stream,
+        };
+        let folder = folder.to_path_buf();
+        Ok(tokio::task::spawn_blocking(move || {
+            let mut stream = match stream {
+                Some(stream) => stream,
+                None => SpeakerStream::create(&folder, client_id, &name, channels)
+                    .inspect_err(|e| log::error!("Unable create recording file: {e:?}"))
+                    .ok()?,
+            };
+            stream
+                .write(data)
+                .inspect_err(|e| log::error!("Unable write recording: {e:?}"))
+                .ok();
+            Some(stream)
+        })
+        .await?)
+    }
+
+    async fn run(
+        config: Recorder,
+        mut receiver: mpsc::Receiver<RecorderEvent>,
+    ) -> anyhow::Result<()> {
+        let folder = PathBuf::from(config.folder());
+        tokio::fs::create_dir_all(&folder).await?;
+
+        let mut streams: HashMap<u16, SpeakerStream> = HashMap::new();
+        let mut cleanup_timer = tokio::time::interval(Duration::from_secs(3600));
+        let mut idle_timer = tokio::time::interval(Duration::from_secs(10));
+
+        loop {
+            tokio::select! {
+                event = receiver.recv() => {
+                    match event {
+                        Some(RecorderEvent::Audio(client_id, name, channels, data)) => {
+                            let stream = streams.remove(&client_id);
+                            if let Some(stream) =
+                                Self::write(&folder, stream, client_id, name, channels, data).await?
+                            {
+                                streams.insert(client_id, stream);
+                            }
+                        }
+                        Some(RecorderEvent::Leave(client_id)) => {
+                            if let Some(stream) = streams.remove(&client_id) {
+                                Self::finish(stream).await?;
+                            }
+                        }
+                        Some(RecorderEvent::Exit) | None => break,
+                    }
+                }
+                _ = idle_timer.tick() => {
+                    let expired = streams
+                        .iter()
+                        .filter(|(_, stream)| stream.is_expired())
+                        .map(|(client_id, _)| *client_id)
+                        .collect::<Vec<_>>();
+                    for client_id in expired {
+                        if let Some(stream) = streams.remove(&client_id) {
+                            Self::finish(stream).await?;
+                        }
+                    }
+                }
+                _ = cleanup_timer.tick() => {
+                    if let Some(retention) = config.retention() {
+                        let folder = folder.clone();
+                        let active = streams
+                            .values()
+                            .map(|stream| stream.path.clone())
+                            .collect::<Vec<_>>();
+                        tokio::task::spawn_blocking(move || {
+                            Self::cleanup(&folder, retention, &active)
+                        })
+                        .await?;
+                    }
+                }
+            }
+        }
+
+        for (_, stream) in streams.drain() {
+            Self::finish(stream).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn join(self) -> anyhow::Result<()> {
+        self.handle.await?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs::File, path::PathBuf, time::Duration};
+
+    use ogg::PacketReader;
+
+    use super::{FRAME_SAMPLES, PACKETS_PER_PAGE, SpeakerStream, VoiceRecorder};
+
+    fn folder(name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("recorder-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    #[test]
+    fn test_speaker_stream() {
+        let folder = folder("stream");
+        let path = folder.join("stream.ogg");
+        let mut stream = SpeakerStream::open(path.clone(), "Alice", 2).unwrap();
+        for index in 0..=PACKETS_PER_PAGE {
+            stream.write(vec![index as u8]).unwrap();
+        }
+        stream.write(vec![0xff]).unwrap();
+        stream.finish().unwrap();
+
+        let mut reader = PacketReader::new(File::open(&path).unwrap());
+        let head = reader.read_packet_expected().unwrap();
+        assert_eq!(&head.data[..8], b"OpusHead");
+        assert_eq!(head.data[9], 2);
+        assert_eq!(
+            u32::from_le_bytes(head.data[12..16].try_into().unwrap()),
+            48000
+        );
+        assert!(head.last_in_page());
+        assert_eq!(head.absgp_page(), 0);
+        let tags = reader.read_packet_expected().unwrap();
+        assert!(tags.data.starts_with(b"OpusTags"));
+        let comments = String::from_utf8_lossy(&tags.data);
+        assert!(comments.contains("SPEAKER=Alice"));
+        assert!(comments.contains("TIMESTAMP="));
+        assert_eq!(tags.absgp_page(), 0);
+
+        let mut packets = Vec::new();
+        while let Some(packet) = reader.read_packet().unwrap() {
+            packets.push(packet);
+        }
+        assert_eq!(packets.len(), PACKETS_PER_PAGE + 2);
+        // Page ends after a fixed number of packets, granule counts 20ms frames
+        let page_end = &packets[PACKETS_PER_PAGE - 1];
+        assert!(page_end.last_in_page());
+        assert_eq!(
+            page_end.absgp_page(),
+            PACKETS_PER_PAGE as u64 * FRAME_SAMPLES
+        );
+        let last = packets.last().unwrap();
+        assert!(last.last_in_stream());
+        assert_eq!(last.data, [0xff]);
+        assert_eq!(
+            last.absgp_page(),
+            (PACKETS_PER_PAGE as u64 + 2) * FRAME_SAMPLES
+        );
+        std::fs::remove_dir_all(folder).ok();
+    }
+
+    #[test]
+    fn test_granule_follows_clock() {
+        let folder = folder("clock");
+        let path = folder.join("clock.ogg");
+        let mut stream = SpeakerStream::open(path.clone(), "Bob", 1).unwrap();
+        stream.write(vec![1]).unwrap();
+        // Silence between talk spurts keeps its length
+        std::thread::sleep(Duration::from_millis(200));
+        stream.write(vec![2]).unwrap();
+        stream.finish().unwrap();
+
+        let mut reader = PacketReader::new(File::open(&path).unwrap());
+        let packets = std::iter::from_fn(|| reader.read_packet().unwrap()).collect::<Vec<_>>();
+        assert_eq!(packets.len(), 4);
+        assert!(packets[3].last_in_stream());
+        assert!(packets[3].absgp_page() >= 48000 / 5);
+        std::fs::remove_dir_all(folder).ok();
+    }
+
+    #[tokio::test]
+    async fn test_session_per_join() {
+        let folder = folder("session");
+        let config =
+            toml::from_str(&format!("folder = {:?}", folder.display().to_string())).unwrap();
+        let (recorder, helper) = VoiceRecorder::start(config);
+        helper.audio(1, "Alice".to_string(), 1, vec![1]).await;
+        helper.audio(1, "Alice".to_string(), 1, vec![2]).await;
+        // Leaving finishes the file, joining again starts a new one
+        helper.leave(1).await;
+        helper.audio(1, "Alice".to_string(), 1, vec![3]).await;
+        helper.exit().await;
+        recorder.join().await.unwrap();
+
+        let mut packets = std::fs::read_dir(&folder)
+            .unwrap()
+            .map(|entry| {
+                let mut reader = PacketReader::new(File::open(entry.unwrap().path()).unwrap());
+                std::iter::from_fn(|| reader.read_packet().unwrap())
+                    .skip(2)
+                    .map(|packet| packet.data)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        packets.sort();
+        assert_eq!(packets, [vec![vec![1], vec![2]], vec![vec![3]]]);
+        std::fs::remove_dir_all(folder).ok();
+    }
+}
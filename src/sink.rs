@@ -0,0 +1,89 @@
+use futures::future::BoxFuture;
+use tokio::{sync::mpsc, time::Instant};
+use tsproto_packets::packets::{AudioData, CodecType, OutAudio};
+
+use crate::tts::TeamSpeakEvent;
+
+/// Destination of played audio, receives one Opus packet every 20ms while unmuted
+pub(crate) trait AudioSink: Send {
+    fn muted(&mut self, muted: bool) -> BoxFuture<'_, ()>;
+    fn audio<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, ()>;
+}
+
+/// Forwards audio to the TeamSpeak connection
+pub(crate) struct TeamSpeakSink {
+    sender: mpsc::Sender<TeamSpeakEvent>,
+}
+
+impl TeamSpeakSink {
+    pub(crate) fn new(sender: mpsc::Sender<TeamSpeakEvent>) -> Self {
+        Self { sender }
+    }
+}
+
+impl AudioSink for TeamSpeakSink {
+    fn muted(&mut self, muted: bool) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            self.sender.send(TeamSpeakEvent::Muted(muted)).await.ok();
+        })
+    }
+
+    fn audio<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            self.sender
+                .send(TeamSpeakEvent::Data(OutAudio::new(&AudioData::C2S {
+                    id: 0,
+                    codec: CodecType::OpusVoice,
+                    data,
+                })))
+                .await
+                .inspect_err(|_| log::error!("Send error"))
+                .ok();
+        })
+    }
+}
+
+/// Used by `--dry-run`, only logs what would be sent to TeamSpeak
+#[derive(Default)]
+pub(crate) struct DryRunSink {
+    start: Option<Instant>,
+    packets: usize,
+    bytes: usize,
+}
+
+impl AudioSink for DryRunSink {
+    fn muted(&mut self, muted: bool) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            if !muted {
+                self.start = Some(Instant::now());
+                self.packets = 0;
+                self.bytes = 0;
+                log::info!("[dry-run] Start playback");
+                return;
+            }
+            let elapsed = self
+                .start
+                .take()
+                .map(|start| start.elapsed())
+                .unwrap_or_default();
+            log::info!(
+                "[dry-run] Played {} packets ({} bytes, {}ms of audio) in {elapsed:?}",
+                self.packets,
+                self.bytes,
+                self.packets * 20
+            );
+        })
+    }
+
+    fn audio<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            self.packets += 1;
+            self.bytes += data.len();
+            log::trace!(
+                "[dry-run] Packet {} with {} bytes",
+                self.packets,
+                data.len()
+            );
+        })
+    }
+}
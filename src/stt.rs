@@ -0,0 +1,385 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::future::BoxFuture;
+use kstool_helper_generator::Helper;
+use tokio::{
+    sync::{Semaphore, broadcast, mpsc},
+    task::JoinHandle,
+};
+
+use crate::{config::Stt, recorder::SpeakerStream, tts::TeamSpeakEvent, types::BroadcastEvent};
+
+// TeamSpeak limits text messages to 1024 characters
+const MAX_CHAT_LENGTH: usize = 1000;
+
+#[derive(Helper)]
+pub enum TranscriberEvent {
+    /// Client id, speaker name, channel count, opus packet
+    Audio(u16, String, u8, Vec<u8>),
+    Exit,
+}
+
+/// Speech-to-text engine, takes an Ogg/Opus file and returns the transcript
+pub trait SpeechBackend: Send + Sync {
+    fn transcribe<'a>(&'a self, audio: &'a Path) -> BoxFuture<'a, anyhow::Result<String>>;
+}
+
+/// Runs a local program (e.g. whisper.cpp) and reads transcript from its stdout
+pub struct CommandBackend {
+    command: Vec<String>,
+    timeout: Duration,
+}
+
+impl CommandBackend {
+    pub fn new(command: Vec<String>, timeout: Duration) -> Self {
+        Self { command, timeout }
+    }
+
+    async fn run(&self, audio: &Path) -> anyhow::Result<String> {
+        let (program, args) = self
+            .command
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("STT command is empty"))?;
+        let input = audio.to_string_lossy();
+        let output = tokio::time::timeout(
+            self.timeout,
+            tokio::process::Command::new(program)
+                .args(args.iter().map(|arg| arg.replace("{input}", &input)))
+                .kill_on_drop(true)
+                .output(),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("STT command timeout"))??;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "STT command exit with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" "))
+    }
+}
+
+impl SpeechBackend for CommandBackend {
+    fn transcribe<'a>(&'a self, audio: &'a Path) -> BoxFuture<'a, anyhow::Result<String>> {
+        Box::pin(self.run(audio))
+    }
+}
+
+struct Utterance {
+    name: String,
+    stream: SpeakerStream,
+    start: Instant,
+    last: Instant,
+}
+
+pub struct Transcriber {
+    handle: JoinHandle<anyhow::Result<()>>,
+}
+
+impl Transcriber {
+    pub(crate) fn start(
+        config: Stt,
+        teamspeak: mpsc::Sender<TeamSpeakEvent>,
+        broadcast: broadcast::Sender<BroadcastEvent>,
+    ) -> (Self, TranscriberHelper) {
+        let backend: Arc<dyn SpeechBackend> = Arc::new(CommandBackend::new(
+            config.command().to_vec(),
+            config.timeout(),
+        ));
+        let (sender, receiver) = TranscriberHelper::new(256);
+        (
+            Self {
+                handle: tokio::spawn(Self::run(config, backend, receiver, teamspeak, broadcast)),
+            },
+            sender,
+        )
+    }
+
+    fn temp_file(client_id: u16, sequence: usize) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "{}-{}-{client_id}-{sequence}.ogg",
+            env!("CARGO_PKG_NAME"),
+            std::process::id()
+        ))
+    }
+
+    async fn transcribe(
+        backend: Arc<dyn SpeechBackend>,
+        permits: Arc<Semaphore>,
+        utterance: Utterance,
+        chat: bool,
+        teamspeak: mpsc::Sender<TeamSpeakEvent>,
+        broadcast: broadcast::Sender<BroadcastEvent>,
+    ) {
+        let Utterance { name, stream, .. } = utterance;
+        let path = stream.path().to_path_buf();
+        if let Err(e) = stream.finish() {
+            log::error!("Unable finish utterance: {e:?}");
+        } else if let Ok(_permit) = permits.acquire().await {
+            match backend.transcribe(&path).await {
+                Ok(text) if !text.is_empty() => {
+                    log::debug!("Transcript {name}: {text}");
+                    if chat {
+                        let mut message = format!("{name}: {text}");
+                        if message.chars().count() > MAX_CHAT_LENGTH {
+                            message = message.chars().take(MAX_CHAT_LENGTH).collect();
+                        }
+                        teamspeak.send(TeamSpeakEvent::Message(message)).await.ok();
+                    }
+                    broadcast.send(BroadcastEvent::Transcript(name, text)).ok();
+                }
+                Ok(_) => {}
+                Err(e) => log::error!("Transcribe error: {e:?}"),
+            }
+        }
+        tokio::fs::remove_file(&path).await.ok();
+    }
+
+    async fn run(
+        config: Stt,
+        backend: Arc<dyn SpeechBackend>,
+        mut receiver: mpsc::Receiver<TranscriberEvent>,
+        teamspeak: mpsc::Sender<TeamSpeakEvent>,
+        broadcast: broadcast::Sender<BroadcastEvent>,
+    ) -> anyhow::Result<()> {
+        let mut utterances: HashMap<u16, Utterance> = HashMap::new();
+        let mut sequence = 0;
+        let mut timer = tokio::time::interval(Duration::from_millis(200));
+        let mut tasks = Vec::new();
+        let permits = Arc::new(Semaphore::new(config.concurrency()));
+
+        loop {
+            tokio::select! {
+                event = receiver.recv() => {
+                    match event {
+                        Some(TranscriberEvent::Audio(client_id, name, channels, data)) => {
+                            if let Some(utterance) = utterances.get_mut(&client_id) {
+                                utterance.last = Instant::now();
+                                utterance
+                                    .stream
+                                    .write(data)
+                                    .inspect_err(|e| log::error!("Unable write utterance: {e:?}"))
+                                    .ok();
+                                continue;
+                            }
+                            sequence += 1;
+                            let mut stream = match SpeakerStream::open(
+                                Self::temp_file(client_id, sequence),
+                                &name,
+                                channels,
+                            ) {
+                                Ok(stream) => stream,
+                                Err(e) => {
+                                    log::error!("Unable create utterance file: {e:?}");
+                                    continue;
+                                }
+                            };
+                            stream.write(data).ok();
+                            utterances.insert(
+                                client_id,
+                                Utterance {
+                                    name,
+                                    stream,
+                                    start: Instant::now(),
+                                    last: Instant::now(),
+                                },
+                            );
+                        }
+                        Some(TranscriberEvent::Exit) | None => break,
+                    }
+                }
+                _ = timer.tick() => {
+                    let finished = utterances
+                        .iter()
+                        .filter(|(_, utterance)| utterance.last.elapsed() > config.silence())
+                        .map(|(client_id, _)| *client_id)
+                        .collect::<Vec<_>>();
+                    for client_id in finished {
+                        let Some(utterance) = utterances.remove(&client_id) else {
+                            continue;
+                        };
+                        if utterance.last - utterance.start < config.min_duration() {
+                            let path = utterance.stream.path().to_path_buf();
+                            drop(utterance);
+                            tokio::fs::remove_file(path).await.ok();
+                            continue;
+                        }
+                        tasks.retain(|task: &JoinHandle<()>| !task.is_finished());
+                        tasks.push(tokio::spawn(Self::transcribe(
+                            backend.clone(),
+                            permits.clone(),
+                            utterance,
+                            config.chat(),
+                            teamspeak.clone(),
+                            broadcast.clone(),
+                        )));
+                    }
+                }
+            }
+        }
+
+        for (_, utterance) in utterances.drain() {
+            let path = utterance.stream.path().to_path_buf();
+            drop(utterance);
+            tokio::fs::remove_file(path).await.ok();
+        }
+        for task in tasks {
+            task.await.ok();
+        }
+        Ok(())
+    }
+
+    pub async fn join(self) -> anyhow::Result<()> {
+        self.handle.await?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        path::Path,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use futures::future::BoxFuture;
+    use tokio::sync::{broadcast, mpsc};
+
+    use super::{CommandBackend, SpeechBackend, Transcriber, TranscriberHelper};
+    use crate::{tts::TeamSpeakEvent, types::BroadcastEvent};
+
+    fn command(script: &str) -> Vec<String> {
+        vec!["sh".to_string(), "-c".to_string(), script.to_string()]
+    }
+
+    #[tokio::test]
+    async fn test_command_backend() {
+        let input = std::env::temp_dir().join(format!("stt-{}.ogg", std::process::id()));
+        std::fs::write(&input, "audio").unwrap();
+        let transcribe = async |command: Vec<String>, timeout: Duration| {
+            CommandBackend::new(command, timeout).run(&input).await
+        };
+
+        // Whitespace is collapsed, input is replaced by file path
+        assert_eq!(
+            transcribe(
+                command("printf ' Hello\\n  world '; cat {input}"),
+                Duration::from_secs(5)
+            )
+            .await
+            .unwrap(),
+            "Hello world audio"
+        );
+        let error = transcribe(command("echo broken >&2; exit 3"), Duration::from_secs(5))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("broken"), "{error}");
+        let error = transcribe(command("sleep 5"), Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "STT command timeout");
+        assert!(
+            transcribe(Vec::new(), Duration::from_secs(5))
+                .await
+                .is_err()
+        );
+        std::fs::remove_file(input).ok();
+    }
+
+    /// Counts audio packets of each utterance, keeps track of calls running at once
+    #[derive(Default)]
+    struct PacketCounter {
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+    }
+
+    impl SpeechBackend for PacketCounter {
+        fn transcribe<'a>(&'a self, audio: &'a Path) -> BoxFuture<'a, anyhow::Result<String>> {
+            Box::pin(async move {
+                let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_running.fetch_max(running, Ordering::SeqCst);
+                let mut reader = ogg::PacketReader::new(std::fs::File::open(audio)?);
+                // Head and tags
+                let packets = std::iter::from_fn(|| reader.read_packet().unwrap()).count() - 2;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                self.running.fetch_sub(1, Ordering::SeqCst);
+                Ok(format!("{packets} packets"))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_utterances() {
+        let config = toml::from_str(
+            "command = [\"true\"]\nsilence = 100\nmin_duration = 100\nconcurrency = 1",
+        )
+        .unwrap();
+        let backend = Arc::new(PacketCounter::default());
+        let (helper, receiver) = TranscriberHelper::new(64);
+        let (teamspeak, mut messages) = mpsc::channel(8);
+        let (broadcast, mut transcripts) = broadcast::channel(8);
+        let transcriber = tokio::spawn(Transcriber::run(
+            config,
+            backend.clone(),
+            receiver,
+            teamspeak,
+            broadcast,
+        ));
+        let talk = async |speakers: &[(u16, &str)], packets: usize| {
+            for _ in 0..packets {
+                for (client_id, name) in speakers {
+                    helper.audio(*client_id, name.to_string(), 1, vec![1]).await;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        let mut next = async || {
+            let Ok(Ok(BroadcastEvent::Transcript(name, text))) =
+                tokio::time::timeout(Duration::from_secs(5), transcripts.recv()).await
+            else {
+                panic!("Transcript expected");
+            };
+            (name, text)
+        };
+
+        // Too short to be transcribed
+        helper.audio(3, "Carol".to_string(), 1, vec![1]).await;
+        talk(&[(1, "Alice"), (2, "Bob")], 10).await;
+        let mut received = vec![next().await, next().await];
+        received.sort();
+        assert_eq!(
+            received,
+            [
+                ("Alice".to_string(), "10 packets".to_string()),
+                ("Bob".to_string(), "10 packets".to_string())
+            ]
+        );
+        // Silence ends an utterance, talking again starts a new one
+        talk(&[(1, "Alice")], 6).await;
+        assert_eq!(next().await, ("Alice".to_string(), "6 packets".to_string()));
+
+        helper.exit().await;
+        transcriber.await.unwrap().unwrap();
+        assert_eq!(backend.max_running.load(Ordering::SeqCst), 1);
+        assert!(transcripts.try_recv().is_err());
+        let mut chat = Vec::new();
+        while let Ok(TeamSpeakEvent::Message(message)) = messages.try_recv() {
+            chat.push(message);
+        }
+        assert_eq!(chat.len(), 3);
+        assert!(chat.contains(&"Alice: 6 packets".to_string()));
+    }
+}
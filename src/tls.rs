@@ -0,0 +1,264 @@
+//! HTTPS for the web server, certificate and key are loaded again once their files change
+
+use std::{net::SocketAddr, sync::Arc, time::SystemTime};
+
+use anyhow::anyhow;
+use axum::{
+    extract::connect_info::Connected,
+    serve::{IncomingStream, Listener},
+};
+use tokio::{
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{Semaphore, mpsc, watch},
+};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        ServerConfig,
+        crypto::ring::default_provider,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    },
+    server::TlsStream,
+};
+
+use crate::{config::Tls, web::PeerAddr};
+
+/// Clients that do not finish the handshake in time are dropped
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+/// Connections beyond this many pending handshakes are dropped right away
+const MAX_HANDSHAKES: usize = 64;
+
+/// Accepts TCP connections and hands out those that completed the TLS handshake,
+/// handshakes run in the background so a slow client does not hold up the others
+pub struct TlsListener {
+    receiver: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub async fn bind<A: ToSocketAddrs>(addr: A, tls: &Tls) -> anyhow::Result<Self> {
+        let config = load(tls).await?;
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (config_sender, config) = watch::channel(config);
+        if let Some(interval) = tls.reload() {
+            tokio::spawn(reload(tls.clone(), config_sender, interval));
+        }
+        let (sender, receiver) = mpsc::channel(16);
+        tokio::spawn(accept(listener, config, sender));
+        Ok(Self {
+            receiver,
+            local_addr,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.receiver.recv().await {
+            Some(connection) => connection,
+            // Accept task never stops while the listener is alive
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        Self(*stream.remote_addr())
+    }
+}
+
+async fn accept(
+    listener: TcpListener,
+    config: watch::Receiver<Arc<ServerConfig>>,
+    sender: mpsc::Sender<(TlsStream<TcpStream>, SocketAddr)>,
+) {
+    let handshakes = Arc::new(Semaphore::new(MAX_HANDSHAKES));
+    loop {
+        let (stream, addr) = tokio::select! {
+            ret = listener.accept() => match ret {
+                Ok(connection) => connection,
+                Err(e) => {
+                    log::warn!("Unable accept connection: {e:?}");
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = sender.closed() => break,
+        };
+        let Ok(permit) = handshakes.clone().try_acquire_owned() else {
+            log::debug!("Too many pending TLS handshakes, drop connection from {addr}");
+            continue;
+        };
+        let acceptor = TlsAcceptor::from(config.borrow().clone());
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            // Held until the connection is handed out
+            let _permit = permit;
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    sender.send((stream, addr)).await.ok();
+                }
+                Ok(Err(e)) => log::debug!("TLS handshake with {addr} failed: {e}"),
+                Err(_) => log::debug!("TLS handshake with {addr} timed out"),
+            }
+        });
+    }
+}
+
+async fn load(tls: &Tls) -> anyhow::Result<Arc<ServerConfig>> {
+    let (cert, key) = (tls.cert().to_string(), tls.key().to_string());
+    tokio::task::spawn_blocking(move || {
+        let certs = CertificateDer::pem_file_iter(&cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| anyhow!("Unable read certificate {cert:?}: {e}"))?;
+        if certs.is_empty() {
+            return Err(anyhow!("No certificate found in {cert:?}"));
+        }
+        let key = PrivateKeyDer::from_pem_file(&key)
+            .map_err(|e| anyhow!("Unable read private key {key:?}: {e}"))?;
+        let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    })
+    .await?
+}
+
+async fn modified(tls: &Tls) -> Option<(SystemTime, SystemTime)> {
+    let cert = tokio::fs::metadata(tls.cert())
+        .await
+        .ok()?
+        .modified()
+        .ok()?;
+    let key = tokio::fs::metadata(tls.key()).await.ok()?.modified().ok()?;
+    Some((cert, key))
+}
+
+/// Replace the config once certificate or key changed, the old one stays on error
+async fn reload(tls: Tls, sender: watch::Sender<Arc<ServerConfig>>, interval: std::time::Duration) {
+    let mut last = modified(&tls).await;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = sender.closed() => break,
+        }
+        let current = modified(&tls).await;
+        if current.is_none() || current == last {
+            continue;
+        }
+        last = current;
+        match load(&tls).await {
+            Ok(config) => {
+                sender.send_replace(config);
+                log::info!("TLS certificate reloaded");
+            }
+            Err(e) => log::error!("Unable reload TLS certificate, keep the old one: {e:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::serve::Listener;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    use super::{MAX_HANDSHAKES, TlsListener};
+    use crate::{
+        config::Tls,
+        test_support::{TLS_CERT, TLS_KEY, tls_connector},
+    };
+
+    #[tokio::test]
+    async fn test_tls() {
+        let folder = std::env::temp_dir().join(format!("tls-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let (cert, key) = (folder.join("cert.pem"), folder.join("key.pem"));
+        std::fs::write(&cert, TLS_CERT).unwrap();
+        let tls: Tls = toml::from_str(&format!(
+            "cert = {:?}\nkey = {:?}\nreload = 0",
+            cert.display().to_string(),
+            key.display().to_string()
+        ))
+        .unwrap();
+        // Key is missing
+        assert!(TlsListener::bind("127.0.0.1:0", &tls).await.is_err());
+        std::fs::write(&key, TLS_KEY).unwrap();
+        let mut listener = TlsListener::bind("127.0.0.1:0", &tls).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let mut stream = tls_connector(&[])
+                .connect(ServerName::try_from("localhost").unwrap(), stream)
+                .await
+                .unwrap();
+            stream.write_all(b"ping").await.unwrap();
+            stream.flush().await.unwrap();
+            stream.get_ref().0.local_addr().unwrap()
+        });
+        let (mut stream, peer) = listener.accept().await;
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        assert_eq!(peer, client.await.unwrap());
+        std::fs::remove_dir_all(folder).ok();
+    }
+
+    #[tokio::test]
+    async fn test_handshake_limit() {
+        let folder = std::env::temp_dir().join(format!("tls-limit-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let (cert, key) = (folder.join("cert.pem"), folder.join("key.pem"));
+        std::fs::write(&cert, TLS_CERT).unwrap();
+        std::fs::write(&key, TLS_KEY).unwrap();
+        let tls: Tls = toml::from_str(&format!(
+            "cert = {:?}\nkey = {:?}\nreload = 0",
+            cert.display().to_string(),
+            key.display().to_string()
+        ))
+        .unwrap();
+        let mut listener = TlsListener::bind("127.0.0.1:0", &tls).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handshake = async || {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            tls_connector(&[])
+                .connect(ServerName::try_from("localhost").unwrap(), stream)
+                .await
+        };
+
+        // Clients that never start their handshake
+        let mut idle = Vec::new();
+        for _ in 0..MAX_HANDSHAKES {
+            idle.push(tokio::net::TcpStream::connect(addr).await.unwrap());
+        }
+        assert!(handshake().await.is_err());
+
+        // Limit is freed once pending handshakes end
+        drop(idle);
+        let client = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                if let Ok(stream) = handshake().await {
+                    break stream;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let (_stream, peer) = listener.accept().await;
+        assert_eq!(peer, client.get_ref().0.local_addr().unwrap());
+        std::fs::remove_dir_all(folder).ok();
+    }
+}
@@ -1,23 +1,38 @@
 use std::{
+    collections::VecDeque,
     io::{Cursor, Write},
     sync::{Arc, RwLock, atomic::AtomicUsize},
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
 use futures::{StreamExt, channel::oneshot};
 use reqwest::{
-    Response,
-    header::{CONTENT_LENGTH, CONTENT_TYPE, HeaderMap, USER_AGENT},
+    Response, StatusCode,
+    header::{CONTENT_LENGTH, CONTENT_TYPE, HeaderMap, RETRY_AFTER, USER_AGENT},
 };
-use symphonia::core::{
-    formats::FormatReader,
-    io::{MediaSource, MediaSourceStream},
+use serde::Deserialize;
+use symphonia::core::io::MediaSource;
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::LocalSet,
 };
-use tokio::{sync::mpsc, task::LocalSet};
 use tsclientlib::prelude::OutMessageTrait;
-use tsproto_packets::packets::{AudioData, OutAudio, OutPacket};
+use tsproto_packets::packets::OutPacket;
 
-use crate::{cache::ConnAgent, config::TTS, web::MessageHelper};
+use crate::{
+    audio::AudioReader,
+    cache::ConnAgent,
+    config::{InterruptMode, OutputFormat, TTS},
+    keys::{KeyEndpoint, KeyStore, mask},
+    metrics::Metrics,
+    presets::Preset,
+    protocol::Progress,
+    sink::AudioSink,
+    types::BroadcastEvent,
+    usage::UsageTracker,
+    voices::Voice,
+    web::MessageHelper,
+};
 
 pub struct MiddlewareTask {
     handle: std::thread::JoinHandle<anyhow::Result<()>>,
@@ -28,9 +43,12 @@ impl MiddlewareTask {
         receiver: mpsc::Receiver<TTSEvent>,
         sender: mpsc::Sender<TTSFinalEvent>,
         leveldb_helper: Arc<ConnAgent>,
+        metrics: Metrics,
     ) -> Self {
         Self {
-            handle: std::thread::spawn(move || Self::run(receiver, sender, leveldb_helper)),
+            handle: std::thread::spawn(move || {
+                Self::run(receiver, sender, leveldb_helper, metrics)
+            }),
         }
     }
 
@@ -38,21 +56,28 @@ impl MiddlewareTask {
         receiver: mpsc::Receiver<TTSEvent>,
         sender: mpsc::Sender<TTSFinalEvent>,
         leveldb_helper: Arc<ConnAgent>,
+        metrics: Metrics,
     ) -> anyhow::Result<()> {
         tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
             .unwrap()
-            .block_on(Self::bootstrap(receiver, sender, leveldb_helper))
+            .block_on(Self::bootstrap(receiver, sender, leveldb_helper, metrics))
     }
 
     pub async fn bootstrap(
         receiver: mpsc::Receiver<TTSEvent>,
         sender: mpsc::Sender<TTSFinalEvent>,
         leveldb_helper: Arc<ConnAgent>,
+        metrics: Metrics,
     ) -> anyhow::Result<()> {
         let localset = LocalSet::new();
-        localset.spawn_local(audio_middleware(receiver, sender, leveldb_helper.clone()));
+        localset.spawn_local(audio_middleware(
+            receiver,
+            sender,
+            leveldb_helper.clone(),
+            metrics,
+        ));
         localset.await;
         Ok(())
     }
@@ -64,6 +89,8 @@ impl MiddlewareTask {
 
 pub(crate) enum TeamSpeakEvent {
     Muted(bool),
+    Recording(bool),
+    Message(String),
     Data(OutPacket),
     Exit,
 }
@@ -87,28 +114,108 @@ impl TeamSpeakEvent {
             badges: None,
         }
     }
+
+    fn build_recording_status<'a>(
+        recording: bool,
+    ) -> tsclientlib::messages::c2s::OutClientUpdatePart<'a> {
+        tsclientlib::messages::c2s::OutClientUpdatePart {
+            name: None,
+            input_muted: None,
+            output_muted: None,
+            is_away: None,
+            away_message: None,
+            input_hardware_enabled: None,
+            output_hardware_enabled: None,
+            is_channel_commander: None,
+            avatar_hash: None,
+            phonetic_name: None,
+            talk_power_request: None,
+            talk_power_request_message: None,
+            is_recording: Some(recording),
+            badges: None,
+        }
+    }
 }
 
 impl OutMessageTrait for TeamSpeakEvent {
     fn to_packet(self) -> tsproto_packets::packets::OutCommand {
         tsclientlib::messages::c2s::OutClientUpdateMessage::new(&mut std::iter::once(match self {
             TeamSpeakEvent::Muted(muted) => Self::build_sound_status(muted),
+            TeamSpeakEvent::Recording(recording) => Self::build_recording_status(recording),
             _ => unreachable!("This is not command packet, please"),
         }))
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    Normal,
+    /// Played before any queued normal message
+    High,
+    /// Interrupts current playback
+    Urgent,
+}
+
+/// Part of a message read by one voice
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub voice: Preset,
+    pub text: String,
+}
+
+impl Segment {
+    pub fn new(voice: Preset, text: String) -> Self {
+        Self { voice, text }
+    }
+}
+
+/// Audio of one chunk of a long message
+pub(crate) enum Chunk {
+    NewData((u64, Option<usize>), reqwest::Response),
+    Data(bytes::Bytes),
+}
+
 pub(crate) enum TTSEvent {
-    NewData((u64, usize), reqwest::Response, MessageHelper),
-    Data(bytes::Bytes, MessageHelper),
+    NewData(
+        (u64, Option<usize>),
+        reqwest::Response,
+        MessageHelper,
+        Priority,
+    ),
+    Data(bytes::Bytes, MessageHelper, Priority),
+    /// Long message, chunks are sent in order as they are synthesized
+    Chunks(mpsc::UnboundedReceiver<Chunk>, MessageHelper, Priority),
     Exit,
 }
 
+/// Remaining chunks of a long message
+type ChunkReceiver = mpsc::UnboundedReceiver<Box<dyn MediaSource>>;
+
 pub(crate) enum TTSFinalEvent {
-    NewData(Box<dyn MediaSource>, MessageHelper),
+    NewData(Box<dyn MediaSource>, MessageHelper, Priority),
+    /// First chunk and the rest, played back-to-back as one message
+    Chunks(Box<dyn MediaSource>, ChunkReceiver, MessageHelper, Priority),
     Exit,
 }
 
+impl TTSFinalEvent {
+    fn into_queued(self) -> Option<QueuedAudio> {
+        let (raw, rest, helper, priority) = match self {
+            Self::NewData(raw, helper, priority) => (raw, None, helper, priority),
+            Self::Chunks(raw, rest, helper, priority) => (raw, Some(rest), helper, priority),
+            Self::Exit => return None,
+        };
+        Some(QueuedAudio {
+            source: QueuedSource::Raw(raw),
+            rest,
+            helper,
+            priority,
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct MutableMediaSource {
     data: Arc<RwLock<Vec<u8>>>,
@@ -130,11 +237,16 @@ impl MutableMediaSource {
 }
 
 impl std::io::Seek for MutableMediaSource {
-    fn seek(&mut self, _pos: std::io::SeekFrom) -> std::io::Result<u64> {
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Not Implement",
-        ))
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        // Only rewinding is needed to restart an interrupted playback
+        match pos {
+            std::io::SeekFrom::Start(offset) => {
+                self.offset
+                    .store(offset as usize, std::sync::atomic::Ordering::Release);
+                Ok(offset)
+            }
+            _ => Err(std::io::Error::other("Not Implement")),
+        }
     }
 }
 
@@ -171,13 +283,13 @@ async fn download(
     Ok(())
 }
 
-async fn delay_send(
-    (original_hash, length): (u64, usize),
+/// Source is returned once download finished or after 500ms, whichever comes first
+async fn start_download(
     response: Response,
-    sender: Arc<mpsc::Sender<TTSFinalEvent>>,
-    leveldb_helper: Arc<ConnAgent>,
-    helper: MessageHelper,
-) -> anyhow::Result<()> {
+) -> (
+    MutableMediaSource,
+    tokio::task::JoinHandle<anyhow::Result<()>>,
+) {
     let source = MutableMediaSource::new();
     let (s, receiver) = oneshot::channel();
     let handler = tokio::spawn(download(response, source.clone(), s));
@@ -185,21 +297,105 @@ async fn delay_send(
     tokio::time::timeout(Duration::from_millis(500), receiver)
         .await
         .ok();
+    (source, handler)
+}
+
+async fn delay_send(
+    original: (u64, Option<usize>),
+    response: Response,
+    sender: Arc<mpsc::Sender<TTSFinalEvent>>,
+    leveldb_helper: Arc<ConnAgent>,
+    metrics: Metrics,
+    helper: MessageHelper,
+    priority: Priority,
+) -> anyhow::Result<()> {
+    let (source, handler) = start_download(response).await;
     sender
-        .send(TTSFinalEvent::NewData(Box::new(source.clone()), helper))
+        .send(TTSFinalEvent::NewData(
+            Box::new(source.clone()),
+            helper,
+            priority,
+        ))
         .await
         .ok();
     handler.await??;
+    write_cache(original, &source, &leveldb_helper, &metrics).await
+}
+
+/// Forward chunks of a long message in order, playback starts with the first one
+async fn forward_chunks(
+    mut receiver: mpsc::UnboundedReceiver<Chunk>,
+    sender: Arc<mpsc::Sender<TTSFinalEvent>>,
+    leveldb_helper: Arc<ConnAgent>,
+    metrics: Metrics,
+    helper: MessageHelper,
+    priority: Priority,
+) -> anyhow::Result<()> {
+    let (chunk_sender, chunk_receiver) = mpsc::unbounded_channel();
+    let mut first = Some((chunk_receiver, helper));
+    let mut downloads = Vec::new();
+    while let Some(chunk) = receiver.recv().await {
+        let source: Box<dyn MediaSource> = match chunk {
+            Chunk::NewData(original, response) => {
+                let (source, handler) = start_download(response).await;
+                downloads.push((original, source.clone(), handler));
+                Box::new(source)
+            }
+            Chunk::Data(data) => Box::new(Cursor::new(data)),
+        };
+        match first.take() {
+            Some((rest, helper)) => {
+                sender
+                    .send(TTSFinalEvent::Chunks(source, rest, helper, priority))
+                    .await
+                    .ok();
+            }
+            None => {
+                // Playback is dropped, e.g. interrupted by urgent message
+                if chunk_sender.send(source).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    drop(chunk_sender);
+    for (original, source, handler) in downloads {
+        handler.await??;
+        write_cache(original, &source, &leveldb_helper, &metrics).await?;
+    }
+    Ok(())
+}
 
-    if length > 30 && length < 75 {
+async fn write_cache(
+    original: (u64, Option<usize>),
+    source: &MutableMediaSource,
+    leveldb_helper: &ConnAgent,
+    metrics: &Metrics,
+) -> anyhow::Result<()> {
+    let raw = { source.data.read().unwrap().to_vec() };
+    cache_audio(original, raw, leveldb_helper, metrics).await
+}
+
+/// Audio of medium length messages is not cached, unless length is `None`,
+/// e.g. the audio is kept in history for replay
+pub(crate) async fn cache_audio(
+    (original_hash, length): (u64, Option<usize>),
+    raw: Vec<u8>,
+    leveldb_helper: &ConnAgent,
+    metrics: &Metrics,
+) -> anyhow::Result<()> {
+    if let Some(length) = length
+        && length > 30
+        && length < 75
+    {
         log::trace!("Skip {original_hash} length: {length}");
         return Ok(());
     }
-    let raw = { source.data.read().unwrap().to_vec() };
     if raw.is_empty() {
         log::warn!("Input data is empty");
         return Ok(());
     }
+    metrics.cached(raw.len());
     leveldb_helper
         .set(original_hash, raw)
         .await
@@ -214,26 +410,43 @@ pub(crate) async fn audio_middleware(
     mut receiver: mpsc::Receiver<TTSEvent>,
     sender: mpsc::Sender<TTSFinalEvent>,
     leveldb_helper: Arc<ConnAgent>,
+    metrics: Metrics,
 ) -> anyhow::Result<()> {
     let sender = Arc::new(sender);
     let mut futures = Vec::new();
     while let Some(event) = receiver.recv().await {
         match event {
-            TTSEvent::NewData(original, response, helper) => {
+            TTSEvent::NewData(original, response, helper, priority) => {
                 futures.push(tokio::task::spawn_local(delay_send(
                     original,
                     response,
                     sender.clone(),
                     leveldb_helper.clone(),
+                    metrics.clone(),
                     helper,
+                    priority,
                 )));
             }
-            TTSEvent::Data(data, helper) => {
+            TTSEvent::Data(data, helper, priority) => {
                 sender
-                    .send(TTSFinalEvent::NewData(Box::new(Cursor::new(data)), helper))
+                    .send(TTSFinalEvent::NewData(
+                        Box::new(Cursor::new(data)),
+                        helper,
+                        priority,
+                    ))
                     .await
                     .ok();
             }
+            TTSEvent::Chunks(chunks, helper, priority) => {
+                futures.push(tokio::task::spawn_local(forward_chunks(
+                    chunks,
+                    sender.clone(),
+                    leveldb_helper.clone(),
+                    metrics.clone(),
+                    helper,
+                    priority,
+                )));
+            }
             TTSEvent::Exit => break,
         }
     }
@@ -243,73 +456,259 @@ pub(crate) async fn audio_middleware(
     Ok(())
 }
 
+enum QueuedSource {
+    Raw(Box<dyn MediaSource>),
+    /// Interrupted playback, keeps its position
+    Reader(AudioReader),
+}
+
+impl QueuedSource {
+    fn into_reader(self, format: &OutputFormat) -> anyhow::Result<AudioReader> {
+        match self {
+            Self::Reader(reader) => Ok(reader),
+            Self::Raw(raw) => AudioReader::open(raw, format),
+        }
+    }
+}
+
+struct QueuedAudio {
+    source: QueuedSource,
+    /// Following chunks of a long message
+    rest: Option<ChunkReceiver>,
+    helper: MessageHelper,
+    priority: Priority,
+}
+
+#[derive(Default)]
+struct PlaybackQueue {
+    queues: [VecDeque<QueuedAudio>; 3],
+}
+
+impl PlaybackQueue {
+    fn push_back(&mut self, item: QueuedAudio) {
+        self.queues[item.priority as usize].push_back(item);
+    }
+
+    fn push_front(&mut self, item: QueuedAudio) {
+        self.queues[item.priority as usize].push_front(item);
+    }
+
+    fn pop(&mut self) -> Option<QueuedAudio> {
+        self.queues.iter_mut().rev().find_map(VecDeque::pop_front)
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+}
+
+/// Reader of the next chunk, waits until it is synthesized
+async fn next_chunk(
+    rest: &mut Option<ChunkReceiver>,
+    format: &OutputFormat,
+) -> Option<AudioReader> {
+    let receiver = rest.as_mut()?;
+    while let Some(source) = receiver.recv().await {
+        match AudioReader::open(source, format) {
+            Ok(reader) => return Some(reader),
+            Err(e) => log::error!("Read chunk error: {e:?}"),
+        }
+    }
+    None
+}
+
+fn rewind(reader: AudioReader) -> anyhow::Result<Box<dyn MediaSource>> {
+    let mut source = reader.into_source();
+    std::io::Seek::seek(&mut source, std::io::SeekFrom::Start(0))?;
+    Ok(Box::new(source))
+}
+
 pub(crate) async fn send_audio(
     mut receiver: mpsc::Receiver<TTSFinalEvent>,
-    sender: mpsc::Sender<TeamSpeakEvent>,
+    mut sink: impl AudioSink,
+    interrupt: InterruptMode,
+    format: OutputFormat,
+    metrics: Metrics,
 ) -> anyhow::Result<()> {
-    while let Some(event) = receiver.recv().await {
-        match event {
-            TTSFinalEvent::NewData(raw, helper) => {
-                let source = MediaSourceStream::new(raw, Default::default());
+    let mut queue = PlaybackQueue::default();
+    let mut exit = false;
+    while !exit {
+        let item = match queue.pop() {
+            Some(item) => item,
+            None => match receiver.recv().await.and_then(TTSFinalEvent::into_queued) {
+                Some(item) => item,
+                None => break,
+            },
+        };
+        metrics.queue_depth(queue.len());
 
-                let mut reader = match symphonia::default::formats::OggReader::try_new(
-                    source,
-                    &Default::default(),
-                ) {
-                    Ok(r) => r,
-                    Err(e) => {
-                        helper.message(format!("Read stream error: {e:?}")).await;
+        let QueuedAudio {
+            source,
+            mut rest,
+            helper,
+            priority,
+        } = item;
+        if helper.is_cancelled() {
+            helper.progress(Progress::Cancelled).await;
+            continue;
+        }
+        let mut reader = match source.into_reader(&format) {
+            Ok(reader) => reader,
+            Err(e) => {
+                helper
+                    .progress(Progress::Error(format!("Read stream error: {e:?}")))
+                    .await;
 
-                        log::error!("Read stream error: {e:?}");
-                        continue;
-                    }
-                };
+                log::error!("Read stream error: {e:?}");
+                continue;
+            }
+        };
 
-                helper.message("Sending audio".to_string()).await;
-
-                sender.send(TeamSpeakEvent::Muted(false)).await.ok();
-                #[cfg(feature = "measure-time")]
-                let mut start = tokio::time::Instant::now();
-                while let Ok(packet) = reader.next_packet() {
-                    #[cfg(feature = "spin-sleep")]
-                    tokio::task::spawn_blocking(|| spin_sleep::sleep(Duration::from_millis(20)))
-                        .await?;
-                    #[cfg(not(feature = "spin-sleep"))]
-                    tokio::time::sleep(Duration::from_millis(20)).await;
-
-                    sender
-                        .send(TeamSpeakEvent::Data(OutAudio::new(&AudioData::C2S {
-                            id: 0,
-                            codec: tsproto_packets::packets::CodecType::OpusVoice,
-                            data: &packet.data,
-                        })))
-                        .await
-                        .inspect_err(|_| log::error!("Send error"))
-                        .ok();
-
-                    #[cfg(feature = "measure-time")]
-                    log::debug!(
-                        "{:?} elapsed to build audio slice",
-                        tokio::time::Instant::now() - start
-                    );
-
-                    #[cfg(feature = "measure-time")]
-                    {
-                        start = tokio::time::Instant::now();
+        helper.progress(Progress::Playing).await;
+
+        sink.muted(false).await;
+        let mut interrupted = false;
+        #[cfg(feature = "measure-time")]
+        let mut start = tokio::time::Instant::now();
+        loop {
+            while let Ok(event) = receiver.try_recv() {
+                let Some(item) = event.into_queued() else {
+                    exit = true;
+                    continue;
+                };
+                if item.priority == Priority::Urgent && priority != Priority::Urgent {
+                    interrupted = true;
+                }
+                queue.push_back(item);
+                metrics.queue_depth(queue.len());
+            }
+            // Check before reading, so no packet is lost when resuming
+            if interrupted || exit || helper.is_cancelled() {
+                break;
+            }
+            let Some(packet) = reader.next_packet() else {
+                // Next chunk of a long message follows without unmuting again
+                match next_chunk(&mut rest, &format).await {
+                    Some(next) => {
+                        reader = next;
+                        continue;
                     }
+                    None => break,
                 }
-                sender.send(TeamSpeakEvent::Muted(true)).await.ok();
-                helper.message("Send audio successful".to_string()).await;
+            };
+
+            #[cfg(feature = "spin-sleep")]
+            tokio::task::spawn_blocking(|| spin_sleep::sleep(Duration::from_millis(20))).await?;
+            #[cfg(not(feature = "spin-sleep"))]
+            tokio::time::sleep(Duration::from_millis(20)).await;
+
+            sink.audio(&packet).await;
+            metrics.played(Duration::from_millis(20));
+
+            #[cfg(feature = "measure-time")]
+            log::debug!(
+                "{:?} elapsed to build audio slice",
+                tokio::time::Instant::now() - start
+            );
+
+            #[cfg(feature = "measure-time")]
+            {
+                start = tokio::time::Instant::now();
+            }
+        }
+        sink.muted(true).await;
+
+        // Cut off by shutdown, the rest is never played
+        if helper.is_cancelled() || exit {
+            helper.progress(Progress::Cancelled).await;
+            continue;
+        }
+        if !interrupted {
+            helper.progress(Progress::Done).await;
+            continue;
+        }
+
+        log::debug!("Playback interrupted by urgent message");
+        let source = match interrupt {
+            InterruptMode::Resume => Some(QueuedSource::Reader(reader)),
+            InterruptMode::Restart => rewind(reader)
+                .inspect_err(|e| log::error!("Unable rewind interrupted audio: {e:?}"))
+                .ok()
+                .map(QueuedSource::Raw),
+            InterruptMode::Drop => None,
+        };
+        match source {
+            Some(source) => {
+                helper
+                    .progress(Progress::Interrupted { requeued: true })
+                    .await;
+                queue.push_front(QueuedAudio {
+                    source,
+                    rest,
+                    helper,
+                    priority,
+                });
+                metrics.queue_depth(queue.len());
+            }
+            None => {
+                helper
+                    .progress(Progress::Interrupted { requeued: false })
+                    .await;
             }
-            TTSFinalEvent::Exit => break,
         }
     }
     Ok(())
 }
 
+// Cooldown for throttled key when Azure does not send Retry-After
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(10);
+// First backoff on 5xx, doubled on each retry
+const BASE_BACKOFF: Duration = Duration::from_millis(200);
+// Give up instead of keeping the client waiting longer than this per retry
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(10);
+
+/// Why a TTS request failed, message is sent to web client as is
+#[derive(Debug)]
+pub enum RequestError {
+    /// Every key has been removed after 401
+    NoKey,
+    /// Every key is throttled, retry after given duration
+    Throttled(Duration),
+    /// Azure returns non-success status after all retries
+    Upstream(StatusCode),
+    /// Monthly hard limit is reached
+    BudgetExhausted,
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoKey => write!(
+                f,
+                "No valid API keys available. All keys may be invalid or exhausted"
+            ),
+            Self::Throttled(wait) => write!(
+                f,
+                "Azure TTS is throttled, retry after {}s",
+                wait.as_secs_f32().ceil()
+            ),
+            Self::Upstream(status) => write!(f, "Azure TTS request failed: {status}"),
+            Self::BudgetExhausted => write!(
+                f,
+                "Monthly Azure character budget is used up, only cached messages can be played"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
 pub struct Requester {
     inner: reqwest::Client,
     tts: TTS,
+    notify: Option<broadcast::Sender<BroadcastEvent>>,
+    usage: Option<Arc<UsageTracker>>,
+    metrics: Metrics,
 }
 
 impl Requester {
@@ -318,7 +717,7 @@ impl Requester {
         header.insert(CONTENT_TYPE, "application/ssml+xml".parse().unwrap());
         header.insert(
             "X-Microsoft-OutputFormat",
-            "ogg-48khz-16bit-mono-opus".parse().unwrap(),
+            tts.format().name().parse().unwrap(),
         );
         header.insert(
             USER_AGENT,
@@ -328,14 +727,112 @@ impl Requester {
         );
         Self {
             inner: reqwest::ClientBuilder::new()
-                .timeout(Duration::from_secs(5))
+                .timeout(tts.timeout())
                 .default_headers(header)
                 .build()
                 .unwrap(),
             tts,
+            notify: None,
+            usage: None,
+            metrics: Default::default(),
         }
     }
 
+    pub fn metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Push key warnings to web clients
+    pub fn notify(mut self, sender: broadcast::Sender<BroadcastEvent>) -> Self {
+        self.notify = Some(sender);
+        self
+    }
+
+    /// Count characters against monthly budget, stop requesting once it is exhausted
+    pub fn usage(mut self, tracker: Arc<UsageTracker>) -> Self {
+        self.usage = Some(tracker);
+        self
+    }
+
+    pub fn usage_tracker(&self) -> Option<&UsageTracker> {
+        self.usage.as_deref()
+    }
+
+    pub fn output_format(&self) -> &OutputFormat {
+        self.tts.format()
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.tts.chunk_size()
+    }
+
+    pub fn keys(&self) -> &KeyStore {
+        self.tts.keys()
+    }
+
+    fn endpoint<'a>(&'a self, key: &'a KeyEndpoint) -> &'a str {
+        key.endpoint.as_deref().unwrap_or(self.tts.endpoint())
+    }
+
+    fn voices_url(endpoint: &str) -> String {
+        endpoint.replace("/cognitiveservices/v1", "/cognitiveservices/voices/list")
+    }
+
+    async fn disable_key(&self, key: &str, reason: String) {
+        log::warn!("Disable key {}: {reason}", mask(key));
+        // Another concurrent request might have disabled it already
+        let Some(remaining) = self.tts.keys().disable(key, reason).await else {
+            return;
+        };
+        let warning = match remaining {
+            0 => "All API keys are disabled, TTS is unavailable".to_string(),
+            1 => "Only 1 API key left, TTS will stop working once it fails".to_string(),
+            _ => return,
+        };
+        log::warn!("{warning}");
+        if let Some(ref notify) = self.notify {
+            notify.send(BroadcastEvent::Warning(warning)).ok();
+        }
+    }
+
+    /// Try disabled keys against voice list endpoint, enable the ones accepted again
+    pub async fn revalidate(&self) {
+        for target in self.tts.keys().disabled().await {
+            let key = &target.key;
+            match self
+                .inner
+                .get(Self::voices_url(self.endpoint(&target)))
+                .header("Ocp-Apim-Subscription-Key", key)
+                .send()
+                .await
+            {
+                Ok(ret) if ret.status().is_success() => {
+                    log::info!("Key {} is valid again, re-enabled", mask(key));
+                    self.tts.keys().enable(key).await;
+                }
+                Ok(ret) => log::debug!("Key {} is still invalid: {}", mask(key), ret.status()),
+                Err(e) => log::warn!("Unable re-validate key: {e:?}"),
+            }
+        }
+    }
+
+    pub async fn voices(&self) -> anyhow::Result<Vec<Voice>> {
+        let target = self.tts.ocp_apim_subscription_key().await?;
+        let ret = self
+            .inner
+            .get(Self::voices_url(self.endpoint(&target)))
+            .header("Ocp-Apim-Subscription-Key", &target.key)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(serde_json::from_slice(&ret.bytes().await?)?)
+    }
+
+    pub fn voices_refresh(&self) -> Duration {
+        self.tts.voices_refresh()
+    }
+
     fn build_headers(length: usize, key: &str) -> HeaderMap {
         let mut header = HeaderMap::new();
         header.insert(CONTENT_LENGTH, length.to_string().parse().unwrap());
@@ -343,54 +840,572 @@ impl Requester {
         header
     }
 
-    fn build_ssml(lang: &str, gender: &str, name: String, text: &str) -> String {
-        format!(
-            "<speak version='1.0' xml:lang='en-US'><voice xml:lang='{lang}' xml:gender='{gender}' name='{name}'>{}</voice></speak>",
-            text.trim()
-        )
+    fn build_ssml(segments: &[Segment]) -> String {
+        let voices = segments
+            .iter()
+            .map(|Segment { voice, text }| {
+                let gender = if voice.gender().is_empty() {
+                    String::new()
+                } else {
+                    format!(" xml:gender='{}'", voice.gender())
+                };
+                format!(
+                    "<voice xml:lang='{}'{gender} name='{}'>{}</voice>",
+                    voice.locale(),
+                    voice.voice(),
+                    voice.prosody().wrap(text.trim())
+                )
+            })
+            .collect::<String>();
+        format!("<speak version='1.0' xml:lang='en-US'>{voices}</speak>")
+    }
+
+    /// Wait before retry `attempt` of a failed request
+    fn backoff(attempt: u32) -> Duration {
+        BASE_BACKOFF
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_RETRY_WAIT)
     }
 
-    pub async fn request(
-        &self,
-        lang: &str,
-        gender: &str,
-        name: String,
-        text: &str,
-    ) -> anyhow::Result<Response> {
-        let ssml = Self::build_ssml(lang, gender, name, text);
+    /// Retry-After as seconds or HTTP-date, a date in the past means no wait
+    fn retry_after(response: &Response) -> Option<Duration> {
+        let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+        if let Ok(seconds) = value.parse() {
+            return Some(Duration::from_secs(seconds));
+        }
+        let date = httpdate::parse_http_date(value).ok()?;
+        Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+    }
+
+    /// Synthesize segments into one audio, each read by its own voice
+    pub async fn request(&self, segments: &[Segment]) -> anyhow::Result<Response> {
+        let characters = segments
+            .iter()
+            .map(|segment| segment.text.trim().chars().count())
+            .sum();
+        let _reservation = match self.usage {
+            Some(ref usage) => Some(
+                usage
+                    .reserve(characters as u64)
+                    .await
+                    .ok_or(RequestError::BudgetExhausted)?,
+            ),
+            None => None,
+        };
+        let ssml = Self::build_ssml(segments);
         log::trace!("Request SSML: {ssml:?}");
+        let mut attempt = 0;
         let ret = loop {
-            let selected = match self.tts.ocp_apim_subscription_key().await {
-                Ok(key) => key,
-                Err(e) => {
-                    // KeyStore is empty - all keys have been exhausted or removed
-                    return Err(anyhow::anyhow!(
-                        "No valid API keys available. All keys may be invalid or exhausted: {}",
-                        e
-                    ));
-                }
+            let target = match self.tts.ocp_apim_subscription_key().await {
+                Ok(target) => target,
+                Err(e) => match self.tts.keys().cooldown_remaining().await {
+                    // Every key is throttled, wait for the first one to come back
+                    Some(wait) if attempt < self.tts.retries() && wait <= MAX_RETRY_WAIT => {
+                        attempt += 1;
+                        log::debug!("All keys are cooling down, wait {wait:?}");
+                        tokio::time::sleep(wait).await;
+                        continue;
+                    }
+                    Some(wait) => return Err(RequestError::Throttled(wait).into()),
+                    None => {
+                        // KeyStore is empty - all keys have been exhausted or removed
+                        log::debug!("Unable select key: {e:?}");
+                        return Err(RequestError::NoKey.into());
+                    }
+                },
             };
-            let ret = self
+            let selected = &target.key;
+            let start = tokio::time::Instant::now();
+            let ret = match self
                 .inner
-                .post(self.tts.endpoint())
+                .post(self.endpoint(&target))
                 .body(ssml.as_bytes().to_vec())
-                .headers(Self::build_headers(ssml.len(), &selected))
+                .headers(Self::build_headers(ssml.len(), selected))
                 .send()
-                .await?;
+                .await
+            {
+                Ok(ret) => {
+                    self.metrics.azure(ret.status().as_str(), start.elapsed());
+                    ret
+                }
+                Err(e) => {
+                    self.metrics.azure("error", start.elapsed());
+                    self.tts
+                        .keys()
+                        .record_failure(selected, e.to_string())
+                        .await;
+                    // Azure is not reached, retried like server errors
+                    if (e.is_connect() || e.is_timeout()) && attempt < self.tts.retries() {
+                        let wait = Self::backoff(attempt);
+                        attempt += 1;
+                        log::warn!("Unable reach Azure: {e}, retry after {wait:?}");
+                        tokio::time::sleep(wait).await;
+                        continue;
+                    }
+                    return Err(e.into());
+                }
+            };
             log::trace!("Api response: {}", ret.status());
-            if ret.status().eq(&reqwest::StatusCode::UNAUTHORIZED) {
-                log::debug!("Remove key {selected}");
-                // Ignore errors from remove_key - the key might have already been removed
-                // by another concurrent request
-                if let Err(e) = self.tts.remove_key(&selected).await {
-                    log::debug!("Key removal failed (likely already removed): {e:?}");
+            if ret.status().eq(&StatusCode::UNAUTHORIZED) {
+                self.disable_key(selected, ret.status().to_string()).await;
+                continue;
+            }
+            if ret.status().eq(&StatusCode::TOO_MANY_REQUESTS) {
+                // Quota is temporary, rest this key and let another one take over
+                let wait = Self::retry_after(&ret).unwrap_or(DEFAULT_COOLDOWN);
+                log::warn!("Key {} is throttled, cooldown {wait:?}", mask(selected));
+                self.tts.keys().cooldown(selected, wait).await;
+                if attempt >= self.tts.retries() {
+                    return Err(RequestError::Throttled(wait).into());
+                }
+                attempt += 1;
+                continue;
+            }
+            if !ret.status().is_success() {
+                self.tts
+                    .keys()
+                    .record_failure(selected, ret.status().to_string())
+                    .await;
+            }
+            if ret.status().is_server_error() {
+                if attempt >= self.tts.retries() {
+                    return Err(RequestError::Upstream(ret.status()).into());
                 }
+                let wait = Self::retry_after(&ret)
+                    .map_or_else(|| Self::backoff(attempt), |wait| wait.min(MAX_RETRY_WAIT));
+                attempt += 1;
+                log::warn!("Azure returns {}, retry after {wait:?}", ret.status());
+                tokio::time::sleep(wait).await;
                 continue;
-            } else {
-                break ret;
             }
+            if !ret.status().is_success() {
+                return Err(RequestError::Upstream(ret.status()).into());
+            }
+            self.tts.keys().record_success(selected, characters).await;
+            if let Some(ref usage) = self.usage {
+                usage.record(selected, characters).await;
+            }
+            break ret;
         };
 
         Ok(ret)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use reqwest::header::RETRY_AFTER;
+    use tokio::sync::{broadcast, mpsc};
+
+    use super::{
+        Priority, RequestError, Requester, Segment, TTSFinalEvent, delay_send, send_audio,
+    };
+    use crate::{
+        audio::AudioReader,
+        cache::LevelDB,
+        config::InterruptMode,
+        presets::Preset,
+        protocol::Progress,
+        test_support::{FakeTeamSpeak, MockAzure, SinkEvent, canned_audio},
+        types::BroadcastEvent,
+        web::{WebsocketEvent, WebsocketHelper},
+    };
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(Requester::backoff(0), Duration::from_millis(200));
+        assert_eq!(Requester::backoff(2), Duration::from_millis(800));
+        assert_eq!(Requester::backoff(40), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_ssml() {
+        assert_eq!(
+            Requester::build_ssml(&[Segment::new(
+                Preset::new("en-US-AvaNeural".to_string(), "Female".to_string()),
+                " Hello ".to_string()
+            )]),
+            "<speak version='1.0' xml:lang='en-US'><voice xml:lang='en-US' xml:gender='Female' name='en-US-AvaNeural'>Hello</voice></speak>"
+        );
+        let narrator: Preset =
+            serde_json::from_str(r#"{"voice": "zh-CN-YunxiNeural", "rate": "-10%"}"#).unwrap();
+        assert_eq!(
+            Requester::build_ssml(&[
+                Segment::new(narrator, "你好".to_string()),
+                Segment::new(
+                    Preset::new("en-US-AvaNeural".to_string(), String::new()),
+                    "Hello".to_string()
+                ),
+            ]),
+            "<speak version='1.0' xml:lang='en-US'><voice xml:lang='zh-CN' name='zh-CN-YunxiNeural'><prosody rate='-10%'>你好</prosody></voice><voice xml:lang='en-US' name='en-US-AvaNeural'>Hello</voice></speak>"
+        );
+    }
+
+    async fn request(requester: &Requester) -> anyhow::Result<reqwest::Response> {
+        requester
+            .request(&[Segment::new(
+                Preset::new("en-US-AvaNeural".to_string(), "Female".to_string()),
+                "Hello".to_string(),
+            )])
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_request() {
+        let mock = MockAzure::start(&["key"]).await;
+        let requester = Requester::new(mock.tts(&["key"]));
+        let response = request(&requester).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.bytes().await.unwrap(), mock.audio());
+    }
+
+    #[tokio::test]
+    async fn test_key_failover() {
+        let mock = MockAzure::start(&["good"]).await;
+        let tts: crate::config::TTS = toml::from_str(&format!(
+            "endpoint = {:?}\nstrategy = \"fallback\"\nOcp-Apim-Subscription-Key = [\"bad\", \"good\"]",
+            mock.endpoint()
+        ))
+        .unwrap();
+        let requester = Requester::new(tts.clone());
+        // Invalid key is tried first
+        for _ in 0..3 {
+            let response = request(&requester).await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+        }
+        // Invalid key is disabled after first 401
+        assert_eq!(mock.requests(), ["bad", "good", "good", "good"]);
+        assert_eq!(tts.ocp_apim_subscription_key().await.unwrap().key, "good");
+    }
+
+    #[tokio::test]
+    async fn test_key_endpoint() {
+        let default = MockAzure::start(&["a"]).await;
+        let other = MockAzure::start(&["b"]).await;
+        let tts: crate::config::TTS = toml::from_str(&format!(
+            r#"endpoint = "{}"
+strategy = "round-robin"
+Ocp-Apim-Subscription-Key = ["a", {{ key = "b", endpoint = "{}" }}]"#,
+            default.endpoint(),
+            other.endpoint()
+        ))
+        .unwrap();
+        let requester = Requester::new(tts);
+        for _ in 0..4 {
+            let response = request(&requester).await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+        }
+        assert_eq!(default.requests(), ["a", "a"]);
+        assert_eq!(other.requests(), ["b", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_key_health() {
+        let mock = MockAzure::start(&["good-key-0001"]).await;
+        let tts = mock.tts(&["good-key-0001", "bad-key-00002"]);
+        let (notify, mut notify_receiver) = broadcast::channel(4);
+        let requester = Requester::new(tts.clone()).notify(notify);
+        while tts.keys().disabled().await.is_empty() {
+            request(&requester).await.unwrap();
+        }
+        assert!(matches!(
+            notify_receiver.try_recv(),
+            Ok(BroadcastEvent::Warning(_))
+        ));
+
+        let status = serde_json::to_value(tts.keys().status().await).unwrap();
+        assert_eq!(status[0]["key"], "good...0001");
+        assert_eq!(status[0]["enabled"], true);
+        assert_eq!(
+            status[0]["characters"],
+            status[0]["requests"].as_u64().unwrap() * 5
+        );
+        assert_eq!(status[1]["enabled"], false);
+        assert_eq!(status[1]["requests"], 1);
+        assert_eq!(status[1]["disabled_reason"], "401 Unauthorized");
+
+        // Still rejected, stays disabled
+        requester.revalidate().await;
+        assert_eq!(tts.keys().disabled().await[0].key, "bad-key-00002");
+        mock.allow("bad-key-00002");
+        requester.revalidate().await;
+        assert!(tts.keys().disabled().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_all_keys_invalid() {
+        let mock = MockAzure::start(&["good"]).await;
+        let tts = mock.tts(&["bad1", "bad2"]);
+        let requester = Requester::new(tts.clone());
+        let error = request(&requester).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RequestError>(),
+            Some(RequestError::NoKey)
+        ));
+        assert_eq!(mock.requests().len(), 2);
+        assert!(tts.ocp_apim_subscription_key().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited() {
+        let mock = MockAzure::start(&["key"]).await;
+        let tts = mock.tts(&["key"]);
+        let requester = Requester::new(tts.clone());
+        mock.rate_limit(1);
+        let start = tokio::time::Instant::now();
+        // The only key cools down for Retry-After, then request is retried
+        let response = request(&requester).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(mock.requests().len(), 2);
+        // Rate limit is not an invalid key
+        assert_eq!(tts.ocp_apim_subscription_key().await.unwrap().key, "key");
+
+        mock.rate_limit(10);
+        let error = request(&requester).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RequestError>(),
+            Some(RequestError::Throttled(_))
+        ));
+        // Waiting for cooldown counts as a retry as well
+        assert_eq!(mock.requests().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_throttled_key_failover() {
+        let mock = MockAzure::start(&["key1", "key2"]).await;
+        let tts = mock.tts(&["key1", "key2"]);
+        let requester = Requester::new(tts.clone());
+        mock.rate_limit(1);
+        let start = tokio::time::Instant::now();
+        let response = request(&requester).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        // Other key is used right away instead of waiting
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 2);
+        assert_ne!(requests[0], requests[1]);
+    }
+
+    #[tokio::test]
+    async fn test_server_error() {
+        let mock = MockAzure::start(&["key"]).await;
+        let requester = Requester::new(mock.tts(&["key"]));
+        mock.fail(2);
+        let response = request(&requester).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(mock.requests().len(), 3);
+
+        mock.fail(10);
+        let error = request(&requester).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RequestError>(),
+            Some(RequestError::Upstream(
+                reqwest::StatusCode::SERVICE_UNAVAILABLE
+            ))
+        ));
+        assert_eq!(mock.requests().len(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable() {
+        // Nothing listens on this port anymore
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let tts: crate::config::TTS = toml::from_str(&format!(
+            "endpoint = \"http://{addr}/cognitiveservices/v1\"\nOcp-Apim-Subscription-Key = \"key\""
+        ))
+        .unwrap();
+        let requester = Requester::new(tts.clone());
+        let start = tokio::time::Instant::now();
+        let error = request(&requester).await.unwrap_err();
+        assert!(error.downcast_ref::<reqwest::Error>().unwrap().is_connect());
+        // Retried with backoff of 200ms, 400ms and 800ms
+        assert!(start.elapsed() >= Duration::from_millis(1400));
+        let status = serde_json::to_value(tts.keys().status().await).unwrap();
+        assert_eq!(status[0]["requests"], 4);
+        assert_eq!(status[0]["enabled"], true);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let retry_after = |value: &str| {
+            Requester::retry_after(&reqwest::Response::from(
+                axum::http::Response::builder()
+                    .header(RETRY_AFTER, value)
+                    .body("")
+                    .unwrap(),
+            ))
+        };
+        assert_eq!(retry_after("5"), Some(Duration::from_secs(5)));
+        assert_eq!(retry_after(" 5 "), Some(Duration::from_secs(5)));
+        let date = retry_after(&httpdate::fmt_http_date(
+            SystemTime::now() + Duration::from_secs(30),
+        ))
+        .unwrap();
+        assert!(date > Duration::from_secs(28) && date <= Duration::from_secs(30));
+        assert_eq!(
+            retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after("soon"), None);
+        assert_eq!(retry_after("-1"), None);
+    }
+
+    #[tokio::test]
+    async fn test_slow_stream_cache() {
+        let mock = MockAzure::start(&["key"]).await;
+        mock.slow_stream(Duration::from_millis(200));
+        let requester = Requester::new(mock.tts(&["key"]));
+        let (agent, db) = LevelDB::new_in_memory();
+        let agent = Arc::new(agent);
+        let (sender, mut receiver) = mpsc::channel(4);
+        let sender = Arc::new(sender);
+
+        let task = tokio::spawn(delay_send(
+            (1, Some(100)),
+            request(&requester).await.unwrap(),
+            sender.clone(),
+            agent.clone(),
+            Default::default(),
+            Default::default(),
+            Priority::Normal,
+        ));
+        // Playback starts before download finished
+        let event = tokio::time::timeout(Duration::from_secs(1), receiver.recv())
+            .await
+            .unwrap();
+        assert!(!task.is_finished());
+        task.await.unwrap().unwrap();
+        assert_eq!(agent.get(1).await.unwrap(), mock.audio());
+
+        let Some(TTSFinalEvent::NewData(source, _, _)) = event else {
+            panic!("Unexpected event");
+        };
+        let mut reader = AudioReader::open(source, &Default::default()).unwrap();
+        let mut packets = 0;
+        while reader.next_packet().is_some() {
+            packets += 1;
+        }
+        assert_eq!(packets, 50);
+
+        // Medium length text is not cached
+        delay_send(
+            (2, Some(50)),
+            request(&requester).await.unwrap(),
+            sender,
+            agent.clone(),
+            Default::default(),
+            Default::default(),
+            Priority::Normal,
+        )
+        .await
+        .unwrap();
+        assert!(receiver.recv().await.is_some());
+        assert_eq!(agent.get(2).await, None);
+
+        db.disconnect().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_urgent_interrupt() {
+        let teamspeak = FakeTeamSpeak::default();
+        let (sender, receiver) = mpsc::channel(4);
+        let player = tokio::spawn(send_audio(
+            receiver,
+            teamspeak.clone(),
+            InterruptMode::Resume,
+            Default::default(),
+            Default::default(),
+        ));
+        let data = |packets| Box::new(std::io::Cursor::new(canned_audio(packets)));
+
+        sender
+            .send(TTSFinalEvent::NewData(
+                data(50),
+                Default::default(),
+                Priority::Normal,
+            ))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        sender
+            .send(TTSFinalEvent::NewData(
+                data(5),
+                Default::default(),
+                Priority::Urgent,
+            ))
+            .await
+            .unwrap();
+        teamspeak.wait_playback(3).await;
+        sender.send(TTSFinalEvent::Exit).await.unwrap();
+        player.await.unwrap().unwrap();
+
+        // Split into playbacks by mute state
+        let mut playbacks = Vec::new();
+        for (_, event) in teamspeak.events() {
+            match event {
+                SinkEvent::Muted(false) => playbacks.push(0),
+                SinkEvent::Audio(_) => *playbacks.last_mut().unwrap() += 1,
+                SinkEvent::Muted(true) => {}
+            }
+        }
+        assert_eq!(playbacks.len(), 3);
+        assert!(playbacks[0] > 0 && playbacks[0] < 50);
+        assert_eq!(playbacks[1], 5);
+        // Interrupted playback resumes where it stopped
+        assert_eq!(playbacks[0] + playbacks[2], 50);
+    }
+
+    #[tokio::test]
+    async fn test_exit_during_playback() {
+        let teamspeak = FakeTeamSpeak::default();
+        let (sender, receiver) = mpsc::channel(4);
+        let player = tokio::spawn(send_audio(
+            receiver,
+            teamspeak.clone(),
+            InterruptMode::Resume,
+            Default::default(),
+            Default::default(),
+        ));
+        let (helper, mut events) = WebsocketHelper::new(16);
+
+        sender
+            .send(TTSFinalEvent::NewData(
+                Box::new(std::io::Cursor::new(canned_audio(50))),
+                Some(helper).into(),
+                Priority::Normal,
+            ))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        sender.send(TTSFinalEvent::Exit).await.unwrap();
+        player.await.unwrap().unwrap();
+
+        let played = teamspeak
+            .events()
+            .into_iter()
+            .filter(|(_, event)| matches!(event, SinkEvent::Audio(_)))
+            .count();
+        assert!(played > 0 && played < 50, "{played}");
+        let mut progress = Vec::new();
+        while let Ok(WebsocketEvent::Progress(_, event)) = events.try_recv() {
+            progress.push(event);
+        }
+        // Audio is cut off, so it is not reported as done
+        assert!(
+            matches!(
+                progress.as_slice(),
+                [Progress::Playing, Progress::Cancelled]
+            ),
+            "{progress:?}"
+        );
+    }
+}
@@ -1,3 +1,31 @@
+use crate::history::HistoryEntry;
+
+pub(crate) fn current_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Current UTC month as `YYYYMM`, Azure quota resets monthly
+pub(crate) fn current_month() -> u32 {
+    month_of(current_timestamp())
+}
+
+pub(crate) fn month_of(timestamp: u64) -> u32 {
+    // Howard Hinnant's civil_from_days
+    let days = (timestamp / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year * 100 + month) as u32
+}
+
 #[derive(Clone, PartialEq)]
 pub(crate) enum MainEvent {
     Exit,
@@ -9,11 +37,22 @@ impl MainEvent {
     }
 }
 
+/// Events pushed to every connected web client
+#[derive(Clone, Debug)]
+pub(crate) enum BroadcastEvent {
+    /// Speaker name, transcript
+    Transcript(String, String),
+    Warning(String),
+    /// Message spoken, shown in history of every client
+    History(HistoryEntry),
+}
+
 #[derive(Clone)]
 pub(crate) struct AdditionalArguments {
     pub(crate) server: Option<String>,
     pub(crate) web: Option<String>,
     pub(crate) leveldb: Option<String>,
+    pub(crate) dry_run: bool,
 }
 
 impl AdditionalArguments {
@@ -22,6 +61,21 @@ impl AdditionalArguments {
             server: matches.get_one("server").cloned(),
             web: matches.get_one("web").cloned(),
             leveldb: matches.get_one("leveldb").cloned(),
+            dry_run: matches.get_flag("dry-run"),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::month_of;
+
+    #[test]
+    fn test_month() {
+        assert_eq!(month_of(0), 197001);
+        // 2024-02-29T23:59:59Z
+        assert_eq!(month_of(1709251199), 202402);
+        // 2026-12-31T12:00:00Z
+        assert_eq!(month_of(1798718400), 202612);
+    }
+}
@@ -0,0 +1,115 @@
+//! Unix domain socket for the web server, e.g. behind a reverse proxy on the same host
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use axum::{
+    extract::connect_info::Connected,
+    serve::{IncomingStream, Listener},
+};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::{config::Web, web::PeerAddr};
+
+/// Peers have no IP address, they show up as localhost in logs and the audit log
+const PEER: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Socket file is removed once the listener is dropped
+pub struct UnixSocketListener {
+    inner: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocketListener {
+    pub fn bind<P: AsRef<Path>>(path: P, config: &Web) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        // Left behind by a run that did not shut down cleanly
+        if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(anyhow!("Socket {} is in use", path.display()));
+            }
+            std::fs::remove_file(path)?;
+        }
+        let inner = UnixListener::bind(path)?;
+        let listener = Self {
+            inner,
+            path: path.to_path_buf(),
+        };
+        if let Some(mode) = config.socket_mode() {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+        if config.socket_owner().is_some() || config.socket_group().is_some() {
+            std::os::unix::fs::chown(path, config.socket_owner(), config.socket_group())?;
+        }
+        Ok(listener)
+    }
+}
+
+impl Listener for UnixSocketListener {
+    type Io = UnixStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let (stream, _) = Listener::accept(&mut self.inner).await;
+        (stream, PEER)
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(PEER)
+    }
+}
+
+impl Connected<IncomingStream<'_, UnixSocketListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, UnixSocketListener>) -> Self {
+        Self(*stream.remote_addr())
+    }
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+
+    use axum::serve::Listener;
+    use tokio::net::UnixStream;
+
+    use super::UnixSocketListener;
+    use crate::config::Web;
+
+    #[tokio::test]
+    async fn test_unix_socket() {
+        let path = std::env::temp_dir().join(format!("tts-{}.sock", std::process::id()));
+        let config: Web = toml::from_str(&format!(
+            "listen = \"unix:{}\"\nsocket_mode = 0o600",
+            path.display()
+        ))
+        .unwrap();
+        assert_eq!(config.bind(), format!("unix:{}", path.display()));
+
+        // Stale socket file of a previous run
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let mut listener = UnixSocketListener::bind(&path, &config).unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert!(UnixSocketListener::bind(&path, &config).is_err());
+
+        let client = tokio::spawn(UnixStream::connect(path.clone()));
+        let (_stream, peer) = listener.accept().await;
+        assert!(peer.ip().is_loopback());
+        client.await.unwrap().unwrap();
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,395 @@
+//! Azure character accounting against the monthly budget, counters are kept in LevelDB
+
+use std::{
+    process::Stdio,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::{io::AsyncWriteExt, sync::broadcast};
+use xxhash_rust::xxh3;
+
+use crate::{
+    cache::ConnAgent,
+    config::Usage,
+    keys::{KeyStore, mask},
+    types::{BroadcastEvent, current_month},
+};
+
+/// LevelDB record of a monthly counter, either for one API key or the total
+fn counter_key(month: u32, key: Option<&str>) -> String {
+    match key {
+        Some(key) => format!("usage:{month}:{:016x}", xxh3::xxh3_64(key.as_bytes())),
+        None => format!("usage:{month}"),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BudgetState {
+    Normal,
+    /// Soft limit reached, web clients are warned
+    Warning,
+    /// Hard limit reached, Azure is not requested until next month
+    Exhausted,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageSummary {
+    /// `YYYYMM`
+    month: u32,
+    characters: u64,
+    soft_limit: Option<u64>,
+    hard_limit: Option<u64>,
+    state: BudgetState,
+    /// Exhausted budget is served by fallback command instead of cache only
+    fallback: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyUsage {
+    id: usize,
+    key: String,
+    characters: u64,
+}
+
+/// Local TTS program used once the budget is exhausted, text is written to its stdin
+/// and audio in `[tts] format` is read from its stdout
+pub struct FallbackCommand {
+    command: Vec<String>,
+    timeout: Duration,
+}
+
+impl FallbackCommand {
+    pub fn new(command: Vec<String>, timeout: Duration) -> Self {
+        Self { command, timeout }
+    }
+
+    pub async fn synthesize(&self, text: &str) -> anyhow::Result<bytes::Bytes> {
+        let (program, args) = self
+            .command
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("Fallback command is empty"))?;
+        let mut child = tokio::process::Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let output = tokio::time::timeout(self.timeout, async {
+            if let Some(mut stdin) = child.stdin.take() {
+                // Programs taking no input may exit before the text is written
+                if let Err(e) = stdin.write_all(text.trim().as_bytes()).await
+                    && e.kind() != std::io::ErrorKind::BrokenPipe
+                {
+                    return Err(e);
+                }
+            }
+            child.wait_with_output().await
+        })
+        .await
+        .map_err(|_| anyhow::anyhow!("Fallback command timeout"))??;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "Fallback command exit with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        if output.stdout.is_empty() {
+            return Err(anyhow::anyhow!("Fallback command returns no audio"));
+        }
+        Ok(output.stdout.into())
+    }
+}
+
+pub struct UsageTracker {
+    leveldb: ConnAgent,
+    soft_limit: Option<u64>,
+    hard_limit: Option<u64>,
+    fallback: Option<FallbackCommand>,
+    notify: Option<broadcast::Sender<BroadcastEvent>>,
+    // Counters are read-modify-write, concurrent requests must not lose updates
+    lock: tokio::sync::Mutex<()>,
+    // Characters of requests sent to Azure but not recorded yet
+    reserved: AtomicU64,
+    // Highest state already announced to web clients, per month
+    announced: Mutex<(u32, BudgetState)>,
+}
+
+impl UsageTracker {
+    pub fn new(leveldb: ConnAgent, config: Option<&Usage>) -> Self {
+        Self {
+            leveldb,
+            soft_limit: config.and_then(Usage::soft_limit),
+            hard_limit: config.and_then(Usage::hard_limit),
+            fallback: config.and_then(|config| {
+                config
+                    .fallback()
+                    .map(|command| FallbackCommand::new(command.to_vec(), config.timeout()))
+            }),
+            notify: None,
+            lock: Default::default(),
+            reserved: Default::default(),
+            announced: Mutex::new((current_month(), BudgetState::Normal)),
+        }
+    }
+
+    /// Push budget warnings to web clients
+    pub fn notify(mut self, sender: broadcast::Sender<BroadcastEvent>) -> Self {
+        self.notify = Some(sender);
+        self
+    }
+
+    pub fn fallback(&self) -> Option<&FallbackCommand> {
+        self.fallback.as_ref()
+    }
+
+    async fn read(&self, counter: &str) -> u64 {
+        self.leveldb
+            .get_record(counter)
+            .await
+            .and_then(|value| value.as_ref().try_into().ok())
+            .map(u64::from_le_bytes)
+            .unwrap_or_default()
+    }
+
+    async fn add(&self, counter: &str, characters: u64) -> u64 {
+        let value = self.read(counter).await + characters;
+        self.leveldb
+            .set_record(counter, value.to_le_bytes().to_vec())
+            .await
+            .inspect_err(|e| log::error!("Unable write usage: {e:?}"))
+            .ok();
+        value
+    }
+
+    /// Restore this month's per-key usage after restart, so weighted selection
+    /// and budget warnings continue from where they were
+    pub async fn load(&self, keys: &KeyStore) {
+        let month = current_month();
+        for key in keys.list().await {
+            let used = self.read(&counter_key(month, Some(&key))).await;
+            keys.set_used(&key, month, used).await;
+        }
+        let state = self.state().await;
+        *self.announced.lock().unwrap() = (month, state);
+        if state == BudgetState::Exhausted {
+            log::warn!("Monthly Azure character budget is already used up");
+        }
+    }
+
+    pub async fn record(&self, key: &str, characters: usize) {
+        let month = current_month();
+        let total = {
+            let _guard = self.lock.lock().await;
+            self.add(&counter_key(month, Some(key)), characters as u64)
+                .await;
+            self.add(&counter_key(month, None), characters as u64).await
+        };
+        self.announce(month, total);
+    }
+
+    /// Hold characters of a request before it is sent, so concurrent requests cannot pass
+    /// the hard limit together. `None` once the budget is exhausted, characters are given
+    /// back when the reservation is dropped, `record` counts what was actually used
+    pub async fn reserve(&self, characters: u64) -> Option<Reservation<'_>> {
+        let _guard = self.lock.lock().await;
+        let used = self.read(&counter_key(current_month(), None)).await
+            + self.reserved.load(Ordering::Acquire);
+        if self.state_of(used) == BudgetState::Exhausted {
+            return None;
+        }
+        self.reserved.fetch_add(characters, Ordering::AcqRel);
+        Some(Reservation {
+            reserved: &self.reserved,
+            characters,
+        })
+    }
+
+    fn state_of(&self, characters: u64) -> BudgetState {
+        if self.hard_limit.is_some_and(|limit| characters >= limit) {
+            BudgetState::Exhausted
+        } else if self.soft_limit.is_some_and(|limit| characters >= limit) {
+            BudgetState::Warning
+        } else {
+            BudgetState::Normal
+        }
+    }
+
+    fn announce(&self, month: u32, characters: u64) {
+        let state = self.state_of(characters);
+        {
+            let mut announced = self.announced.lock().unwrap();
+            if announced.0 == month && announced.1 >= state {
+                return;
+            }
+            *announced = (month, state);
+        }
+        let warning = match state {
+            BudgetState::Normal => return,
+            BudgetState::Warning => {
+                format!("Azure usage reached soft limit: {characters} characters used this month")
+            }
+            BudgetState::Exhausted if self.fallback.is_some() => format!(
+                "Azure usage reached hard limit ({characters} characters), using fallback TTS until next month"
+            ),
+            BudgetState::Exhausted => format!(
+                "Azure usage reached hard limit ({characters} characters), only cached messages are played until next month"
+            ),
+        };
+        log::warn!("{warning}");
+        if let Some(ref notify) = self.notify {
+            notify.send(BroadcastEvent::Warning(warning)).ok();
+        }
+    }
+
+    pub async fn state(&self) -> BudgetState {
+        self.state_of(self.read(&counter_key(current_month(), None)).await)
+    }
+
+    pub async fn summary(&self, month: u32) -> UsageSummary {
+        let characters = self.read(&counter_key(month, None)).await;
+        UsageSummary {
+            month,
+            characters,
+            soft_limit: self.soft_limit,
+            hard_limit: self.hard_limit,
+            state: self.state_of(characters),
+            fallback: self.fallback.is_some(),
+        }
+    }
+
+    /// Usage of every configured key in given month, keys are masked
+    pub async fn keys(&self, keys: &KeyStore, month: u32) -> Vec<KeyUsage> {
+        let mut ret = Vec::new();
+        for (id, key) in keys.list().await.into_iter().enumerate() {
+            ret.push(KeyUsage {
+                id,
+                key: mask(&key),
+                characters: self.read(&counter_key(month, Some(&key))).await,
+            });
+        }
+        ret
+    }
+}
+
+/// Characters held by a request in flight, see [`UsageTracker::reserve`]
+pub struct Reservation<'a> {
+    reserved: &'a AtomicU64,
+    characters: u64,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.reserved.fetch_sub(self.characters, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::broadcast;
+
+    use super::{BudgetState, UsageTracker};
+    use crate::{
+        cache::LevelDB,
+        config::Usage,
+        keys::KeyStore,
+        types::{BroadcastEvent, current_month},
+    };
+
+    fn limit(config: &str) -> Usage {
+        toml::from_str(config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_usage() {
+        let (agent, db) = LevelDB::new_in_memory();
+        let keys: KeyStore = serde_json::from_str(r#"["a", "b"]"#).unwrap();
+        let (notify, mut receiver) = broadcast::channel(4);
+        let tracker = UsageTracker::new(
+            agent.clone(),
+            Some(&limit("soft_limit = 10\nhard_limit = 20")),
+        )
+        .notify(notify);
+
+        tracker.record("a", 6).await;
+        tracker.record("b", 3).await;
+        assert_eq!(tracker.state().await, BudgetState::Normal);
+        tracker.record("a", 2).await;
+        assert_eq!(tracker.state().await, BudgetState::Warning);
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(BroadcastEvent::Warning(text)) if text.contains("soft limit")
+        ));
+        // Announced only once
+        tracker.record("b", 1).await;
+        assert!(receiver.try_recv().is_err());
+        tracker.record("b", 10).await;
+        assert_eq!(tracker.state().await, BudgetState::Exhausted);
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(BroadcastEvent::Warning(text)) if text.contains("only cached")
+        ));
+
+        let month = current_month();
+        let usage = tracker.keys(&keys, month).await;
+        assert_eq!(usage[0].characters, 8);
+        assert_eq!(usage[1].characters, 14);
+        assert_eq!(tracker.summary(month).await.characters, 22);
+        assert_eq!(tracker.summary(month - 1).await.characters, 0);
+
+        // Counters survive restart
+        let restored = UsageTracker::new(agent, None);
+        restored.load(&keys).await;
+        assert_eq!(restored.summary(month).await.characters, 22);
+        assert_eq!(restored.state().await, BudgetState::Normal);
+
+        db.disconnect().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reserve() {
+        let (agent, db) = LevelDB::new_in_memory();
+        let tracker = UsageTracker::new(agent, Some(&limit("hard_limit = 20")));
+        tracker.record("a", 5).await;
+
+        // Requests in flight count against the hard limit
+        let first = tracker.reserve(10).await.unwrap();
+        let second = tracker.reserve(10).await.unwrap();
+        assert!(tracker.reserve(1).await.is_none());
+        drop(second);
+        drop(first);
+        let reservation = tracker.reserve(10).await.unwrap();
+        tracker.record("a", 10).await;
+        drop(reservation);
+        assert!(tracker.reserve(1).await.is_some());
+        tracker.record("a", 5).await;
+        assert!(tracker.reserve(1).await.is_none());
+
+        db.disconnect().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fallback_command() {
+        let (agent, db) = LevelDB::new_in_memory();
+        let tracker = UsageTracker::new(
+            agent,
+            Some(&limit(
+                r#"hard_limit = 1
+fallback = ["sh", "-c", "cat; printf audio"]"#,
+            )),
+        );
+        let fallback = tracker.fallback().unwrap();
+        assert_eq!(
+            fallback.synthesize(" Hello ").await.unwrap().as_ref(),
+            b"Helloaudio"
+        );
+        db.disconnect().await.unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+//! Voice list for the web UI, fetched from Azure `voices/list` and cached in LevelDB
+
+use std::{collections::BTreeMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{cache::ConnAgent, tts::Requester, types::current_timestamp};
+
+/// Entry of Azure `voices/list`, other fields are ignored
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Voice {
+    short_name: String,
+    gender: String,
+    locale: String,
+}
+
+/// Gender -> locale -> variants, e.g. `{"Female": {"en-US": ["AvaNeural"]}}`
+pub type VoiceTable = BTreeMap<String, BTreeMap<String, Vec<String>>>;
+
+#[derive(Deserialize, Serialize)]
+struct CachedVoices {
+    /// Unix timestamp
+    updated: u64,
+    voices: VoiceTable,
+}
+
+fn build_table(voices: Vec<Voice>) -> VoiceTable {
+    let mut table = VoiceTable::new();
+    for voice in voices {
+        // Web UI sends variant without locale, see `web::Data::variant`
+        let variant = voice
+            .short_name
+            .strip_prefix(&format!("{}-", voice.locale))
+            .map(str::to_string)
+            .unwrap_or(voice.short_name);
+        table
+            .entry(voice.gender)
+            .or_default()
+            .entry(voice.locale)
+            .or_default()
+            .push(variant);
+    }
+    table
+}
+
+pub struct VoiceList {
+    leveldb: ConnAgent,
+    refresh: Duration,
+    // Concurrent page loads should not all hit Azure
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl VoiceList {
+    pub fn new(leveldb: ConnAgent, refresh: Duration) -> Self {
+        Self {
+            leveldb,
+            refresh,
+            lock: Default::default(),
+        }
+    }
+
+    const RECORD: &str = "voices";
+
+    async fn cached(&self) -> Option<CachedVoices> {
+        let raw = self.leveldb.get_record(Self::RECORD).await?;
+        serde_json::from_slice(&raw)
+            .inspect_err(|e| log::warn!("Unable decode cached voice list: {e:?}"))
+            .ok()
+    }
+
+    /// Cached list if younger than refresh interval, otherwise fetch again.
+    /// Stale list is still returned if Azure is unreachable
+    pub async fn get(&self, requester: &Requester) -> anyhow::Result<VoiceTable> {
+        let _guard = self.lock.lock().await;
+        let cached = self.cached().await;
+        if let Some(ref cached) = cached
+            && current_timestamp().saturating_sub(cached.updated) < self.refresh.as_secs()
+        {
+            return Ok(cached.voices.clone());
+        }
+        let voices = match requester.voices().await {
+            Ok(voices) => build_table(voices),
+            Err(e) => {
+                return match cached {
+                    Some(cached) => {
+                        log::warn!("Unable refresh voice list, use cached one: {e:?}");
+                        Ok(cached.voices)
+                    }
+                    None => Err(e),
+                };
+            }
+        };
+        log::debug!("Voice list refreshed, {} genders", voices.len());
+        self.leveldb
+            .set_record(
+                Self::RECORD,
+                serde_json::to_vec(&CachedVoices {
+                    updated: current_timestamp(),
+                    voices: voices.clone(),
+                })?,
+            )
+            .await
+            .inspect_err(|e| log::error!("Unable write voice list cache: {e:?}"))
+            .ok();
+        Ok(voices)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::VoiceList;
+    use crate::{cache::LevelDB, test_support::MockAzure, tts::Requester};
+
+    #[tokio::test]
+    async fn test_voice_list() {
+        let mock = MockAzure::start(&["key"]).await;
+        let requester = Requester::new(mock.tts(&["key"]));
+        let (agent, db) = LevelDB::new_in_memory();
+
+        let voices = VoiceList::new(agent.clone(), Duration::from_secs(3600));
+        let table = voices.get(&requester).await.unwrap();
+        assert_eq!(
+            table["Female"]["en-US"],
+            ["AvaNeural", "EmmaMultilingualNeural"]
+        );
+        assert_eq!(table["Male"]["zh-CN"], ["YunxiNeural"]);
+
+        // Served from cache while fresh, and when refresh fails
+        drop(mock);
+        assert_eq!(voices.get(&requester).await.unwrap(), table);
+        let stale = VoiceList::new(agent, Duration::ZERO);
+        assert_eq!(stale.get(&requester).await.unwrap(), table);
+
+        db.disconnect().await.unwrap();
+    }
+}
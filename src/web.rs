@@ -1,15 +1,29 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use anyhow::anyhow;
 use axum::{
     extract::{
         ws::{Message, WebSocket},
-        ConnectInfo, WebSocketUpgrade,
+        connect_info::Connected,
+        ConnectInfo, Path, Query, WebSocketUpgrade,
     },
-    response::{Html, IntoResponse},
-    routing::get,
+    http::{
+        header::{AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_TYPE},
+        HeaderMap, HeaderName, StatusCode,
+    },
+    response::{Html, IntoResponse, Response},
+    routing::{get, post, put},
+    serve::{IncomingStream, Listener},
     Extension,
 };
+use bytes::Bytes;
 use futures::{SinkExt, StreamExt};
 use kstool_helper_generator::Helper;
 use serde::Deserialize;
@@ -17,59 +31,106 @@ use tokio::sync::{broadcast, mpsc};
 use xxhash_rust::xxh3;
 
 use crate::{
+    audio::to_wav,
+    audit::{AuditEntry, AuditLog, AuditQuery, Origin},
     cache::ConnAgent,
-    config::Config,
-    tts::{Requester, TTSEvent},
+    chunk::chunks,
+    config::{AudioContainer, Config, OutputFormat},
+    history::History,
+    language::LanguageDetector,
+    metrics::Metrics,
+    preprocess::Preprocessor,
+    presets::{DefaultVoice, Preset, PresetStore},
+    protocol::{ClientMessage, Progress, ServerMessage, VERSION},
+    tts::{cache_audio, Chunk, Priority, RequestError, Requester, Segment, TTSEvent},
+    types::{current_month, BroadcastEvent},
+    usage::UsageTracker,
+    voices::VoiceList,
     MainEvent,
 };
 #[cfg(not(debug_assertions))]
 const INDEX_PAGE: &str = include_str!("html/index.html");
-const CURRENT_SUPPORT_TTS: &str = include_str!("html/mstts.js");
 
-/* #[derive(Clone,Debug,Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 #[serde(untagged)]
 pub enum TTSRequest {
-
-} */
+    /// Play a history entry again from cache
+    Replay {
+        replay: u64,
+        #[serde(default)]
+        priority: Priority,
+    },
+    Speak(Data),
+}
 
 #[derive(Helper)]
 pub enum WebsocketEvent {
-    Message(String),
+    /// Request id, always 0 on the plain protocol
+    Progress(u64, Progress),
 }
 
 #[derive(Clone, Default)]
 pub struct MessageHelper {
     inner: Option<WebsocketHelper>,
+    request: u64,
+    cancelled: Arc<AtomicBool>,
 }
 
 impl MessageHelper {
-    pub async fn message(&self, input: String) -> Option<()> {
+    fn request(mut self, id: u64, cancelled: Arc<AtomicBool>) -> Self {
+        self.request = id;
+        self.cancelled = cancelled;
+        self
+    }
+
+    pub async fn progress(&self, progress: Progress) -> Option<()> {
         if let Some(ref inner) = self.inner {
-            inner.message(input).await
+            inner.progress(self.request, progress).await
         } else {
             Some(())
         }
     }
+
+    /// Client asked to stop this request
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
 }
 
 impl From<Option<WebsocketHelper>> for MessageHelper {
     fn from(value: Option<WebsocketHelper>) -> Self {
-        Self { inner: value }
+        Self {
+            inner: value,
+            ..Default::default()
+        }
     }
 }
 
 impl From<WebsocketHelper> for MessageHelper {
     fn from(value: WebsocketHelper) -> Self {
-        Self { inner: Some(value) }
+        Some(value).into()
     }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Data {
     content: String,
+    #[serde(default)]
     code: String,
+    #[serde(default)]
     sex: String,
+    #[serde(default)]
     variant: String,
+    #[serde(default)]
+    priority: Priority,
+    /// Named preset, takes precedence over voice fields
+    preset: Option<String>,
+    /// Name of a default voice under `[users]`, used if no voice is given. Declared by
+    /// the client and not authenticated, any client may pick any name
+    user: Option<String>,
+    /// Send audio back to the client instead of playing it in the channel
+    #[serde(default)]
+    preview: bool,
 }
 
 impl Data {
@@ -81,29 +142,137 @@ impl Data {
         }
     }
 
-    fn hash(&self) -> u64 {
-        xxh3::xxh3_64(format!("{}{}", self.variant(), self.content.trim()).as_bytes())
+    /// Preset, then voice fields, then detected language, then default voice of user
+    async fn segments(
+        &self,
+        presets: &PresetStore,
+        detector: Option<&LanguageDetector>,
+    ) -> anyhow::Result<Vec<Segment>> {
+        let whole = |voice| Ok(vec![Segment::new(voice, self.content.clone())]);
+        if let Some(ref name) = self.preset {
+            return whole(
+                presets
+                    .preset(name)
+                    .await
+                    .ok_or_else(|| anyhow!("Preset {name:?} not found"))?,
+            );
+        }
+        if !self.variant.is_empty() {
+            // Voice and gender end up in SSML attributes
+            let voice = Preset::new(self.variant(), self.sex.clone());
+            voice.validate().map_err(|e| anyhow!(e))?;
+            return whole(voice);
+        }
+        let default = match self.user {
+            Some(ref user) => presets.user(user).await?,
+            None => None,
+        };
+        let detected = detector
+            .map(|detector| detector.segments(&self.content))
+            .unwrap_or_default();
+        if detected.is_empty() {
+            return match default {
+                Some(voice) => whole(voice),
+                None => Err(anyhow!("No voice, preset or user default voice given")),
+            };
+        }
+        let mut segments = Vec::new();
+        for (name, text) in detected {
+            let voice = match presets.preset(name).await {
+                Some(preset) => preset,
+                None => {
+                    let voice = Preset::new(name.to_string(), String::new());
+                    voice
+                        .validate()
+                        .map_err(|e| anyhow!("Detected voice {name:?}: {e}"))?;
+                    voice
+                }
+            };
+            // User default voice is kept if it speaks the detected language
+            let voice = match default {
+                Some(ref default) if default.language() == voice.language() => default.clone(),
+                _ => voice,
+            };
+            segments.push(Segment::new(voice, text));
+        }
+        Ok(segments)
     }
 }
 
+fn hash(segments: &[Segment], format: &OutputFormat) -> u64 {
+    // Prosody is empty by default, so hash of a single plain voice is unchanged
+    let text = segments
+        .iter()
+        .map(|segment| {
+            format!(
+                "{}{}{}",
+                segment.voice.voice(),
+                segment.voice.prosody(),
+                segment.text.trim()
+            )
+        })
+        .collect::<String>();
+    // Keep default format hash unchanged, so existing cache is still usable
+    if format.is_default() {
+        xxh3::xxh3_64(text.as_bytes())
+    } else {
+        xxh3::xxh3_64(format!("{}{text}", format.name()).as_bytes())
+    }
+}
+
+/// Voice names of segments, comma separated
+fn voices(segments: &[Segment]) -> String {
+    segments
+        .iter()
+        .map(|segment| segment.voice.voice())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Tell the page where its routes are mounted
+fn render_homepage(page: &str, base_path: &str) -> String {
+    page.replacen(
+        "const BASE_PATH = \"\";",
+        &format!("const BASE_PATH = {};", serde_json::Value::from(base_path)),
+        1,
+    )
+}
+
 #[cfg(debug_assertions)]
-pub async fn load_homepage() -> impl IntoResponse {
-    Ok::<_, String>(Html(
-        tokio::fs::read_to_string("src/html/index.html")
+async fn load_homepage(Extension(extension): Extension<Arc<WebExtension>>) -> impl IntoResponse {
+    Ok::<_, String>(Html(render_homepage(
+        &tokio::fs::read_to_string("src/html/index.html")
             .await
             .map_err(|e| e.to_string())?,
-    ))
+        &extension.base_path,
+    )))
 }
 
 #[cfg(not(debug_assertions))]
-pub async fn load_homepage() -> impl IntoResponse {
-    Html(INDEX_PAGE)
+async fn load_homepage(Extension(extension): Extension<Arc<WebExtension>>) -> impl IntoResponse {
+    Html(render_homepage(INDEX_PAGE, &extension.base_path))
 }
 
+const HIT_CACHE: &str = "Hit cache";
+const FALLBACK: &str = "Fallback";
+const REPLAY: &str = "Replay from cache";
+const PREVIEW: &str = "Preview";
+
 struct WebExtension {
     sender: mpsc::Sender<TTSEvent>,
     requester: Requester,
     leveldb_helper: ConnAgent,
+    broadcast: broadcast::Sender<BroadcastEvent>,
+    admin_token: Option<String>,
+    voices: VoiceList,
+    presets: PresetStore,
+    preprocessor: Option<Preprocessor>,
+    detector: Option<LanguageDetector>,
+    metrics: Metrics,
+    audit: Option<AuditLog>,
+    history: History,
+    /// Prefix of every route, see [`crate::config::Web::base_path`]
+    base_path: String,
 }
 
 impl WebExtension {
@@ -111,13 +280,226 @@ impl WebExtension {
         sender: mpsc::Sender<TTSEvent>,
         requester: Requester,
         leveldb_helper: ConnAgent,
+        broadcast: broadcast::Sender<BroadcastEvent>,
+        admin_token: Option<String>,
     ) -> Self {
         Self {
             sender,
+            voices: VoiceList::new(leveldb_helper.clone(), requester.voices_refresh()),
+            presets: PresetStore::new(
+                leveldb_helper.clone(),
+                Default::default(),
+                Default::default(),
+            ),
+            preprocessor: None,
+            detector: None,
+            metrics: Default::default(),
+            audit: None,
+            history: History::new(leveldb_helper.clone(), 0),
+            base_path: String::new(),
             requester,
             leveldb_helper,
+            broadcast,
+            admin_token,
+        }
+    }
+
+    fn presets(mut self, presets: PresetStore) -> Self {
+        self.presets = presets;
+        self
+    }
+
+    fn preprocessor(mut self, preprocessor: Option<Preprocessor>) -> Self {
+        self.preprocessor = preprocessor;
+        self
+    }
+
+    fn detector(mut self, detector: Option<LanguageDetector>) -> Self {
+        self.detector = detector;
+        self
+    }
+
+    fn metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    fn audit(mut self, audit: Option<AuditLog>) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    fn history(mut self, history: History) -> Self {
+        self.history = history;
+        self
+    }
+
+    fn base_path(mut self, base_path: String) -> Self {
+        self.base_path = base_path;
+        self
+    }
+
+    /// Audio of segments from cache, Azure or fallback command, with status for web client.
+    /// Azure audio of 31 to 74 bytes long messages is only cached if it is `replayable`
+    async fn synthesize(
+        &self,
+        segments: &[Segment],
+        sender: &MessageHelper,
+        replayable: bool,
+    ) -> anyhow::Result<(Chunk, String)> {
+        let hash = hash(segments, self.requester.output_format());
+        if let Some(cached) = self.leveldb_helper.get(hash).await {
+            log::trace!("Cache {hash} hit!");
+            self.metrics.cache(true);
+            return Ok((Chunk::Data(cached), HIT_CACHE.to_string()));
+        }
+        self.metrics.cache(false);
+        sender.progress(Progress::Synthesizing).await;
+        let ret = match self.requester.request(segments).await {
+            Ok(ret) => ret,
+            Err(e) => {
+                if let Some(RequestError::BudgetExhausted) = e.downcast_ref()
+                    && let Some(fallback) = self
+                        .requester
+                        .usage_tracker()
+                        .and_then(UsageTracker::fallback)
+                {
+                    // Not cached, cache is keyed by Azure voice
+                    let text = segments
+                        .iter()
+                        .map(|segment| segment.text.as_str())
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    let audio = fallback.synthesize(&text).await?;
+                    return Ok((Chunk::Data(audio), FALLBACK.to_string()));
+                }
+                return Err(e);
+            }
+        };
+        let code = ret.status().to_string();
+        let length = (!replayable).then(|| segments.iter().map(|segment| segment.text.len()).sum());
+        Ok((Chunk::NewData((hash, length), ret), code))
+    }
+
+    /// Whole audio of segments with status, Azure audio is downloaded and cached
+    async fn download(
+        &self,
+        segments: &[Segment],
+        sender: &MessageHelper,
+    ) -> anyhow::Result<(Bytes, String)> {
+        // Whatever the length, hash is handed out for downloading it again
+        let (chunk, code) = self.synthesize(segments, sender, true).await?;
+        let audio = match chunk {
+            Chunk::Data(audio) => audio,
+            Chunk::NewData(original, response) => {
+                let audio = response.bytes().await?;
+                cache_audio(
+                    original,
+                    audio.to_vec(),
+                    &self.leveldb_helper,
+                    &self.metrics,
+                )
+                .await
+                .ok();
+                audio
+            }
+        };
+        Ok((audio, code))
+    }
+
+    /// Preprocessed content of data, split into segments by voice
+    async fn prepare(&self, data: &mut Data) -> anyhow::Result<Vec<Segment>> {
+        if let Some(ref preprocessor) = self.preprocessor {
+            data.content = preprocessor.apply(&data.content);
+            if data.content.is_empty() {
+                return Err(anyhow!("Message is empty after preprocessing"));
+            }
+        }
+        data.segments(&self.presets, self.detector.as_ref()).await
+    }
+
+    /// Add spoken message to history and show it on every client
+    async fn remember(&self, entry: &AuditEntry, audio: Vec<u64>) {
+        if let Some(item) = self
+            .history
+            .push(
+                entry.text.clone(),
+                entry.voice.clone(),
+                entry.user.clone(),
+                audio,
+            )
+            .await
+        {
+            self.broadcast.send(BroadcastEvent::History(item)).ok();
         }
     }
+
+    /// Audio in the requested format, cache key is sent along if the audio is cached,
+    /// so the file can be downloaded again later
+    fn audio_file(&self, hash: Option<u64>, audio: Bytes, format: Option<&str>) -> Response {
+        let output = self.requester.output_format();
+        let (container, audio) = match format {
+            Some(name) if name != output.container().extension() => {
+                if name != AudioContainer::Riff.extension() {
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        format!("Audio is not available as {name:?}"),
+                    )
+                        .into_response();
+                }
+                if !cfg!(feature = "transcode") {
+                    return (
+                        StatusCode::NOT_IMPLEMENTED,
+                        "Converting audio to WAV requires the `transcode` feature",
+                    )
+                        .into_response();
+                }
+                match to_wav(audio, output) {
+                    Ok(audio) => (AudioContainer::Riff, audio),
+                    Err(e) => {
+                        return (
+                            StatusCode::BAD_REQUEST,
+                            format!("Unable convert audio to WAV: {e}"),
+                        )
+                            .into_response();
+                    }
+                }
+            }
+            _ => (output.container(), audio),
+        };
+        let name = hash.map_or_else(|| "audio".to_string(), |hash| hash.to_string());
+        let mut response = (
+            [
+                (CONTENT_TYPE, container.mime().to_string()),
+                (
+                    CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{name}.{}\"", container.extension()),
+                ),
+            ],
+            audio,
+        )
+            .into_response();
+        if let Some(hash) = hash {
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static("x-audio-hash"), hash.into());
+        }
+        response
+    }
+
+    fn check_admin(&self, headers: &HeaderMap) -> Result<(), (StatusCode, &'static str)> {
+        let Some(ref token) = self.admin_token else {
+            return Err((StatusCode::NOT_FOUND, "Admin API is disabled"));
+        };
+        let provided = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if provided != Some(token.as_str()) {
+            return Err((StatusCode::UNAUTHORIZED, "Invalid admin token"));
+        }
+        Ok(())
+    }
 }
 
 pub async fn route(
@@ -125,73 +507,471 @@ pub async fn route(
     leveldb_helper: ConnAgent,
     tts_event_sender: mpsc::Sender<TTSEvent>,
     mut broadcast: broadcast::Receiver<MainEvent>,
+    notify_sender: broadcast::Sender<BroadcastEvent>,
+    metrics: Metrics,
     override_bind: Option<String>,
 ) -> anyhow::Result<()> {
-    let client = Requester::new(config.tts().clone());
+    let usage =
+        UsageTracker::new(leveldb_helper.clone(), config.usage()).notify(notify_sender.clone());
+    usage.load(config.tts().keys()).await;
+    let client = Requester::new(config.tts().clone())
+        .notify(notify_sender.clone())
+        .usage(Arc::new(usage))
+        .metrics(metrics.clone());
+    let presets = PresetStore::new(
+        leveldb_helper.clone(),
+        config.presets().clone(),
+        config.users().clone(),
+    );
+    presets.load().await;
+    let audit = config
+        .audit()
+        .map(|audit| AuditLog::new(leveldb_helper.clone(), audit));
+    let history = History::new(leveldb_helper.clone(), config.web().history());
+    history.load().await;
+    let extension = Arc::new(
+        WebExtension::new(
+            tts_event_sender,
+            client,
+            leveldb_helper,
+            notify_sender,
+            config.web().admin_token().map(str::to_string),
+        )
+        .presets(presets)
+        .preprocessor(config.preprocess().map(Preprocessor::new))
+        .detector(config.language().map(LanguageDetector::new))
+        .metrics(metrics)
+        .audit(audit)
+        .history(history)
+        .base_path(config.web().base_path()),
+    );
+    let revalidate = config.tts().revalidate().map(|interval| {
+        let extension = extension.clone();
+        tokio::spawn(async move {
+            let mut timer = tokio::time::interval(interval);
+            timer.tick().await;
+            loop {
+                timer.tick().await;
+                extension.requester.revalidate().await;
+            }
+        })
+    });
 
+    let router = router(extension);
+
+    let bind = override_bind.unwrap_or_else(|| config.web().bind());
+    let shutdown = async move {
+        while broadcast
+            .recv()
+            .await
+            .is_ok_and(|e| MainEvent::is_not_exit(&e))
+        {}
+        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+    };
+
+    match (bind.strip_prefix("unix:"), config.web().tls()) {
+        (Some(_), Some(_)) => {
+            return Err(anyhow!("TLS is not supported on unix domain sockets"));
+        }
+        #[cfg(unix)]
+        (Some(path), None) => {
+            let listener = crate::unix_socket::UnixSocketListener::bind(path, config.web())
+                .inspect_err(|e| log::error!("Web server bind error: {e:?}"))?;
+            serve(listener, router, shutdown).await?;
+        }
+        #[cfg(not(unix))]
+        (Some(_), None) => {
+            return Err(anyhow!(
+                "Unix domain sockets are not supported on this platform"
+            ));
+        }
+        #[cfg(feature = "rustls")]
+        (None, Some(tls)) => {
+            let listener = crate::tls::TlsListener::bind(bind, tls)
+                .await
+                .inspect_err(|e| log::error!("Web server bind error: {e:?}"))?;
+            serve(listener, router, shutdown).await?;
+        }
+        #[cfg(not(feature = "rustls"))]
+        (None, Some(_)) => return Err(anyhow!("TLS requires the `rustls` feature")),
+        (None, None) => {
+            let listener = tokio::net::TcpListener::bind(bind)
+                .await
+                .inspect_err(|e| log::error!("Web server bind error: {e:?}"))?;
+            serve(listener, router, shutdown).await?;
+        }
+    }
+    if let Some(revalidate) = revalidate {
+        revalidate.abort();
+    }
+    Ok(())
+}
+
+/// Every route below `[web] base_path`
+fn router(extension: Arc<WebExtension>) -> axum::Router {
     let router = axum::Router::new()
         .route(
             "/",
             axum::routing::get(load_homepage), /* .post(post_handler) */
         )
-        .route("/ws", axum::routing::get(ws_upgrade))
+        // HTTP/2 websockets are opened by CONNECT requests
+        .route("/ws", axum::routing::any(ws_upgrade))
+        .route("/ws/v1", axum::routing::any(ws_v1_upgrade))
+        .route("/metrics", get(render_metrics))
+        .route("/api/v1/voices", get(list_voices))
+        .route("/api/v1/keys", get(list_keys))
+        .route("/api/v1/keys/{id}/enable", post(enable_key))
+        .route("/api/v1/presets", get(list_presets))
         .route(
-            "/mstts.js",
-            get(|| async {
-                (
-                    [("Cache-Control", "public, max-age=31536000")],
-                    Html(CURRENT_SUPPORT_TTS),
-                )
-            }),
+            "/api/v1/presets/{name}",
+            put(set_preset).delete(remove_preset),
         )
-        .layer(Extension(Arc::new(WebExtension::new(
-            tts_event_sender,
-            client,
-            leveldb_helper,
-        ))));
+        .route("/api/v1/users", get(list_users))
+        .route(
+            "/api/v1/users/{user}/voice",
+            put(set_user_voice).delete(remove_user_voice),
+        )
+        .route("/api/v1/usage", get(usage_summary))
+        .route("/api/v1/usage/keys", get(usage_keys))
+        .route("/api/v1/audit", get(audit_log))
+        .route("/api/v1/history", get(list_history))
+        .route("/api/v1/audio/{hash}", get(download_audio))
+        .route("/api/v1/synthesize", post(synthesize_audio));
+    let router = if extension.base_path.is_empty() {
+        router
+    } else {
+        // Page is shown with and without trailing slash
+        axum::Router::new()
+            .route(&format!("{}/", extension.base_path), get(load_homepage))
+            .nest(&extension.base_path, router)
+    };
+    router.layer(Extension(extension))
+}
 
-    let listener =
-        tokio::net::TcpListener::bind(override_bind.unwrap_or_else(|| config.web().bind()))
-            .await
-            .inspect_err(|e| log::error!("Web server bind error: {e:?}"))?;
+/// Peer address of a connection, every listener implements `Connected` for it
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PeerAddr(pub(crate) SocketAddr);
+
+impl Connected<IncomingStream<'_, tokio::net::TcpListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, tokio::net::TcpListener>) -> Self {
+        Self(*stream.remote_addr())
+    }
+}
 
+async fn serve<L>(
+    listener: L,
+    router: axum::Router,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()>
+where
+    L: Listener<Addr = SocketAddr>,
+    PeerAddr: for<'a> Connected<IncomingStream<'a, L>>,
+{
     axum::serve(
         listener,
-        router.into_make_service_with_connect_info::<SocketAddr>(),
+        router.into_make_service_with_connect_info::<PeerAddr>(),
     )
-    .with_graceful_shutdown(async move {
-        while broadcast
-            .recv()
-            .await
-            .is_ok_and(|e| MainEvent::is_not_exit(&e))
-        {}
-        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
-    })
-    .await?;
-    Ok(())
+    .with_graceful_shutdown(shutdown)
+    .await
+}
+
+async fn list_keys(
+    Extension(extension): Extension<Arc<WebExtension>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = extension.check_admin(&headers) {
+        return rejection.into_response();
+    }
+    axum::Json(extension.requester.keys().status().await).into_response()
+}
+
+async fn enable_key(
+    Extension(extension): Extension<Arc<WebExtension>>,
+    headers: HeaderMap,
+    Path(id): Path<usize>,
+) -> Response {
+    if let Err(rejection) = extension.check_admin(&headers) {
+        return rejection.into_response();
+    }
+    if extension.requester.keys().enable_by_id(id).await {
+        log::info!("Key {id} re-enabled by admin");
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "Key not found").into_response()
+    }
+}
+
+async fn list_voices(Extension(extension): Extension<Arc<WebExtension>>) -> Response {
+    match extension.voices.get(&extension.requester).await {
+        Ok(voices) => axum::Json(voices).into_response(),
+        Err(e) => {
+            log::error!("Unable fetch voice list: {e:?}");
+            (StatusCode::BAD_GATEWAY, "Unable fetch voice list").into_response()
+        }
+    }
+}
+
+async fn list_presets(Extension(extension): Extension<Arc<WebExtension>>) -> Response {
+    axum::Json(extension.presets.presets().await).into_response()
+}
+
+async fn set_preset(
+    Extension(extension): Extension<Arc<WebExtension>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    axum::Json(preset): axum::Json<Preset>,
+) -> Response {
+    if let Err(rejection) = extension.check_admin(&headers) {
+        return rejection.into_response();
+    }
+    match extension.presets.set_preset(name, preset).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+async fn remove_preset(
+    Extension(extension): Extension<Arc<WebExtension>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Response {
+    if let Err(rejection) = extension.check_admin(&headers) {
+        return rejection.into_response();
+    }
+    match extension.presets.remove_preset(&name).await {
+        Some(Ok(())) => StatusCode::NO_CONTENT.into_response(),
+        Some(Err(e)) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            "Preset not found or defined in config",
+        )
+            .into_response(),
+    }
+}
+
+async fn list_users(
+    Extension(extension): Extension<Arc<WebExtension>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = extension.check_admin(&headers) {
+        return rejection.into_response();
+    }
+    axum::Json(extension.presets.users().await).into_response()
+}
+
+async fn set_user_voice(
+    Extension(extension): Extension<Arc<WebExtension>>,
+    headers: HeaderMap,
+    Path(user): Path<String>,
+    axum::Json(voice): axum::Json<DefaultVoice>,
+) -> Response {
+    if let Err(rejection) = extension.check_admin(&headers) {
+        return rejection.into_response();
+    }
+    match extension.presets.set_user(user, voice).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+async fn remove_user_voice(
+    Extension(extension): Extension<Arc<WebExtension>>,
+    headers: HeaderMap,
+    Path(user): Path<String>,
+) -> Response {
+    if let Err(rejection) = extension.check_admin(&headers) {
+        return rejection.into_response();
+    }
+    match extension.presets.remove_user(&user).await {
+        Some(Ok(())) => StatusCode::NO_CONTENT.into_response(),
+        Some(Err(e)) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        None => (StatusCode::NOT_FOUND, "User not found or defined in config").into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct AudioQuery {
+    /// `wav` or the extension of Azure output format, the latter if absent
+    format: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct UsageQuery {
+    /// `YYYYMM`, current month if absent
+    month: Option<u32>,
+}
+
+async fn audit_log(
+    Extension(extension): Extension<Arc<WebExtension>>,
+    headers: HeaderMap,
+    Query(query): Query<AuditQuery>,
+) -> Response {
+    if let Err(rejection) = extension.check_admin(&headers) {
+        return rejection.into_response();
+    }
+    let Some(ref audit) = extension.audit else {
+        return (StatusCode::NOT_FOUND, "Audit log is disabled").into_response();
+    };
+    axum::Json(audit.query(&query).await).into_response()
+}
+
+async fn download_audio(
+    Extension(extension): Extension<Arc<WebExtension>>,
+    Path(hash): Path<u64>,
+    Query(query): Query<AudioQuery>,
+) -> Response {
+    match extension.leveldb_helper.get(hash).await {
+        Some(audio) => extension.audio_file(Some(hash), audio, query.format.as_deref()),
+        None => (StatusCode::NOT_FOUND, "Audio is not cached").into_response(),
+    }
+}
+
+/// Audio file of a message without playing it, whole message in one Azure request
+async fn synthesize_audio(
+    Extension(extension): Extension<Arc<WebExtension>>,
+    ConnectInfo(PeerAddr(addr)): ConnectInfo<PeerAddr>,
+    Query(query): Query<AudioQuery>,
+    axum::Json(mut data): axum::Json<Data>,
+) -> Response {
+    let origin = Origin::new("rest", addr.ip().to_string());
+    extension.metrics.request(origin.source());
+    let mut entry = AuditEntry::new(&origin, data.user.clone(), data.content.clone());
+    let segments = match extension.prepare(&mut data).await {
+        Ok(segments) => segments,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    entry.voice = voices(&segments);
+    let hash = hash(&segments, extension.requester.output_format());
+    let ret = extension
+        .download(&segments, &MessageHelper::default())
+        .await;
+    if let Some(ref audit) = extension.audit {
+        entry.cached = ret
+            .as_ref()
+            .is_ok_and(|(_, code)| code.as_str() == HIT_CACHE);
+        entry.finish(&match ret {
+            Ok((_, ref code)) => Ok(code.clone()),
+            Err(ref e) => Err(anyhow!("{e}")),
+        });
+        audit.record(&entry).await;
+    }
+    match ret {
+        // Fallback audio is not cached
+        Ok((audio, code)) => extension.audio_file(
+            (code != FALLBACK).then_some(hash),
+            audio,
+            query.format.as_deref(),
+        ),
+        Err(e) => {
+            let status = match e.downcast_ref() {
+                Some(RequestError::BudgetExhausted | RequestError::Throttled(_)) => {
+                    StatusCode::TOO_MANY_REQUESTS
+                }
+                _ => StatusCode::BAD_GATEWAY,
+            };
+            (status, e.to_string()).into_response()
+        }
+    }
+}
+
+async fn list_history(Extension(extension): Extension<Arc<WebExtension>>) -> Response {
+    axum::Json(extension.history.entries().await).into_response()
+}
+
+async fn render_metrics(Extension(extension): Extension<Arc<WebExtension>>) -> Response {
+    match extension.metrics.render() {
+        Ok(text) => ([(CONTENT_TYPE, "text/plain; version=0.0.4")], text).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn usage_summary(
+    Extension(extension): Extension<Arc<WebExtension>>,
+    Query(query): Query<UsageQuery>,
+) -> Response {
+    let Some(usage) = extension.requester.usage_tracker() else {
+        return (StatusCode::NOT_FOUND, "Usage tracking is disabled").into_response();
+    };
+    axum::Json(
+        usage
+            .summary(query.month.unwrap_or_else(current_month))
+            .await,
+    )
+    .into_response()
+}
+
+async fn usage_keys(
+    Extension(extension): Extension<Arc<WebExtension>>,
+    headers: HeaderMap,
+    Query(query): Query<UsageQuery>,
+) -> Response {
+    if let Err(rejection) = extension.check_admin(&headers) {
+        return rejection.into_response();
+    }
+    let Some(usage) = extension.requester.usage_tracker() else {
+        return (StatusCode::NOT_FOUND, "Usage tracking is disabled").into_response();
+    };
+    axum::Json(
+        usage
+            .keys(
+                extension.requester.keys(),
+                query.month.unwrap_or_else(current_month),
+            )
+            .await,
+    )
+    .into_response()
 }
 
 async fn ws_upgrade(
     ws: WebSocketUpgrade,
     Extension(extension): Extension<Arc<WebExtension>>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ConnectInfo(PeerAddr(addr)): ConnectInfo<PeerAddr>,
+) -> impl IntoResponse {
+    upgrade(ws, extension, addr, false)
+}
+
+async fn ws_v1_upgrade(
+    ws: WebSocketUpgrade,
+    Extension(extension): Extension<Arc<WebExtension>>,
+    ConnectInfo(PeerAddr(addr)): ConnectInfo<PeerAddr>,
 ) -> impl IntoResponse {
+    upgrade(ws, extension, addr, true)
+}
+
+fn upgrade(
+    ws: WebSocketUpgrade,
+    extension: Arc<WebExtension>,
+    addr: SocketAddr,
+    typed: bool,
+) -> Response {
     ws.on_upgrade(move |socket| {
         log::debug!("Accept connection from {addr:?}");
-        async {
-            ws_handler(socket, extension)
-                .await
-                .inspect_err(|e| log::error!("Websocket error: {e:?}"))
-                .ok();
+        async move {
+            let metrics = extension.metrics.clone();
+            metrics.websocket(true);
+            let origin = Origin::new("websocket", addr.ip().to_string());
+            if typed {
+                ws_v1_handler(socket, extension, origin).await
+            } else {
+                ws_handler(socket, extension, origin).await
+            }
+            .inspect_err(|e| log::error!("Websocket error: {e:?}"))
+            .ok();
+            metrics.websocket(false);
         }
     })
 }
 
-async fn ws_handler(socket: WebSocket, extension: Arc<WebExtension>) -> anyhow::Result<()> {
+async fn ws_handler(
+    socket: WebSocket,
+    extension: Arc<WebExtension>,
+    origin: Origin,
+) -> anyhow::Result<()> {
     //log::debug!("Handle websocket");
     let (mut sender, mut receiver) = socket.split();
 
     let (outer_sender, mut inner_receiver) = WebsocketHelper::new(4);
+    let mut notify_receiver = extension.broadcast.subscribe();
     loop {
         tokio::select! {
             message = receiver.next() => {
@@ -199,14 +979,20 @@ async fn ws_handler(socket: WebSocket, extension: Arc<WebExtension>) -> anyhow::
                     log::trace!("{message:?}");
 
                     match decode_message(&message) {
-                        Ok(data) => {
-                            if data.content.eq("cLoSe ConneCtion!") {
-                                break
-                            }
+                        Ok(request) => {
+                            let ret = match request {
+                                TTSRequest::Speak(data) => {
+                                    if data.content.eq("cLoSe ConneCtion!") {
+                                        break
+                                    }
+                                    handle_request(data, &extension, outer_sender.clone().into(), &origin).await
+                                }
+                                TTSRequest::Replay { replay, priority } => {
+                                    handle_replay(replay, priority, &extension, outer_sender.clone().into(), &origin).await
+                                }
+                            };
                             sender.send(Message::Text(
-                                handle_request(data, &extension, outer_sender.clone().into())
-                                    .await
-                                    .unwrap_or_else(|e| e.to_string()).into()
+                                ret.unwrap_or_else(|e| e.to_string()).into()
                                     //.tap(|s| log::debug!("{s:?}"))
                                 )).await.inspect_err(|e| log::error!("{e:?}")).ok();
                         },
@@ -218,22 +1004,150 @@ async fn ws_handler(socket: WebSocket, extension: Arc<WebExtension>) -> anyhow::
             }
             Some(event) = inner_receiver.recv() => {
                 match event {
-                    WebsocketEvent::Message(msg) => {
-                        sender.send(Message::Text(msg.into())).await?;
+                    WebsocketEvent::Progress(_, progress) => {
+                        if let Some(audio) = progress.audio() {
+                            sender.send(Message::Binary(audio)).await?;
+                        }
+                        if let Some(msg) = progress.legacy() {
+                            sender.send(Message::Text(msg.into())).await?;
+                        }
                     },
                 }
             }
+            Ok(event) = notify_receiver.recv() => {
+                match event {
+                    BroadcastEvent::Transcript(name, text) => {
+                        sender.send(Message::Text(format!("[Transcript] {name}: {text}").into())).await?;
+                    }
+                    BroadcastEvent::Warning(text) => {
+                        sender.send(Message::Text(format!("[Warning] {text}").into())).await?;
+                    }
+                    BroadcastEvent::History(entry) => {
+                        sender.send(Message::Text(format!("[History] {}", serde_json::to_string(&entry)?).into())).await?;
+                    }
+                }
+            }
         }
     }
     //log::debug!("Disconnect websocket");
     Ok(())
 }
 
-fn decode_message(msg: &Message) -> anyhow::Result<Data> {
+/// Typed protocol, see [`crate::protocol`]
+async fn ws_v1_handler(
+    socket: WebSocket,
+    extension: Arc<WebExtension>,
+    origin: Origin,
+) -> anyhow::Result<()> {
+    let (mut sender, mut receiver) = socket.split();
+    let (outer_sender, mut inner_receiver) = WebsocketHelper::new(4);
+    let mut notify_receiver = extension.broadcast.subscribe();
+    let (request_sender, request_receiver) = mpsc::unbounded_channel();
+    // Requests already sent on still play after the connection is closed, like on the plain protocol
+    tokio::spawn(ws_v1_worker(request_receiver, extension.clone(), origin));
+    let mut topics = BTreeSet::new();
+    // Cancel flags of unfinished speak and replay requests
+    let mut requests: HashMap<u64, Arc<AtomicBool>> = HashMap::new();
+
+    let hello = ServerMessage::Hello { version: VERSION };
+    sender.send(Message::Text(hello.to_json().into())).await?;
+    loop {
+        tokio::select! {
+            message = receiver.next() => {
+                let Some(Ok(message)) = message else {
+                    break;
+                };
+                let request = match message {
+                    Message::Text(text) => serde_json::from_str::<ClientMessage>(&text),
+                    Message::Close(_) => break,
+                    _ => continue,
+                };
+                let reply = match request {
+                    Ok(ClientMessage::Speak { id, data }) => {
+                        let cancelled = requests.entry(id).or_default().clone();
+                        let helper = MessageHelper::from(outer_sender.clone()).request(id, cancelled);
+                        request_sender.send((TTSRequest::Speak(data), helper)).ok();
+                        continue;
+                    }
+                    Ok(ClientMessage::Replay { id, entry, priority }) => {
+                        let cancelled = requests.entry(id).or_default().clone();
+                        let helper = MessageHelper::from(outer_sender.clone()).request(id, cancelled);
+                        request_sender.send((TTSRequest::Replay { replay: entry, priority }, helper)).ok();
+                        continue;
+                    }
+                    Ok(ClientMessage::Cancel { id, request }) => match requests.get(&request) {
+                        Some(cancelled) => {
+                            cancelled.store(true, Ordering::Relaxed);
+                            ServerMessage::Done { id }
+                        }
+                        None => ServerMessage::Error { id: Some(id), message: format!("Request {request} is not running") },
+                    },
+                    Ok(ClientMessage::Ping { id }) => ServerMessage::Pong { id },
+                    Ok(ClientMessage::Subscribe { id, topics: subscribed }) => {
+                        topics = subscribed;
+                        ServerMessage::Subscribed { id, topics: topics.clone() }
+                    }
+                    Ok(ClientMessage::Status { id }) => {
+                        let (queue, clients, teamspeak) = extension.metrics.snapshot();
+                        ServerMessage::Status { id, version: env!("CARGO_PKG_VERSION"), queue, clients, teamspeak }
+                    }
+                    Err(e) => ServerMessage::Error { id: None, message: format!("Invalid message: {e}") },
+                };
+                sender.send(Message::Text(reply.to_json().into())).await?;
+            }
+            Some(event) = inner_receiver.recv() => {
+                let WebsocketEvent::Progress(id, progress) = event;
+                if progress.is_final() {
+                    requests.remove(&id);
+                }
+                let audio = progress.audio();
+                sender.send(Message::Text(progress.into_message(id).to_json().into())).await?;
+                if let Some(audio) = audio {
+                    sender.send(Message::Binary(audio)).await?;
+                }
+            }
+            Ok(event) = notify_receiver.recv() => {
+                let message = ServerMessage::from(event);
+                if message.topic().is_some_and(|topic| topics.contains(&topic)) {
+                    sender.send(Message::Text(message.to_json().into())).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Handles speak and replay requests of a connection one by one, so playback keeps their order
+async fn ws_v1_worker(
+    mut receiver: mpsc::UnboundedReceiver<(TTSRequest, MessageHelper)>,
+    extension: Arc<WebExtension>,
+    origin: Origin,
+) {
+    while let Some((request, sender)) = receiver.recv().await {
+        if sender.is_cancelled() {
+            sender.progress(Progress::Cancelled).await;
+            continue;
+        }
+        let ret = match request {
+            TTSRequest::Speak(data) => {
+                handle_request(data, &extension, sender.clone(), &origin).await
+            }
+            TTSRequest::Replay { replay, priority } => {
+                handle_replay(replay, priority, &extension, sender.clone(), &origin).await
+            }
+        };
+        // Queued is sent before audio is handed to playback
+        if let Err(e) = ret {
+            sender.progress(Progress::Error(e.to_string())).await;
+        }
+    }
+}
+
+fn decode_message(msg: &Message) -> anyhow::Result<TTSRequest> {
     msg.to_text()
         .map_err(|e| anyhow!("Ignore error in decode {e:?}"))
         .and_then(|s| {
-            serde_json::from_str::<Data>(s).map_err(|e| anyhow!("Deserialize error: {e:?}"))
+            serde_json::from_str::<TTSRequest>(s).map_err(|e| anyhow!("Deserialize error: {e:?}"))
         })
 }
 
@@ -241,38 +1155,200 @@ async fn handle_request(
     data: Data,
     extension: &Arc<WebExtension>,
     sender: MessageHelper,
+    origin: &Origin,
 ) -> anyhow::Result<String> {
-    let hash = data.hash();
-    let code = match extension.leveldb_helper.get(hash).await {
-        Some(data) => {
-            log::trace!("Cache {hash} hit!");
+    extension.metrics.request(origin.source());
+    let mut entry = AuditEntry::new(origin, data.user.clone(), data.content.clone());
+    let ret = speak(data, extension, sender, &mut entry).await;
+    if let Some(ref audit) = extension.audit {
+        entry.finish(&ret);
+        audit.record(&entry).await;
+    }
+    ret
+}
+
+async fn handle_replay(
+    id: u64,
+    priority: Priority,
+    extension: &Arc<WebExtension>,
+    sender: MessageHelper,
+    origin: &Origin,
+) -> anyhow::Result<String> {
+    extension.metrics.request(origin.source());
+    let item = extension
+        .history
+        .get(id)
+        .await
+        .ok_or_else(|| anyhow!("History entry {id} not found"))?;
+    let mut entry = AuditEntry::new(origin, item.user.clone(), item.text.clone());
+    entry.voice = item.voice.clone();
+    entry.cached = true;
+    let ret = replay(&item.audio, priority, extension, sender).await;
+    if let Some(ref audit) = extension.audit {
+        entry.finish(&ret);
+        audit.record(&entry).await;
+    }
+    ret
+}
+
+/// Audio is only taken from cache, Azure is never requested
+async fn replay(
+    audio: &[u64],
+    priority: Priority,
+    extension: &Arc<WebExtension>,
+    sender: MessageHelper,
+) -> anyhow::Result<String> {
+    let mut chunks = Vec::new();
+    for hash in audio {
+        chunks.push(
             extension
-                .sender
-                .send(TTSEvent::Data(data, sender))
+                .leveldb_helper
+                .get(*hash)
                 .await
-                .ok();
-            "Hit cache".to_string()
+                .ok_or_else(|| anyhow!("Audio is not cached anymore"))?,
+        );
+    }
+    sender.progress(Progress::Queued(REPLAY.to_string())).await;
+    let event = match <[_; 1]>::try_from(chunks) {
+        Ok([audio]) => TTSEvent::Data(audio, sender, priority),
+        Err(chunks) => {
+            let (chunk_sender, receiver) = mpsc::unbounded_channel();
+            for chunk in chunks {
+                chunk_sender.send(Chunk::Data(chunk)).ok();
+            }
+            TTSEvent::Chunks(receiver, sender, priority)
         }
-        None => {
-            let ret = extension
-                .requester
-                .request(&data.code, &data.sex, data.variant(), &data.content)
-                .await?;
-            let code = ret.status();
-            extension
-                .sender
-                .send(TTSEvent::NewData(
-                    (data.hash(), data.content.len()),
-                    ret,
-                    sender,
-                ))
+    };
+    extension
+        .sender
+        .send(event)
+        .await
+        .inspect_err(|_| log::error!("Fail to send response"))
+        .ok();
+    Ok(REPLAY.to_string())
+}
+
+async fn speak(
+    mut data: Data,
+    extension: &Arc<WebExtension>,
+    sender: MessageHelper,
+    entry: &mut AuditEntry,
+) -> anyhow::Result<String> {
+    let segments = extension.prepare(&mut data).await?;
+    entry.voice = voices(&segments);
+    let chunks = chunks(segments, extension.requester.chunk_size());
+    let audio = chunks
+        .iter()
+        .map(|chunk| hash(chunk, extension.requester.output_format()))
+        .collect::<Vec<_>>();
+    if data.preview {
+        let total = chunks.len();
+        tokio::spawn(preview(chunks, extension.clone(), sender));
+        return Ok(format!("{PREVIEW}, {total} chunks"));
+    }
+    let mut chunks = chunks.into_iter();
+    let total = chunks.len();
+    let first = chunks.next().ok_or_else(|| anyhow!("Message is empty"))?;
+    // Spoken messages go into history, which replays them from cache
+    let replayable = extension.history.is_enabled();
+    let (chunk, code) = extension.synthesize(&first, &sender, replayable).await?;
+    entry.cached = code == HIT_CACHE;
+    // Fallback audio is not cached, nothing to replay
+    if code != FALLBACK {
+        extension.remember(entry, audio).await;
+    }
+    if total == 1 {
+        sender.progress(Progress::Queued(code.clone())).await;
+        let event = match chunk {
+            Chunk::NewData(original, response) => {
+                TTSEvent::NewData(original, response, sender, data.priority)
+            }
+            Chunk::Data(audio) => TTSEvent::Data(audio, sender, data.priority),
+        };
+        extension
+            .sender
+            .send(event)
+            .await
+            .inspect_err(|_| log::error!("Fail to send response"))
+            .ok();
+        return Ok(code);
+    }
+
+    // Playback starts with the first chunk, the rest is synthesized meanwhile
+    let detail = format!("{code}, {total} chunks");
+    sender.progress(Progress::Queued(detail.clone())).await;
+    let (chunk_sender, receiver) = mpsc::unbounded_channel();
+    chunk_sender.send(chunk).ok();
+    extension
+        .sender
+        .send(TTSEvent::Chunks(receiver, sender.clone(), data.priority))
+        .await
+        .inspect_err(|_| log::error!("Fail to send response"))
+        .ok();
+    let extension = extension.clone();
+    tokio::spawn(async move {
+        for (index, segments) in chunks.enumerate() {
+            if sender.is_cancelled() {
+                break;
+            }
+            match extension
+                .synthesize(&segments, &MessageHelper::default(), replayable)
                 .await
-                .inspect_err(|_| log::error!("Fail to send response"))
-                .ok();
-            code.to_string()
+            {
+                Ok((chunk, _)) => {
+                    // Playback is dropped
+                    if chunk_sender.send(chunk).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    log::error!("Unable synthesize chunk: {e:?}");
+                    sender
+                        .progress(Progress::Error(format!(
+                            "Chunk {}/{total} failed: {e}",
+                            index + 2
+                        )))
+                        .await;
+                    break;
+                }
+            }
         }
-    };
-    Ok(code)
+    });
+    Ok(detail)
+}
+
+/// Synthesize chunks one by one and send them to the client, Azure audio is cached so
+/// sending the message afterwards hits cache
+async fn preview(chunks: Vec<Vec<Segment>>, extension: Arc<WebExtension>, sender: MessageHelper) {
+    let total = chunks.len();
+    let mime = extension.requester.output_format().container().mime();
+    for (index, segments) in chunks.into_iter().enumerate() {
+        if sender.is_cancelled() {
+            sender.progress(Progress::Cancelled).await;
+            return;
+        }
+        let progress = match extension.download(&segments, &sender).await {
+            Ok((audio, _)) => Progress::Audio {
+                audio,
+                mime,
+                last: index + 1 == total,
+            },
+            Err(e) => {
+                log::error!("Unable synthesize preview: {e:?}");
+                sender
+                    .progress(Progress::Error(format!(
+                        "Preview {}/{total} failed: {e}",
+                        index + 1
+                    )))
+                    .await;
+                return;
+            }
+        };
+        if sender.progress(progress).await.is_none() {
+            // Client is gone
+            return;
+        }
+    }
 }
 
 /* async fn post_handler(
@@ -316,3 +1392,794 @@ async fn handle_request(
     Ok(code)
 }
  */
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
+        time::Duration,
+    };
+
+    use axum::{
+        Extension,
+        extract::{ConnectInfo, Path, Query},
+        http::{
+            StatusCode,
+            header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        },
+        response::Response,
+    };
+    use serde_json::json;
+    use tokio::sync::{broadcast, mpsc};
+
+    use super::{
+        AudioQuery, Data, MessageHelper, PeerAddr, TTSRequest, WebExtension, WebsocketEvent,
+        WebsocketHelper, download_audio, handle_replay, handle_request, hash, router, serve,
+        synthesize_audio, ws_v1_worker,
+    };
+    use crate::{
+        audit::{AuditLog, AuditQuery, Origin},
+        cache::LevelDB,
+        config::Usage,
+        history::History,
+        language::LanguageDetector,
+        presets::{DefaultVoice, Preset, PresetStore},
+        protocol::Progress,
+        test_support::{SinkEvent, TestEnv},
+        tts::{RequestError, Requester, Segment, TTSEvent},
+        types::BroadcastEvent,
+        usage::UsageTracker,
+    };
+
+    fn data(content: &str) -> Data {
+        serde_json::from_value(json!({
+            "content": content,
+            "code": "en-US",
+            "sex": "Female",
+            "variant": "AvaNeural",
+        }))
+        .unwrap()
+    }
+
+    fn voice() -> Preset {
+        Preset::new("en-US-AvaNeural".to_string(), "Female".to_string())
+    }
+
+    fn origin() -> Origin {
+        Origin::new("websocket", "127.0.0.1".to_string())
+    }
+
+    fn extension(env: &TestEnv, requester: Requester) -> WebExtension {
+        WebExtension::new(
+            env.sender.clone(),
+            requester,
+            env.agent.clone(),
+            broadcast::channel(16).0,
+            None,
+        )
+    }
+
+    fn data_hash(content: &str) -> u64 {
+        hash(
+            &[Segment::new(voice(), content.to_string())],
+            &Default::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_voice_resolution() {
+        let (agent, db) = LevelDB::new_in_memory();
+        let presets = PresetStore::new(
+            agent,
+            HashMap::from([(
+                "narrator".to_string(),
+                serde_json::from_str(r#"{"voice": "en-GB-RyanNeural", "rate": "-10%"}"#).unwrap(),
+            )]),
+            HashMap::from([(
+                "uid=".to_string(),
+                DefaultVoice::Preset("narrator".to_string()),
+            )]),
+        );
+        let resolve = |raw: serde_json::Value| {
+            let presets = &presets;
+            async move {
+                let mut segments = serde_json::from_value::<Data>(raw)
+                    .unwrap()
+                    .segments(presets, None)
+                    .await?;
+                assert_eq!(segments.len(), 1);
+                anyhow::Ok(segments.remove(0).voice)
+            }
+        };
+
+        let narrator = resolve(json!({"content": "Hi", "preset": "narrator"}))
+            .await
+            .unwrap();
+        assert_eq!(narrator.voice(), "en-GB-RyanNeural");
+        // Explicit voice wins over user default
+        assert_eq!(
+            resolve(
+                json!({"content": "Hi", "user": "uid=", "code": "en-US", "variant": "AvaNeural"})
+            )
+            .await
+            .unwrap(),
+            Preset::new("en-US-AvaNeural".to_string(), String::new())
+        );
+        assert_eq!(
+            resolve(json!({"content": "Hi", "user": "uid="}))
+                .await
+                .unwrap(),
+            narrator
+        );
+        assert!(
+            resolve(json!({"content": "Hi", "user": "nobody"}))
+                .await
+                .is_err()
+        );
+        assert!(
+            resolve(json!({"content": "Hi", "preset": "missing"}))
+                .await
+                .is_err()
+        );
+        // Voice fields are put into SSML
+        assert!(
+            resolve(json!({"content": "Hi", "code": "en-US", "variant": "AvaNeural' x='"}))
+                .await
+                .is_err()
+        );
+        assert!(
+            resolve(
+                json!({"content": "Hi", "code": "en-US", "variant": "AvaNeural", "sex": "'/>"})
+            )
+            .await
+            .is_err()
+        );
+        // Prosody is part of cache key
+        assert_ne!(
+            hash(
+                &[Segment::new(narrator, "Hi".to_string())],
+                &Default::default()
+            ),
+            hash(
+                &[Segment::new(
+                    Preset::new("en-GB-RyanNeural".to_string(), String::new()),
+                    "Hi".to_string()
+                )],
+                &Default::default()
+            )
+        );
+
+        db.disconnect().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_language_detection() {
+        let (agent, db) = LevelDB::new_in_memory();
+        let presets = PresetStore::new(
+            agent,
+            HashMap::from([(
+                "narrator".to_string(),
+                serde_json::from_str(r#"{"voice": "en-GB-RyanNeural"}"#).unwrap(),
+            )]),
+            HashMap::from([(
+                "uid=".to_string(),
+                DefaultVoice::Preset("narrator".to_string()),
+            )]),
+        );
+        let detector = LanguageDetector::new(
+            &toml::from_str(
+                r#"
+[voices]
+cmn = "zh-CN-XiaoxiaoNeural"
+eng = "en-US-AvaNeural"
+"#,
+            )
+            .unwrap(),
+        );
+        let resolve = |raw: serde_json::Value| {
+            let (presets, detector) = (&presets, &detector);
+            async move {
+                serde_json::from_value::<Data>(raw)
+                    .unwrap()
+                    .segments(presets, Some(detector))
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|segment| (segment.voice.voice().to_string(), segment.text))
+                    .collect::<Vec<_>>()
+            }
+        };
+        let content = "我们明天下午三点开会 please remember to bring the quarterly report";
+
+        assert_eq!(
+            resolve(json!({"content": content})).await,
+            [
+                (
+                    "zh-CN-XiaoxiaoNeural".to_string(),
+                    "我们明天下午三点开会".to_string()
+                ),
+                (
+                    "en-US-AvaNeural".to_string(),
+                    "please remember to bring the quarterly report".to_string()
+                ),
+            ]
+        );
+        // User default is kept for its own language
+        assert_eq!(
+            resolve(json!({"content": content, "user": "uid="})).await[1].0,
+            "en-GB-RyanNeural"
+        );
+        // Explicit voice skips detection
+        assert_eq!(
+            resolve(json!({"content": content, "code": "en-US", "variant": "AvaNeural"}))
+                .await
+                .len(),
+            1
+        );
+
+        db.disconnect().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pipeline() {
+        let env = TestEnv::with_playback().await;
+        let extension =
+            Arc::new(extension(&env, env.requester()).history(History::new(env.agent.clone(), 10)));
+        let mut notify_receiver = extension.broadcast.subscribe();
+        let data = data("Hello");
+
+        assert_eq!(
+            handle_request(data.clone(), &extension, Default::default(), &origin())
+                .await
+                .unwrap(),
+            "200 OK"
+        );
+        env.teamspeak.wait_playback(1).await;
+        let events = env.teamspeak.events();
+        assert_eq!(events.len(), 52);
+        assert_eq!(events.first().unwrap().1, SinkEvent::Muted(false));
+        assert_eq!(events.last().unwrap().1, SinkEvent::Muted(true));
+        assert!(
+            events[1..51]
+                .iter()
+                .all(|(_, event)| event.eq(&SinkEvent::Audio(vec![0xf8, 0xff, 0xfe])))
+        );
+        // Packets are paced at 20ms
+        let span = events[50].0 - events[1].0;
+        assert!(span >= Duration::from_millis(49 * 18), "{span:?}");
+        assert!(span <= Duration::from_millis(49 * 40), "{span:?}");
+
+        let hash = data_hash("Hello");
+        env.wait_cached(hash).await;
+        assert_eq!(
+            handle_request(data, &extension, Default::default(), &origin())
+                .await
+                .unwrap(),
+            "Hit cache"
+        );
+        env.teamspeak.wait_playback(2).await;
+        assert_eq!(env.teamspeak.events().len(), 104);
+        assert_eq!(env.mock.requests().len(), 1);
+
+        // Every spoken message is shared, replay never requests Azure
+        for id in [1, 2] {
+            let Ok(BroadcastEvent::History(entry)) = notify_receiver.recv().await else {
+                panic!("History entry expected");
+            };
+            assert_eq!((entry.id, entry.text.as_str()), (id, "Hello"));
+            assert_eq!(entry.audio, [hash]);
+        }
+        let request: TTSRequest = serde_json::from_str(r#"{"replay": 1}"#).unwrap();
+        assert!(matches!(request, TTSRequest::Replay { replay: 1, .. }));
+        assert_eq!(
+            handle_replay(
+                1,
+                Default::default(),
+                &extension,
+                Default::default(),
+                &origin()
+            )
+            .await
+            .unwrap(),
+            "Replay from cache"
+        );
+        env.teamspeak.wait_playback(3).await;
+        assert_eq!(env.teamspeak.events().len(), 156);
+        assert_eq!(env.mock.requests().len(), 1);
+        assert!(
+            handle_replay(
+                9,
+                Default::default(),
+                &extension,
+                Default::default(),
+                &origin()
+            )
+            .await
+            .is_err()
+        );
+
+        // Medium length messages are cached too while they are in history
+        let content = "This message is exactly forty bytes long";
+        assert_eq!(content.len(), 40);
+        assert_eq!(
+            handle_request(
+                self::data(content),
+                &extension,
+                Default::default(),
+                &origin()
+            )
+            .await
+            .unwrap(),
+            "200 OK"
+        );
+        env.teamspeak.wait_playback(4).await;
+        let Ok(BroadcastEvent::History(entry)) = notify_receiver.recv().await else {
+            panic!("History entry expected");
+        };
+        assert_eq!(entry.audio, [data_hash(content)]);
+        env.wait_cached(data_hash(content)).await;
+        assert_eq!(
+            handle_replay(
+                entry.id,
+                Default::default(),
+                &extension,
+                Default::default(),
+                &origin()
+            )
+            .await
+            .unwrap(),
+            "Replay from cache"
+        );
+        env.teamspeak.wait_playback(5).await;
+        assert_eq!(env.mock.requests().len(), 2);
+
+        env.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_medium_length() {
+        let env = TestEnv::with_playback().await;
+        let extension = Arc::new(extension(&env, env.requester()));
+        let content = "This message is exactly forty bytes long";
+
+        // Without history nothing replays it, so it is not cached
+        for _ in 0..2 {
+            assert_eq!(
+                handle_request(data(content), &extension, Default::default(), &origin())
+                    .await
+                    .unwrap(),
+                "200 OK"
+            );
+        }
+        env.teamspeak.wait_playback(2).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(env.agent.get(data_hash(content)).await.is_none());
+        assert_eq!(env.mock.requests().len(), 2);
+
+        env.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_progress() {
+        let env = TestEnv::with_playback().await;
+        let extension = Arc::new(extension(&env, env.requester()));
+        let (helper, mut events) = WebsocketHelper::new(16);
+        let (request_sender, request_receiver) = mpsc::unbounded_channel();
+        let worker = tokio::spawn(ws_v1_worker(request_receiver, extension, origin()));
+        let mut next = async || {
+            let WebsocketEvent::Progress(id, progress) = events.recv().await.unwrap();
+            serde_json::to_value(progress.into_message(id)).unwrap()
+        };
+
+        let flag = Arc::new(AtomicBool::new(false));
+        request_sender
+            .send((
+                TTSRequest::Speak(data("Hello")),
+                MessageHelper::from(helper.clone()).request(1, flag.clone()),
+            ))
+            .unwrap();
+        for expected in [
+            json!({"type": "synthesizing", "id": 1}),
+            json!({"type": "queued", "id": 1, "detail": "200 OK"}),
+            json!({"type": "playing", "id": 1}),
+            json!({"type": "done", "id": 1}),
+        ] {
+            assert_eq!(next().await, expected);
+        }
+
+        // Cancelled while playing
+        request_sender
+            .send((
+                TTSRequest::Speak(data("Hello")),
+                MessageHelper::from(helper.clone()).request(2, flag.clone()),
+            ))
+            .unwrap();
+        assert_eq!(
+            next().await,
+            json!({"type": "queued", "id": 2, "detail": "Hit cache"})
+        );
+        assert_eq!(next().await, json!({"type": "playing", "id": 2}));
+        flag.store(true, Ordering::Relaxed);
+        assert_eq!(
+            next().await,
+            json!({"type": "error", "id": 2, "message": "Cancelled"})
+        );
+        env.teamspeak.wait_playback(2).await;
+        assert!(env.teamspeak.events().len() < 104);
+
+        // Cancelled before it is handled
+        request_sender
+            .send((
+                TTSRequest::Replay {
+                    replay: 1,
+                    priority: Default::default(),
+                },
+                MessageHelper::from(helper).request(3, flag),
+            ))
+            .unwrap();
+        assert_eq!(
+            next().await,
+            json!({"type": "error", "id": 3, "message": "Cancelled"})
+        );
+        assert_eq!(env.mock.requests().len(), 1);
+
+        drop(request_sender);
+        worker.await.unwrap();
+        env.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_chunked() {
+        let env = TestEnv::with_playback().await;
+        let tts = toml::from_str(&format!(
+            "endpoint = {:?}\nOcp-Apim-Subscription-Key = [\"key\"]\nchunk_size = 25",
+            env.mock.endpoint()
+        ))
+        .unwrap();
+        let extension = Arc::new(extension(&env, Requester::new(tts)));
+        let content = "First sentence here. Second sentence here. Third one.";
+
+        assert_eq!(
+            handle_request(data(content), &extension, Default::default(), &origin())
+                .await
+                .unwrap(),
+            "200 OK, 3 chunks"
+        );
+        // Chunks are played back-to-back as one message
+        env.teamspeak.wait_playback(1).await;
+        let events = env.teamspeak.events();
+        assert_eq!(events.len(), 152);
+        assert_eq!(events.last().unwrap().1, SinkEvent::Muted(true));
+        assert_eq!(env.mock.requests().len(), 3);
+
+        // Each chunk is cached on its own
+        let hash = data_hash("Third one.");
+        env.wait_cached(hash).await;
+        assert_eq!(
+            handle_request(data(content), &extension, Default::default(), &origin())
+                .await
+                .unwrap(),
+            "Hit cache, 3 chunks"
+        );
+        env.teamspeak.wait_playback(2).await;
+        assert_eq!(env.teamspeak.events().len(), 304);
+        assert_eq!(env.mock.requests().len(), 3);
+
+        env.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_preview() {
+        let mut env = TestEnv::new().await;
+        let extension = Arc::new(extension(&env, env.requester()));
+        let (helper, mut events) = WebsocketHelper::new(16);
+        let mut data = data("Hello");
+        data.preview = true;
+
+        let mut next = async || {
+            let WebsocketEvent::Progress(_, progress) = events.recv().await.unwrap();
+            progress
+        };
+
+        assert_eq!(
+            handle_request(data.clone(), &extension, helper.clone().into(), &origin())
+                .await
+                .unwrap(),
+            "Preview, 1 chunks"
+        );
+        assert!(matches!(next().await, Progress::Synthesizing));
+        let progress = next().await;
+        assert!(progress.is_final());
+        assert_eq!(progress.audio().unwrap(), env.mock.audio());
+        assert!(env.agent.get(data_hash("Hello")).await.is_some());
+
+        // Second preview hits cache
+        handle_request(data, &extension, helper.into(), &origin())
+            .await
+            .unwrap();
+        assert_eq!(next().await.audio().unwrap(), env.mock.audio());
+        assert_eq!(env.mock.requests().len(), 1);
+        // Nothing is played
+        assert!(env.queued().try_recv().is_err());
+        env.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_download() {
+        let env = TestEnv::new().await;
+        let extension = Arc::new(extension(&env, env.requester()));
+        let synthesize = |content: &str| {
+            synthesize_audio(
+                Extension(extension.clone()),
+                ConnectInfo(PeerAddr("127.0.0.1:1234".parse().unwrap())),
+                Query(AudioQuery { format: None }),
+                axum::Json(data(content)),
+            )
+        };
+        let download = |hash: u64, format: Option<&str>| {
+            download_audio(
+                Extension(extension.clone()),
+                Path(hash),
+                Query(AudioQuery {
+                    format: format.map(str::to_string),
+                }),
+            )
+        };
+        let body = async |response: Response| {
+            axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap()
+        };
+
+        // Public like speaking on the websocket, no admin token is set
+        let response = synthesize("Hello").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "audio/ogg");
+        let hash = data_hash("Hello");
+        assert_eq!(
+            response.headers()["x-audio-hash"].to_str().unwrap(),
+            hash.to_string()
+        );
+        assert_eq!(body(response).await, env.mock.audio());
+
+        // Cached by synthesize
+        let response = download(hash, None).await;
+        assert_eq!(
+            response.headers()[CONTENT_DISPOSITION],
+            format!("attachment; filename=\"{hash}.ogg\"")
+        );
+        assert_eq!(body(response).await, env.mock.audio());
+        assert_eq!(env.mock.requests().len(), 1);
+        assert_eq!(download(1, None).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            body(download(hash, Some("ogg")).await).await,
+            env.mock.audio()
+        );
+        assert_eq!(
+            download(hash, Some("mp3")).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        // Ogg/Opus has to be decoded
+        #[cfg(not(feature = "transcode"))]
+        assert_eq!(
+            download(hash, Some("wav")).await.status(),
+            StatusCode::NOT_IMPLEMENTED
+        );
+
+        // Medium length messages are cached too
+        let content = "This message is exactly forty bytes long";
+        let response = synthesize(content).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let hash = data_hash(content);
+        assert_eq!(
+            response.headers()["x-audio-hash"].to_str().unwrap(),
+            hash.to_string()
+        );
+        assert_eq!(download(hash, None).await.status(), StatusCode::OK);
+        assert_eq!(env.mock.requests().len(), 2);
+        env.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_budget() {
+        let mut env = TestEnv::new().await;
+        let usage: Usage = toml::from_str("hard_limit = 5").unwrap();
+        let requester = env
+            .requester()
+            .usage(Arc::new(UsageTracker::new(env.agent.clone(), Some(&usage))));
+        let extension = Arc::new(extension(&env, requester).audit(Some(AuditLog::new(
+            env.agent.clone(),
+            &toml::from_str("").unwrap(),
+        ))));
+
+        // Reaches the limit
+        assert_eq!(
+            handle_request(data("Hello"), &extension, Default::default(), &origin())
+                .await
+                .unwrap(),
+            "200 OK"
+        );
+        assert!(matches!(
+            env.queued().recv().await,
+            Some(TTSEvent::NewData(..))
+        ));
+        let error = handle_request(data("World"), &extension, Default::default(), &origin())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(RequestError::BudgetExhausted)
+        ));
+        assert_eq!(env.mock.requests().len(), 1);
+
+        // Cached messages are still played
+        env.agent
+            .set(data_hash("World"), env.mock.audio().to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            handle_request(data("World"), &extension, Default::default(), &origin())
+                .await
+                .unwrap(),
+            "Hit cache"
+        );
+
+        let entries = extension
+            .audit
+            .as_ref()
+            .unwrap()
+            .query(&AuditQuery::default())
+            .await;
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| (entry.text.as_str(), entry.cached, entry.success))
+                .collect::<Vec<_>>(),
+            [
+                ("World", true, true),
+                ("World", false, false),
+                ("Hello", false, true)
+            ]
+        );
+        assert_eq!(entries[2].voice, "en-US-AvaNeural");
+        assert_eq!(entries[2].client, "127.0.0.1");
+        env.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_budget_fallback() {
+        let mut env = TestEnv::new().await;
+        let usage: Usage =
+            toml::from_str("hard_limit = 0\nfallback = [\"printf\", \"audio\"]").unwrap();
+        let requester = env
+            .requester()
+            .usage(Arc::new(UsageTracker::new(env.agent.clone(), Some(&usage))));
+        let extension = Arc::new(extension(&env, requester));
+
+        assert_eq!(
+            handle_request(data("Hello"), &extension, Default::default(), &origin())
+                .await
+                .unwrap(),
+            "Fallback"
+        );
+        assert!(matches!(
+            env.queued().recv().await,
+            Some(TTSEvent::Data(audio, ..)) if audio.as_ref() == b"audio"
+        ));
+        assert!(env.mock.requests().is_empty());
+        env.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_base_path() {
+        let env = TestEnv::new().await;
+        let extension = Arc::new(extension(&env, env.requester()).base_path("/tts".to_string()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, router(extension), std::future::pending()));
+        let get = async |path: &str| reqwest::get(format!("http://{addr}{path}")).await.unwrap();
+
+        for path in ["/tts", "/tts/"] {
+            let response = get(path).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(
+                response
+                    .text()
+                    .await
+                    .unwrap()
+                    .contains("const BASE_PATH = \"/tts\";")
+            );
+        }
+        assert_eq!(get("/tts/api/v1/history").await.status(), StatusCode::OK);
+        assert_eq!(get("/api/v1/history").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(get("/").await.status(), StatusCode::NOT_FOUND);
+
+        server.abort();
+        env.stop().await;
+    }
+
+    #[cfg(feature = "rustls")]
+    #[tokio::test]
+    async fn test_websocket_tls() {
+        use axum::{http::Method, serve::Listener};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_rustls::rustls::pki_types::ServerName;
+
+        use crate::{
+            config::Tls,
+            test_support::{TLS_CERT, TLS_KEY, tls_connector},
+            tls::TlsListener,
+        };
+
+        let env = TestEnv::new().await;
+        let extension = Arc::new(extension(&env, env.requester()));
+        let folder = std::env::temp_dir().join(format!("ws-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let (cert, key) = (folder.join("cert.pem"), folder.join("key.pem"));
+        std::fs::write(&cert, TLS_CERT).unwrap();
+        std::fs::write(&key, TLS_KEY).unwrap();
+        let tls: Tls = toml::from_str(&format!(
+            "cert = {:?}\nkey = {:?}\nreload = 0",
+            cert.display().to_string(),
+            key.display().to_string()
+        ))
+        .unwrap();
+        let listener = TlsListener::bind("127.0.0.1:0", &tls).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, router(extension), std::future::pending()));
+        let connect = async |alpn: &[u8]| {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            tls_connector(&[alpn])
+                .connect(ServerName::try_from("localhost").unwrap(), stream)
+                .await
+                .unwrap()
+        };
+
+        // HTTP/1.1 upgrade
+        let mut stream = connect(b"http/1.1").await;
+        stream
+            .write_all(
+                b"GET /ws/v1 HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\n\
+                Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut status = [0; 12];
+        stream.read_exact(&mut status).await.unwrap();
+        assert_eq!(&status, b"HTTP/1.1 101");
+
+        // Browsers negotiating HTTP/2 open websockets with extended CONNECT
+        let (client, connection) = h2::client::handshake(connect(b"h2").await).await.unwrap();
+        tokio::spawn(connection);
+        let mut client = client.ready().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !client.is_extended_connect_protocol_enabled() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        for path in ["/ws", "/ws/v1"] {
+            let mut request = axum::http::Request::builder()
+                .method(Method::CONNECT)
+                .uri(format!("https://localhost{path}"))
+                .header("sec-websocket-version", "13")
+                .body(())
+                .unwrap();
+            request
+                .extensions_mut()
+                .insert(h2::ext::Protocol::from_static("websocket"));
+            let (response, _stream) = client.send_request(request, false).unwrap();
+            assert_eq!(response.await.unwrap().status(), StatusCode::OK, "{path}");
+        }
+
+        server.abort();
+        env.stop().await;
+        std::fs::remove_dir_all(folder).ok();
+    }
+}